@@ -10,7 +10,12 @@ import {
   Task,
   TaskCreateRequest,
   TaskUpdateRequest,
-  TaskCreateResponse
+  TaskCreateResponse,
+  NoopRule,
+  NoopRulesApplyRequest,
+  Session,
+  SessionResponse,
+  SessionPromoteRequest
 } from './types';
 
 // Convert preset date ranges to actual dates
@@ -133,6 +138,10 @@ class ApiService {
     if (filters.search) params.append('search', filters.search);
     if (filters.search_content) params.append('search_content', filters.search_content);
     if (filters.show_noop) params.append('show_noop', 'true');
+    if (filters.status) params.append('status', filters.status);
+    if (filters.tag) params.append('tag', filters.tag);
+    if (filters.field) params.append('field', filters.field);
+    if (filters.terminal) params.append('terminal', filters.terminal);
     
     const queryString = params.toString();
     const url = queryString ? `/entries?${queryString}` : '/entries';
@@ -153,6 +162,10 @@ class ApiService {
     if (filters.search) params.append('search', filters.search);
     if (filters.search_content) params.append('search_content', filters.search_content);
     if (filters.show_noop) params.append('show_noop', 'true');
+    if (filters.status) params.append('status', filters.status);
+    if (filters.tag) params.append('tag', filters.tag);
+    if (filters.field) params.append('field', filters.field);
+    if (filters.terminal) params.append('terminal', filters.terminal);
     
     const queryString = params.toString();
     const url = queryString ? `/entries/summary?${queryString}` : '/entries/summary';
@@ -180,6 +193,35 @@ class ApiService {
     return this.post<ApiResponse>('/redact', data);
   }
 
+  // Get the rules that mark entries as no-op
+  async getNoopRules(): Promise<NoopRule[]> {
+    return this.get<NoopRule[]>('/noop-rules');
+  }
+
+  // Apply no-op rules to existing entries
+  async applyNoopRules(data: NoopRulesApplyRequest): Promise<ApiResponse> {
+    return this.post<ApiResponse>('/noop-rules/apply', data);
+  }
+
+  // Get recorded sessions
+  async getSessions(): Promise<Session[]> {
+    return this.get<Session[]>('/sessions');
+  }
+
+  // Get a session with its events, optionally only those in a time range
+  async getSession(uuid: string, fromMs?: number, toMs?: number): Promise<SessionResponse> {
+    const params = new URLSearchParams();
+    if (fromMs !== undefined) params.append('from_ms', String(fromMs));
+    if (toMs !== undefined) params.append('to_ms', String(toMs));
+    const queryString = params.toString();
+    return this.get<SessionResponse>(queryString ? `/session/${uuid}?${queryString}` : `/session/${uuid}`);
+  }
+
+  // Promote a time range of a session to an entry
+  async promoteSession(uuid: string, data: SessionPromoteRequest): Promise<ApiResponse & { uuid?: string }> {
+    return this.post<ApiResponse & { uuid?: string }>(`/session/${uuid}/promote`, data);
+  }
+
   // Get diff content
   async getDiffContent(uuid: string): Promise<DiffResponse> {
     // Note: this endpoint doesn't have the /api prefix
@@ -190,6 +232,11 @@ class ApiService {
     return response.json();
   }
 
+  // Get all tags in use
+  async getTags(): Promise<string[]> {
+    return this.get<string[]>('/tags');
+  }
+
   // Get all tasks
   async getTasks(): Promise<Task[]> {
     return this.get<Task[]>('/tasks');
@@ -239,6 +286,8 @@ class ApiService {
   getCopyText(entry: LogEntry | LogEntrySummary): string {
     if (entry.capture_type === 'Run') {
       return `prodlog run ${entry.cmd}`;
+    } else if (entry.capture_type === 'Note') {
+      return entry.message;
     } else {
       return entry.cmd.startsWith('sudo') 
         ? `prodlog edit -s ${entry.filename}`
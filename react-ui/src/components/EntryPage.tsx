@@ -366,7 +366,7 @@ export default function EntryPage() {
       />
 
       <div className="section">
-        <h2>{entry.capture_type === 'Run' ? entry.cmd : entry.filename}</h2>
+        <h2>{entry.capture_type === 'Run' ? entry.cmd : entry.capture_type === 'Note' ? 'Note' : entry.filename}</h2>
         <div className="info-grid">
           <div className="info-item">
             <span className="info-label">Host:</span>
@@ -380,6 +380,14 @@ export default function EntryPage() {
             <span className="info-label">Directory:</span>
             <span className="info-value">{entry.cwd}</span>
           </div>
+          {entry.terminal && (
+            <div className="info-item">
+              <span className="info-label">Terminal:</span>
+              <span className="info-value">
+                <a href={`/?terminal=${encodeURIComponent(entry.terminal)}`} onClick={(e) => { e.preventDefault(); navigate(`/?terminal=${encodeURIComponent(entry.terminal)}`); }}>{entry.terminal}</a>
+              </span>
+            </div>
+          )}
           <div className="info-item">
             <span className="info-label">Start:</span>
             <span className="info-value">{api.formatTimestamp(entry.start_time)}</span>
@@ -406,9 +414,9 @@ export default function EntryPage() {
 
       {entry.capture_type === 'Run' ? (
         <OutputDisplay entry={entry} />
-      ) : (
+      ) : entry.capture_type === 'Edit' ? (
         <DiffDisplay entry={entry} />
-      )}
+      ) : null}
     </div>
   );
 } 
\ No newline at end of file
@@ -33,6 +33,13 @@ const EditIcon = () => (
   </svg>
 );
 
+const NoteIcon = () => (
+  <svg fill="none" stroke="currentColor" strokeWidth="1" width="16" height="16">
+    <path d="M3 2h10v9l-3 3H3z"/>
+    <path d="M13 11h-3v3"/>
+  </svg>
+);
+
 const ExpandedIcon = () => (
   <svg fill="none" stroke="currentColor" width="16" height="16">
     <path stroke-width="2" d="M2 8h12"/>
@@ -328,7 +335,7 @@ function SingleIndexEntry({
       onClick={handleClick}
     >
       <td>
-        {entry.capture_type === 'Run' ? <RunIcon /> : <EditIcon />}
+        {entry.capture_type === 'Run' ? <RunIcon /> : entry.capture_type === 'Note' ? <NoteIcon /> : <EditIcon />}
       </td>
       {isSelectMode && (
         <td onClick={(e) => e.stopPropagation()}>
@@ -570,6 +577,7 @@ export default function IndexPage() {
     search: searchParams.get('search') || undefined,
     search_content: searchParams.get('search_content') || undefined,
     show_noop: searchParams.get('show_noop') === 'true' || undefined,
+    terminal: searchParams.get('terminal') || undefined,
   }), [searchParams]);
 
   // Parse view mode from URL
@@ -591,6 +599,7 @@ export default function IndexPage() {
     if (newFilters.search) params.set('search', newFilters.search);
     if (newFilters.search_content) params.set('search_content', newFilters.search_content);
     if (newFilters.show_noop) params.set('show_noop', 'true');
+    if (newFilters.terminal) params.set('terminal', newFilters.terminal);
     
     setSearchParams(params);
   };
@@ -927,6 +936,9 @@ export default function IndexPage() {
             )}
           </div>
           <ActiveTaskMessage activeTaskId={activeTaskId} tasks={tasks} />
+          <button className="bluebutton" type="button" onClick={() => navigate('/live')}>
+            Live Session
+          </button>
           <button className="bluebutton" type="button" onClick={() => navigate('/redact')}>
             Bulk Redact Passwords
           </button>
@@ -0,0 +1,131 @@
+import React, { useState, useEffect, useRef } from 'react';
+import { useNavigate } from 'react-router-dom';
+import { Terminal } from '@xterm/xterm';
+import '@xterm/xterm/css/xterm.css';
+import { LiveEvent } from '../types';
+
+type LiveCapture = Extract<LiveEvent, { type: 'capture_started' }>;
+
+// Wait before reconnecting when the connection drops, e.g. because prodlog_record restarted
+const RECONNECT_DELAY_MS = 2000;
+
+function decodeBase64(data: string): Uint8Array {
+  const binaryString = atob(data);
+  const bytes = new Uint8Array(binaryString.length);
+  for (let i = 0; i < binaryString.length; i++) {
+    bytes[i] = binaryString.charCodeAt(i);
+  }
+  return bytes;
+}
+
+export default function LivePage() {
+  const navigate = useNavigate();
+  const terminalRef = useRef<HTMLDivElement>(null);
+  const [connected, setConnected] = useState(false);
+  const [captures, setCaptures] = useState<LiveCapture[]>([]);
+
+  useEffect(() => {
+    if (!terminalRef.current) return;
+
+    const terminal = new Terminal({
+      cols: 120,
+      rows: 40,
+      scrollback: 100000,
+      fontSize: 14,
+      fontFamily: 'monospace',
+      disableStdin: true
+    });
+    // The view is read-only, don't let xterm.js handle any key events
+    terminal.attachCustomKeyEventHandler(() => false);
+    terminal.open(terminalRef.current);
+
+    let socket: WebSocket | null = null;
+    let reconnectTimer: number | undefined;
+    let closed = false;
+
+    const connect = () => {
+      const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+      socket = new WebSocket(`${protocol}//${window.location.host}/api/live`);
+
+      socket.onopen = () => {
+        // We're sent the buffered output again, so start from a clean terminal
+        terminal.reset();
+        setCaptures([]);
+        setConnected(true);
+      };
+
+      socket.onmessage = (message) => {
+        const event: LiveEvent = JSON.parse(message.data);
+        switch (event.type) {
+          case 'output':
+            terminal.write(decodeBase64(event.data));
+            break;
+          case 'resize':
+            terminal.resize(event.cols, event.rows);
+            break;
+          case 'capture_started':
+            setCaptures(previous => [...previous, event]);
+            break;
+          case 'capture_stopped':
+            setCaptures(previous => previous.filter(capture => capture.uuid !== event.uuid));
+            break;
+        }
+      };
+
+      socket.onclose = () => {
+        setConnected(false);
+        if (!closed) {
+          reconnectTimer = window.setTimeout(connect, RECONNECT_DELAY_MS);
+        }
+      };
+    };
+
+    connect();
+
+    return () => {
+      closed = true;
+      window.clearTimeout(reconnectTimer);
+      socket?.close();
+      terminal.dispose();
+    };
+  }, []);
+
+  return (
+    <div className="container">
+      <div className="header">
+        <h1>Live Session</h1>
+        <button className="bluebutton" type="button" onClick={() => navigate('/')}>
+          ← Back to list
+        </button>
+      </div>
+
+      <div className="section">
+        {!connected ? (
+          <div className="message error">Not connected to prodlog, reconnecting...</div>
+        ) : captures.length === 0 ? (
+          <div>No capture is running.</div>
+        ) : (
+          captures.map(capture => (
+            <div key={capture.uuid}>
+              Capturing {capture.capture_type === 'Edit' ? 'edit' : 'command'} on {capture.host}:{capture.cwd}:{' '}
+              <code>{capture.cmd}</code>{' '}
+              (<a href={`/entry/${capture.uuid}`}>entry</a>)
+            </div>
+          ))
+        )}
+      </div>
+
+      <div className="section">
+        <div
+          ref={terminalRef}
+          style={{
+            backgroundColor: '#000',
+            padding: '1rem',
+            borderRadius: '8px',
+            minHeight: '200px'
+          }}
+        />
+      </div>
+    </div>
+  );
+}
@@ -5,6 +5,7 @@ import './styles.css';
 import IndexPage from './components/IndexPage';
 import EntryPage from './components/EntryPage';
 import RedactPage from './components/RedactPage';
+import LivePage from './components/LivePage';
 
 function App() {
   return (
@@ -13,6 +14,7 @@ function App() {
         <Route path="/" element={<IndexPage />} />
         <Route path="/entry/:uuid" element={<EntryPage />} />
         <Route path="/redact" element={<RedactPage />} />
+        <Route path="/live" element={<LivePage />} />
       </Routes>
     </Router>
   );
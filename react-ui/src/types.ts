@@ -1,6 +1,6 @@
 export interface LogEntry {
   uuid: string;
-  capture_type: 'Run' | 'Edit';
+  capture_type: 'Run' | 'Edit' | 'Note';
   host: string;
   cwd: string;
   cmd: string;
@@ -9,21 +9,39 @@ export interface LogEntry {
   message: string;
   is_noop: boolean;
   exit_code: number;
+  status: 'Completed' | 'Interrupted' | 'InProgress';
   local_user: string;
   remote_user: string;
   captured_output: string; // base64 encoded
   filename: string;
   original_content: string; // base64 encoded
   edited_content: string; // base64 encoded
+  original_hash?: string; // SHA-256, contents at /api/blob/<hash>
+  edited_hash?: string;
   terminal_rows: number;
   terminal_cols: number;
   task_id?: number;
+  parent_uuid?: string;
+  facts: Record<string, string>; // e.g. os, kernel, git.head, env.SUDO_USER
+  tags: string[];
+  custom_fields: Record<string, string>;
+  additional_files: EditedFile[]; // the files after the first one of a multi-file edit
+  content_error?: string;
+  terminal: string; // the terminal it was captured in, when several record to one prodlog
+}
+
+export interface EditedFile {
+  filename: string;
+  original_content: string; // base64 encoded
+  edited_content: string; // base64 encoded
+  original_hash?: string;
+  edited_hash?: string;
 }
 
 // Lightweight version for index page - excludes large content fields
 export interface LogEntrySummary {
   uuid: string;
-  capture_type: 'Run' | 'Edit';
+  capture_type: 'Run' | 'Edit' | 'Note';
   host: string;
   cwd: string;
   cmd: string;
@@ -32,12 +50,18 @@ export interface LogEntrySummary {
   message: string;
   is_noop: boolean;
   exit_code: number;
+  status: 'Completed' | 'Interrupted' | 'InProgress';
   local_user: string;
   remote_user: string;
   filename: string;
   terminal_rows: number;
   terminal_cols: number;
   task_id?: number;
+  parent_uuid?: string;
+  content_error?: string;
+  terminal: string;
+  tags: string[];
+  custom_fields: Record<string, string>;
 }
 
 export interface Task {
@@ -62,6 +86,10 @@ export interface Filters {
   search?: string;
   search_content?: string;
   show_noop?: boolean;
+  status?: 'completed' | 'interrupted' | 'in_progress';
+  tag?: string;
+  field?: string; // "name=value", or "name" for entries that have the field
+  terminal?: string;
 }
 
 export interface ApiResponse<T = any> {
@@ -70,6 +98,17 @@ export interface ApiResponse<T = any> {
   message?: string;
 }
 
+export interface NoopRule {
+  name: string;
+  type: 'command_pattern' | 'read_only_binary' | 'unchanged_edit';
+  patterns?: string[];
+  binaries?: string[];
+}
+
+export interface NoopRulesApplyRequest {
+  rule?: string; // all rules when not given
+}
+
 export interface BulkRedactRequest {
   passwords: string[];
 }
@@ -83,6 +122,8 @@ export interface EntryUpdateRequest {
   uuid: string;
   message: string;
   is_noop: boolean;
+  tags?: string[];
+  custom_fields?: Record<string, string>;
 }
 
 export interface TaskCreateRequest {
@@ -102,5 +143,56 @@ export interface TaskCreateResponse {
 }
 
 export interface DiffResponse {
+  diff: string; // diff of the first file
+  files: FileDiff[];
+}
+
+export interface FileDiff {
+  filename: string;
   diff: string;
-} 
\ No newline at end of file
+} 
+
+// Sent over the /api/live WebSocket
+export type LiveEvent =
+  | { type: 'output'; data: string } // base64 encoded
+  | { type: 'resize'; cols: number; rows: number }
+  | {
+      type: 'capture_started';
+      uuid: string;
+      parent_uuid?: string;
+      capture_type: 'Run' | 'Edit' | 'Note';
+      host: string;
+      cwd: string;
+      cmd: string;
+    }
+  | { type: 'capture_stopped'; uuid: string; status: 'Completed' | 'Interrupted' | 'InProgress'; exit_code: number };
+
+// A recording of a whole terminal session, see --record-session
+export interface Session {
+  uuid: string;
+  start_time: string;
+  duration_ms: number;
+  status: 'Completed' | 'Interrupted' | 'InProgress';
+  local_user: string;
+  cmd: string;
+}
+
+export interface SessionEvent {
+  offset_ms: number; // since the start of the session
+  kind: { Output: string } | { Input: string } | 'MaskedInput' | { Resize: { cols: number; rows: number } };
+}
+
+export interface SessionResponse {
+  session: Session;
+  events: SessionEvent[];
+}
+
+export interface SessionPromoteRequest {
+  from_ms: number;
+  to_ms: number;
+  host: string;
+  cwd: string;
+  cmd: string;
+  message?: string;
+  remote_user?: string;
+}
@@ -0,0 +1,140 @@
+use std::io::Write;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::model::{ CaptureEvent, CaptureEventKind, CaptureV2_4 };
+
+// Entries recorded before we kept timing information only have the final output.
+// Treat that as a single chunk of output at the start of the capture.
+fn events_or_fallback(entry: &CaptureV2_4) -> Vec<CaptureEvent> {
+    if entry.events.is_empty() {
+        vec![CaptureEvent {
+            offset_ms: 0,
+            kind: CaptureEventKind::Output(entry.captured_output.clone()),
+        }]
+    } else {
+        entry.events.clone()
+    }
+}
+
+/// Converts a chunk of output to a string, keeping an incomplete UTF-8 sequence at
+/// the end of the chunk in `pending` so it can be completed by the next chunk.
+fn decode_utf8_chunk(pending: &mut Vec<u8>, chunk: &[u8]) -> String {
+    pending.extend_from_slice(chunk);
+    let mut result = String::new();
+    let mut rest: &[u8] = pending;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                result.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                result.push_str(std::str::from_utf8(valid).unwrap());
+                match e.error_len() {
+                    Some(len) => {
+                        result.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        // Incomplete sequence at the end, wait for more data
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    *pending = rest.to_vec();
+    result
+}
+
+/// Exports a capture in asciicast v2 format (https://docs.asciinema.org/manual/asciicast/v2/).
+pub fn to_asciicast(entry: &CaptureV2_4) -> String {
+    let events = events_or_fallback(entry);
+
+    // The first event is the terminal size at the start of the capture, if we know it.
+    let (width, height, events) = match events.first() {
+        Some(CaptureEvent { offset_ms: 0, kind: CaptureEventKind::Resize { cols, rows } })
+            if *cols > 0 && *rows > 0 => (*cols, *rows, &events[1..]),
+        _ if entry.terminal_cols > 0 && entry.terminal_rows > 0 =>
+            (entry.terminal_cols, entry.terminal_rows, &events[..]),
+        _ => (80, 24, &events[..]),
+    };
+
+    let header = json!({
+        "version": 2,
+        "width": width,
+        "height": height,
+        "timestamp": entry.start_time.timestamp(),
+        "title": format!("{}:{} $ {}", entry.host, entry.cwd, entry.cmd),
+    });
+
+    let mut cast = header.to_string();
+    cast.push('\n');
+    let mut pending = Vec::new();
+    let mut pending_input = Vec::new();
+    for event in events {
+        let time = (event.offset_ms as f64) / 1000.0;
+        let line = match &event.kind {
+            CaptureEventKind::Output(data) => {
+                let text = decode_utf8_chunk(&mut pending, data);
+                if text.is_empty() {
+                    continue;
+                }
+                json!([time, "o", text])
+            }
+            CaptureEventKind::Input(data) => {
+                let text = decode_utf8_chunk(&mut pending_input, data);
+                if text.is_empty() {
+                    continue;
+                }
+                json!([time, "i", text])
+            }
+            // Asciicast has no way to say input was hidden, so leave it out
+            CaptureEventKind::MaskedInput => continue,
+            CaptureEventKind::Resize { cols, rows } => json!([time, "r", format!("{}x{}", cols, rows)]),
+        };
+        cast.push_str(&line.to_string());
+        cast.push('\n');
+    }
+    cast
+}
+
+/// Replays the output of a capture on our own terminal, `speed` times faster than
+/// it was recorded.
+pub fn replay(entry: &CaptureV2_4, speed: f64) -> Result<(), std::io::Error> {
+    let mut stdout = std::io::stdout();
+    let mut previous_offset_ms = 0;
+    for event in events_or_fallback(entry) {
+        let delay_ms = event.offset_ms.saturating_sub(previous_offset_ms);
+        previous_offset_ms = event.offset_ms;
+        if delay_ms > 0 {
+            std::thread::sleep(Duration::from_secs_f64((delay_ms as f64) / 1000.0 / speed));
+        }
+        // We can't resize the terminal we're replaying on, so only output is replayed.
+        if let CaptureEventKind::Output(data) = &event.kind {
+            stdout.write_all(data)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_utf8_chunk_split_sequence() {
+        let mut pending = Vec::new();
+        let bytes = "né".as_bytes();
+        assert_eq!(decode_utf8_chunk(&mut pending, &bytes[..2]), "n");
+        assert_eq!(decode_utf8_chunk(&mut pending, &bytes[2..]), "é");
+        assert!(pending.is_empty());
+        assert_eq!(decode_utf8_chunk(&mut pending, b"a\xffb"), "a\u{FFFD}b");
+    }
+}
@@ -1,10 +1,15 @@
 use std::{path::PathBuf, sync::OnceLock};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::output_limits::OutputLimits;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)] // Add metadata
 pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(
         long,
         value_name = "DIR",
@@ -20,17 +25,24 @@ pub struct CliArgs {
         long,
         value_name = "IMPORT",
         default_value = None,
-        help = "Import a prodlog json or sqlite file"
+        help = "Import a prodlog sqlite file"
     )]
     pub import: Option<String>,
 
     #[arg(
         long,
         value_name = "CMD",
-        default_value = "/bin/bash",
-        help = "Initial command to run. Defaults to starting bash, but you can use something like 'ssh <host>' to go to a remote directly."
+        conflicts_with = "args",
+        help = "Initial command to run, split into arguments the way a shell would. Defaults to $SHELL, but you can use something like 'ssh <host>' to go to a remote directly."
+    )]
+    pub cmd: Option<String>,
+
+    #[arg(
+        last = true,
+        value_name = "ARGS",
+        help = "Initial command to run as separate arguments, instead of --cmd, e.g. -- ssh -J bastion host"
     )]
-    pub cmd: String,
+    pub args: Vec<String>,
 
     #[arg(
         long,
@@ -39,6 +51,150 @@ pub struct CliArgs {
         help = "Background colour for the UI."
     )]
     pub ui_background: String,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Accept commands from prodlog scripts that are too old to authenticate them, until a newer script connects."
+    )]
+    pub allow_legacy_scripts: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        default_value = None,
+        help = "JSON file with the rules that mark captures as no-op. Defaults to noop_rules.json in the prodlog directory, or built-in rules if there is none."
+    )]
+    pub noop_rules: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Record the whole terminal session, not just captures, so that output can be promoted to an entry later."
+    )]
+    pub record_session: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Let other machines follow the terminal in the live view of the web UI. Without it, only viewers on this machine are let in."
+    )]
+    pub share_live_view: bool,
+
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "PRODLOG_INGEST_TOKEN",
+        hide_env_values = true,
+        help = "Let other tools add entries by posting them to /api/entries with this token, in an 'Authorization: Bearer <TOKEN>' header. Better set with PRODLOG_INGEST_TOKEN, as other users can see the command line."
+    )]
+    pub ingest_token: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Capture every command run in shells that send OSC 133 shell integration marks, without needing `prodlog run`."
+    )]
+    pub shell_integration: bool,
+
+    #[arg(
+        long,
+        value_name = "CHAR",
+        default_value = "^]",
+        help = "Escape character for commands handled by prodlog_record itself, e.g. ^] for Ctrl-]. Type it at the start of a line followed by ? for help, or use none to disable escape commands."
+    )]
+    pub escape_char: String,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = None,
+        help = "Name of this terminal, shown with the entries captured in it. Defaults to the tty and the command running in it."
+    )]
+    pub terminal_name: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = None,
+        help = "Keep only the first BYTES bytes of the output of a capture, and the last --output-tail bytes. Output is kept in full by default."
+    )]
+    pub output_head: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = None,
+        help = "Keep only the last BYTES bytes of the output of a capture, and the first --output-head bytes."
+    )]
+    pub output_tail: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Keep only the final state of lines redrawn with carriage returns, like progress bars, in the output of captures."
+    )]
+    pub collapse_redraws: bool,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        default_value = None,
+        help = "PostgreSQL database to store entries in instead of prodlog.sqlite in --dir, e.g. postgres://prodlog@db.example.com/prodlog, so that a team can share one."
+    )]
+    pub database_url: Option<String>,
+
+    #[arg(
+        long = "secondary-sink",
+        value_name = "SINK",
+        help = "Also write entries to SINK: sqlite:<FILE>, a postgres:// URL, or jsonl:<DIR> for an append-only audit log. Writes to it are queued in --dir and retried until they succeed, so it can't hold up the terminal. Can be given more than once."
+    )]
+    pub secondary_sinks: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Replay the output of a captured entry in this terminal
+    Replay {
+        #[arg(value_name = "UUID", help = "UUID of the entry to replay")]
+        uuid: String,
+
+        #[arg(
+            long,
+            value_name = "FACTOR",
+            default_value = "1.0",
+            help = "Replay speed, e.g. 2 to replay twice as fast as the original"
+        )]
+        speed: f64,
+    },
+}
+
+impl CliArgs {
+    /// The arguments of the initial command: the ones after `--`, or --cmd split into
+    /// arguments, or the user's shell.
+    pub fn command_line(&self) -> Result<Vec<String>, String> {
+        let args = if !self.args.is_empty() {
+            self.args.clone()
+        } else if let Some(cmd) = &self.cmd {
+            shell_words::split(cmd).map_err(|e| format!("Can't parse --cmd {:?}: {}", cmd, e))?
+        } else {
+            vec![std::env::var("SHELL").ok().filter(|shell| !shell.is_empty()).unwrap_or_else(|| "/bin/bash".to_string())]
+        };
+        if args.is_empty() {
+            return Err("Empty shell command".to_string());
+        }
+        Ok(args)
+    }
+
+    /// The --output-head and --output-tail limits, where giving only one of them
+    /// keeps nothing at the other end.
+    pub fn output_limits(&self) -> OutputLimits {
+        let head_tail = match (self.output_head, self.output_tail) {
+            (None, None) => None,
+            (head, tail) => Some((head.unwrap_or(0), tail.unwrap_or(0))),
+        };
+        OutputLimits { head_tail, collapse_redraws: self.collapse_redraws }
+    }
 }
 
 static CONFIG: OnceLock<CliArgs> = OnceLock::new();
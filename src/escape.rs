@@ -0,0 +1,222 @@
+/// A command typed after the escape character.
+#[derive(Debug, PartialEq)]
+pub enum EscapeCommand {
+    /// Start capturing everything on screen, described by the given text.
+    StartCapture(String),
+    /// Stop the innermost capture started with StartCapture.
+    StopCapture,
+    ListTasks,
+    /// Activate the task with this id, start a new task with this name, or clear the
+    /// active task if empty.
+    SetTask(String),
+    AddNote(String),
+    Status,
+    Help,
+}
+
+/// What to do with a piece of the input.
+#[derive(Debug, PartialEq)]
+pub enum InputAction {
+    /// Pass it on to the child.
+    Forward(Vec<u8>),
+    /// Ask for a line of text.
+    Prompt(&'static str),
+    /// Show what was typed at the prompt.
+    Echo(Vec<u8>),
+    Command(EscapeCommand),
+}
+
+#[derive(Clone, Copy)]
+enum PromptKind {
+    CaptureDescription,
+    Task,
+    Note,
+}
+
+enum EscapeState {
+    Normal,
+    // The escape character was typed, the next key is a command.
+    Escaped,
+    Prompting(PromptKind, Vec<u8>),
+}
+
+const KEYS: &[(char, &str)] = &[
+    ('c', "start capturing everything on screen"),
+    ('x', "stop capturing"),
+    ('t', "set or clear the active task"),
+    ('n', "add a note"),
+    ('s', "show status"),
+    ('?', "show this help"),
+];
+
+/// Finds ssh-style escape commands in what's typed, the escape character followed by a
+/// key, and passes everything else on. These are handled by prodlog_record itself, so
+/// unlike the prodlog script they work on any host, including network devices.
+pub struct EscapeHandler {
+    escape_char: u8,
+    state: EscapeState,
+    // Like ssh's ~, the escape character only starts a command at the start of a line,
+    // so programs that use it themselves, like telnet, still get it elsewhere.
+    at_line_start: bool,
+}
+
+/// Parses the --escape-char option: `^X` for a control character, a single character,
+/// or `none` to disable escape commands.
+pub fn parse_escape_char(value: &str) -> Result<Option<u8>, String> {
+    match value.as_bytes() {
+        b"none" => Ok(None),
+        [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => Ok(Some(c.to_ascii_uppercase() & 0x1f)),
+        [c] if c.is_ascii() => Ok(Some(*c)),
+        _ => Err(format!("Invalid escape character {:?}, expected e.g. ^] or none", value)),
+    }
+}
+
+/// The help text, with `escape_char` as given in --escape-char.
+pub fn help(escape_char: &str) -> Vec<String> {
+    let mut lines = vec![format!("Escape commands, typed after {} at the start of a line:", escape_char)];
+    lines.extend(KEYS.iter().map(|(key, description)| format!("  {}  {}", key, description)));
+    lines.push(format!("Type {} twice at the start of a line to send it to the terminal.", escape_char));
+    lines
+}
+
+impl EscapeHandler {
+    pub fn new(escape_char: u8) -> Self {
+        EscapeHandler { escape_char, state: EscapeState::Normal, at_line_start: true }
+    }
+
+    /// Handles the raw bytes of one key event. These can also be several keys, e.g.
+    /// when text is pasted.
+    pub fn feed(&mut self, raw: &[u8]) -> Vec<InputAction> {
+        let mut actions = Vec::new();
+        let mut pos = 0;
+        while pos < raw.len() {
+            match &mut self.state {
+                EscapeState::Normal => {
+                    let mut end = pos;
+                    while end < raw.len() && !(self.at_line_start && raw[end] == self.escape_char) {
+                        self.at_line_start = raw[end] == b'\r' || raw[end] == b'\n';
+                        end += 1;
+                    }
+                    if end > pos {
+                        actions.push(InputAction::Forward(raw[pos..end].to_vec()));
+                    }
+                    if end < raw.len() {
+                        self.state = EscapeState::Escaped;
+                    }
+                    pos = end + 1;
+                }
+                EscapeState::Escaped => {
+                    let key = raw[pos];
+                    pos += 1;
+                    self.state = EscapeState::Normal;
+                    match key {
+                        _ if key == self.escape_char => {
+                            actions.push(InputAction::Forward(vec![key]));
+                            self.at_line_start = false;
+                        }
+                        b'c' => self.prompt(PromptKind::CaptureDescription, &mut actions),
+                        b'x' => actions.push(InputAction::Command(EscapeCommand::StopCapture)),
+                        b't' => {
+                            actions.push(InputAction::Command(EscapeCommand::ListTasks));
+                            self.prompt(PromptKind::Task, &mut actions);
+                        }
+                        b'n' => self.prompt(PromptKind::Note, &mut actions),
+                        b's' => actions.push(InputAction::Command(EscapeCommand::Status)),
+                        _ => actions.push(InputAction::Command(EscapeCommand::Help)),
+                    }
+                }
+                EscapeState::Prompting(kind, text) => {
+                    let key = raw[pos];
+                    pos += 1;
+                    match key {
+                        b'\r' | b'\n' => {
+                            let text = String::from_utf8_lossy(text).trim().to_string();
+                            let command = match kind {
+                                PromptKind::CaptureDescription => EscapeCommand::StartCapture(text),
+                                PromptKind::Task => EscapeCommand::SetTask(text),
+                                PromptKind::Note => EscapeCommand::AddNote(text),
+                            };
+                            actions.push(InputAction::Echo(b"\r\n".to_vec()));
+                            actions.push(InputAction::Command(command));
+                            self.state = EscapeState::Normal;
+                        }
+                        // Ctrl-C, or Esc on its own rather than as the start of e.g. an arrow key
+                        0x03 => {
+                            actions.push(InputAction::Echo(b"^C\r\n".to_vec()));
+                            self.state = EscapeState::Normal;
+                        }
+                        0x1b if pos == raw.len() => {
+                            actions.push(InputAction::Echo(b"^C\r\n".to_vec()));
+                            self.state = EscapeState::Normal;
+                        }
+                        0x1b => {
+                            // Ignore keys we can't edit with, like arrow keys
+                            pos = raw.len();
+                        }
+                        0x7f | 0x08 => {
+                            // Remove the last character, which may be several bytes
+                            while let Some(byte) = text.pop() {
+                                if byte & 0xc0 != 0x80 {
+                                    actions.push(InputAction::Echo(b"\x08 \x08".to_vec()));
+                                    break;
+                                }
+                            }
+                        }
+                        _ if key < 0x20 => (),
+                        _ => {
+                            text.push(key);
+                            actions.push(InputAction::Echo(vec![key]));
+                        }
+                    }
+                }
+            }
+        }
+        actions
+    }
+
+    fn prompt(&mut self, kind: PromptKind, actions: &mut Vec<InputAction>) {
+        actions.push(
+            InputAction::Prompt(match kind {
+                PromptKind::CaptureDescription => "Describe what you're about to do: ",
+                PromptKind::Task => "Task id, or a name to start a new task (empty to clear the active task): ",
+                PromptKind::Note => "Note: ",
+            })
+        );
+        self.state = EscapeState::Prompting(kind, Vec::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_commands() {
+        let mut handler = EscapeHandler::new(0x1d);
+        assert_eq!(handler.feed(b"\x1d"), vec![]);
+        assert_eq!(handler.feed(b"\x1d"), vec![InputAction::Forward(vec![0x1d])]);
+        // Only at the start of a line
+        assert_eq!(handler.feed(b"\x1ds"), vec![InputAction::Forward(b"\x1ds".to_vec())]);
+        assert_eq!(handler.feed(b"ls\x1d\r"), vec![InputAction::Forward(b"ls\x1d\r".to_vec())]);
+        assert_eq!(handler.feed(b"\x1ds"), vec![InputAction::Command(EscapeCommand::Status)]);
+
+        let actions = handler.feed(b"\x1dnok\x7f\x7f\xc3\xa9\x7fe");
+        assert_eq!(actions[0], InputAction::Prompt("Note: "));
+        assert_eq!(handler.feed(b"\x1b[A"), vec![]);
+        let actions = handler.feed(b"\rpwd\r");
+        assert_eq!(actions, vec![
+            InputAction::Echo(b"\r\n".to_vec()),
+            InputAction::Command(EscapeCommand::AddNote("e".to_string())),
+            InputAction::Forward(b"pwd\r".to_vec())
+        ]);
+    }
+
+    #[test]
+    fn test_parse_escape_char() {
+        assert_eq!(parse_escape_char("^]"), Ok(Some(0x1d)));
+        assert_eq!(parse_escape_char("^a"), Ok(Some(0x01)));
+        assert_eq!(parse_escape_char("~"), Ok(Some(b'~')));
+        assert_eq!(parse_escape_char("none"), Ok(None));
+        assert!(parse_escape_char("^]]").is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use crate::model::CaptureV2_4;
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use std::collections::HashMap;
+
+use crate::model::{ CaptureEvent, CaptureEventKind, CaptureV2_4 };
 
 pub fn base64_decode_string(data: &str) -> String {
     use base64::{Engine as _, engine::general_purpose};
@@ -22,6 +26,162 @@ pub fn base64_decode(data: &str) -> Vec<u8> {
     }
 }
 
+/// Optional arguments of a prodlog command. These are sent as `name=value` after the
+/// positional arguments, so older versions of prodlog_record simply ignore them.
+pub struct NamedArgs(Vec<(String, String)>);
+
+impl NamedArgs {
+    pub fn parse(args: &[String]) -> Self {
+        NamedArgs(
+            args
+                .iter()
+                .filter_map(|arg| arg.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        )
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of an argument that can be given more than once.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All arguments whose name starts with `prefix`, with the prefix removed.
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.0
+            .iter()
+            .filter_map(move |(name, value)| Some((name.strip_prefix(prefix)?, value.as_str())))
+    }
+}
+
+/// HMAC-SHA256 of a message, in hex.
+pub fn hmac_sha256_hex(key: &[u8], message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Compares secrets in constant time, so they can't be guessed byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Nonces are at least 128 bits of randomness in hex. The upper bound only keeps a
+// printed handshake from making us store arbitrarily long strings.
+const MIN_NONCE_LEN: usize = 32;
+const MAX_NONCE_LEN: usize = 128;
+
+/// Which prodlog commands a session accepts. Each script sends a fresh random nonce in
+/// its handshake and gets back a key derived from the session's secret and that nonce,
+/// which it signs its commands with, along with the nonce and a counter it increments
+/// for every command. A handshake with a nonce that was already used gets no reply, and
+/// a command is only accepted once, so neither can be replayed by printing an old one.
+/// Scripts from before 2.11 can't sign their commands, so their commands are only
+/// accepted with --allow-legacy-scripts, and only as long as no script was given a key:
+/// a handshake printed by e.g. `cat` could otherwise turn authentication off.
+pub struct SessionAuth {
+    secret: [u8; 32],
+    // The last counter accepted from each script, by the nonce of its handshake
+    scripts: HashMap<String, u64>,
+    allow_legacy_scripts: bool,
+    accept_unauthenticated: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeRefusal {
+    // A script that can't sign its commands, without --allow-legacy-scripts
+    LegacyNotAllowed,
+    // A script that can't sign its commands, after a key was given to one that can
+    LegacyAfterKeyIssued,
+    // A script that signs its commands, but didn't send a valid nonce to get its key for
+    MissingNonce,
+    // A handshake whose nonce was used before, so probably one being printed again
+    NonceReused,
+}
+
+impl SessionAuth {
+    pub fn new(allow_legacy_scripts: bool) -> Self {
+        SessionAuth {
+            secret: rand::random(),
+            scripts: HashMap::new(),
+            allow_legacy_scripts,
+            accept_unauthenticated: false,
+        }
+    }
+
+    /// The key the script that sent `nonce` in its handshake signs its commands with.
+    fn script_key(&self, nonce: &str) -> String {
+        hmac_sha256_hex(&self.secret, nonce)
+    }
+
+    /// Handles a handshake from a script that can (`has_mac`) or can't sign its
+    /// commands. Returns the key to reply with to scripts that can.
+    pub fn handshake(&mut self, has_mac: bool, nonce: Option<&str>) -> Result<Option<String>, HandshakeRefusal> {
+        if has_mac {
+            let nonce = nonce
+                .filter(|nonce| (MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len()))
+                .filter(|nonce| nonce.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or(HandshakeRefusal::MissingNonce)?;
+            if self.scripts.contains_key(nonce) {
+                return Err(HandshakeRefusal::NonceReused);
+            }
+            self.scripts.insert(nonce.to_string(), 0);
+            self.accept_unauthenticated = false;
+            Ok(Some(self.script_key(nonce)))
+        } else if !self.allow_legacy_scripts {
+            Err(HandshakeRefusal::LegacyNotAllowed)
+        } else if !self.scripts.is_empty() {
+            Err(HandshakeRefusal::LegacyAfterKeyIssued)
+        } else {
+            self.accept_unauthenticated = true;
+            Ok(None)
+        }
+    }
+
+    /// Whether to act on a command. `signature` is what followed the `@` of a signed
+    /// command: NONCE.COUNTER.MAC, where the MAC is the HMAC-SHA256 of
+    /// NONCE.COUNTER.MESSAGE with the script's key. Accepting a command uses up its
+    /// counter, so it's only accepted once.
+    pub fn accepts(&mut self, message: &str, signature: Option<&str>) -> bool {
+        let Some(signature) = signature else {
+            return self.accept_unauthenticated;
+        };
+        let mut parts = signature.splitn(3, '.');
+        let (Some(nonce), Some(counter), Some(mac)) = (parts.next(), parts.next(), parts.next()) else {
+            return false;
+        };
+        let Some(&last_counter) = self.scripts.get(nonce) else {
+            return false;
+        };
+        let Ok(counter) = counter.parse::<u64>() else {
+            return false;
+        };
+        let expected = hmac_sha256_hex(
+            self.script_key(nonce).as_bytes(),
+            &format!("{}.{}.{}", nonce, counter, message)
+        );
+        if counter <= last_counter || !constant_time_eq(expected.as_bytes(), mac.as_bytes()) {
+            return false;
+        }
+        self.scripts.insert(nonce.to_string(), counter);
+        true
+    }
+}
+
 pub fn compare_major_minor_versions(version1: &str, version2: &str) -> bool {
     let v1_parts: Vec<&str> = version1.split('.').collect();
     let v2_parts: Vec<&str> = version2.split('.').collect();
@@ -33,6 +193,18 @@ pub fn compare_major_minor_versions(version1: &str, version2: &str) -> bool {
     v1_parts[0] == v2_parts[0] && v1_parts[1] == v2_parts[1]
 }
 
+/// Parses the major and minor number of a version like "2.9.0".
+pub fn parse_major_minor_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+pub fn major_minor_version(version: &str) -> String {
+    version.splitn(3, '.').take(2).collect::<Vec<_>>().join(".")
+}
+
 /// Redacts passwords from all fields of an entry
 /// Returns true if any redaction occurred
 pub fn redact_passwords_from_entry(entry: &mut CaptureV2_4, passwords: &[String]) -> bool {
@@ -47,18 +219,26 @@ pub fn redact_passwords_from_entry(entry: &mut CaptureV2_4, passwords: &[String]
     }
 
     // Redact passwords in captured output
-    let output_str = String::from_utf8_lossy(&entry.captured_output);
-    let mut new_output = output_str.to_string();
-    let mut output_modified = false;
-    for password in passwords {
-        if new_output.contains(password) {
-            new_output = new_output.replace(password, "[REDACTED]");
-            output_modified = true;
+    if !entry.events.is_empty() {
+        // The output is stored as timed events, so that's where it needs to be redacted.
+        if redact_passwords_from_events(&mut entry.events, passwords) {
+            entry.captured_output = entry.output_from_events();
             redacted = true;
         }
-    }
-    if output_modified {
-        entry.captured_output = new_output.into_bytes();
+    } else {
+        let output_str = String::from_utf8_lossy(&entry.captured_output);
+        let mut new_output = output_str.to_string();
+        let mut output_modified = false;
+        for password in passwords {
+            if new_output.contains(password) {
+                new_output = new_output.replace(password, "[REDACTED]");
+                output_modified = true;
+                redacted = true;
+            }
+        }
+        if output_modified {
+            entry.captured_output = new_output.into_bytes();
+        }
     }
 
     // Redact passwords in original content (for edit entries)
@@ -95,16 +275,168 @@ pub fn redact_passwords_from_entry(entry: &mut CaptureV2_4, passwords: &[String]
         }
     }
 
+    // Redact passwords in facts, since they can include environment variables
+    for value in entry.facts.values_mut() {
+        for password in passwords {
+            if value.contains(password) {
+                *value = value.replace(password, "[REDACTED]");
+                redacted = true;
+            }
+        }
+    }
+
+    // Redact passwords in the other files of multi-file edits
+    for file in entry.additional_files.iter_mut() {
+        for content in [&mut file.original_content, &mut file.edited_content] {
+            if redact_passwords_from_content(content, passwords) {
+                redacted = true;
+            }
+        }
+    }
+
+    redacted
+}
+
+fn redact_passwords_from_content(content: &mut Vec<u8>, passwords: &[String]) -> bool {
+    let mut new_content = String::from_utf8_lossy(content).to_string();
+    let mut modified = false;
+    for password in passwords {
+        if new_content.contains(password) {
+            new_content = new_content.replace(password, "[REDACTED]");
+            modified = true;
+        }
+    }
+    if modified {
+        *content = new_content.into_bytes();
+    }
+    modified
+}
+
+/// Redacts passwords from the output and input events of an entry or session.
+/// Returns true if any redaction occurred
+pub fn redact_passwords_from_events(events: &mut Vec<CaptureEvent>, passwords: &[String]) -> bool {
+    merge_straddled_events(events, passwords, false);
+    merge_straddled_events(events, passwords, true);
+
+    let mut redacted = false;
+    for event in events.iter_mut() {
+        if let CaptureEventKind::Output(data) | CaptureEventKind::Input(data) = &mut event.kind {
+            for password in passwords {
+                if let Some(new_data) = replace_bytes(data, password.as_bytes(), b"[REDACTED]") {
+                    *data = new_data;
+                    redacted = true;
+                }
+            }
+        }
+    }
     redacted
 }
 
+fn event_data(kind: &CaptureEventKind, input: bool) -> Option<&Vec<u8>> {
+    match kind {
+        CaptureEventKind::Output(data) if !input => Some(data),
+        CaptureEventKind::Input(data) if input => Some(data),
+        _ => None,
+    }
+}
+
+fn event_data_mut(kind: &mut CaptureEventKind, input: bool) -> Option<&mut Vec<u8>> {
+    match kind {
+        CaptureEventKind::Output(data) if !input => Some(data),
+        CaptureEventKind::Input(data) if input => Some(data),
+        _ => None,
+    }
+}
+
+/// Output is recorded in whatever chunks we happened to read from the pty, and input
+/// usually one keystroke at a time, so a password may be split over several events.
+/// Merge those events into the first one so the password can be replaced as a whole.
+/// Output and input are merged separately, depending on `input`.
+fn merge_straddled_events(events: &mut Vec<CaptureEvent>, passwords: &[String], input: bool) {
+    // (event index, start, end) of each event in the concatenated data
+    let mut ranges = Vec::new();
+    let mut output = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        if let Some(data) = event_data(&event.kind, input) {
+            ranges.push((i, output.len(), output.len() + data.len()));
+            output.extend_from_slice(data);
+        }
+    }
+
+    // For each event, the last event it needs to be merged with.
+    let mut merge_until: Vec<usize> = (0..ranges.len()).collect();
+    let range_index_of = |pos: usize| ranges.partition_point(|&(_, _, end)| end <= pos);
+    for password in passwords {
+        let password = password.as_bytes();
+        if password.is_empty() || password.len() > output.len() {
+            continue;
+        }
+        for (start, window) in output.windows(password.len()).enumerate() {
+            if window == password {
+                let first = range_index_of(start);
+                let last = range_index_of(start + password.len() - 1);
+                merge_until[first] = merge_until[first].max(last);
+            }
+        }
+    }
+
+    let mut absorbed = vec![false; events.len()];
+    let mut appended: Vec<Vec<u8>> = vec![Vec::new(); events.len()];
+    let mut target: Option<(usize, usize)> = None;
+    for (range_index, &(event_index, start, end)) in ranges.iter().enumerate() {
+        match target {
+            Some((target_event, last)) if range_index <= last => {
+                appended[target_event].extend_from_slice(&output[start..end]);
+                absorbed[event_index] = true;
+                target = Some((target_event, last.max(merge_until[range_index])));
+            }
+            _ => {
+                target = if merge_until[range_index] > range_index {
+                    Some((event_index, merge_until[range_index]))
+                } else {
+                    None
+                };
+            }
+        }
+    }
+
+    let mut index = 0;
+    events.retain_mut(|event| {
+        let keep = !absorbed[index];
+        if let Some(data) = event_data_mut(&mut event.kind, input) {
+            data.append(&mut appended[index]);
+        }
+        index += 1;
+        keep
+    });
+}
+
+fn replace_bytes(haystack: &[u8], needle: &[u8], replacement: &[u8]) -> Option<Vec<u8>> {
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return None;
+    }
+    let mut result = Vec::with_capacity(haystack.len());
+    let mut found = false;
+    let mut pos = 0;
+    while pos < haystack.len() {
+        if haystack[pos..].starts_with(needle) {
+            result.extend_from_slice(replacement);
+            pos += needle.len();
+            found = true;
+        } else {
+            result.push(haystack[pos]);
+            pos += 1;
+        }
+    }
+    if found { Some(result) } else { None }
+}
+
 pub fn unescape_and_unquote_cmd(raw_cmd: &str) -> String {
     let mut result = Vec::new();
     let mut current_element = String::new();
     let mut escaped = false;
 
-    let mut chars = raw_cmd.chars().peekable();
-    while let Some(ch) = chars.next() {
+    for ch in raw_cmd.chars() {
         if escaped {
             // Previous character was a backslash: unescape this character
             current_element.push(ch);
@@ -159,6 +491,75 @@ fn unquote_element(element: &str) -> String {
 mod tests {
     use super::*;
 
+    const NONCE: &str = "00112233445566778899aabbccddeeff";
+    const OTHER_NONCE: &str = "ffeeddccbbaa99887766554433221100";
+
+    fn sign(key: &str, nonce: &str, counter: u64, message: &str) -> String {
+        let mac = hmac_sha256_hex(key.as_bytes(), &format!("{}.{}.{}", nonce, counter, message));
+        format!("{}.{}.{}", nonce, counter, mac)
+    }
+
+    #[test]
+    fn test_legacy_handshake_needs_opt_in() {
+        let mut auth = SessionAuth::new(false);
+        assert_eq!(auth.handshake(false, None), Err(HandshakeRefusal::LegacyNotAllowed));
+        assert!(!auth.accepts("PRODLOG_STOP_CAPTURE", None));
+        let mut auth = SessionAuth::new(true);
+        assert_eq!(auth.handshake(false, None), Ok(None));
+        assert!(auth.accepts("PRODLOG_STOP_CAPTURE", None));
+        // A newer script turns authentication back on
+        let key = auth.handshake(true, Some(NONCE)).unwrap().unwrap();
+        assert!(!auth.accepts("PRODLOG_STOP_CAPTURE", None));
+        assert!(auth.accepts("PRODLOG_STOP_CAPTURE", Some(&sign(&key, NONCE, 1, "PRODLOG_STOP_CAPTURE"))));
+    }
+
+    #[test]
+    fn test_handshake_cant_turn_auth_off_after_key_was_issued() {
+        let mut auth = SessionAuth::new(true);
+        assert!(auth.handshake(true, Some(NONCE)).is_ok());
+        assert_eq!(auth.handshake(false, None), Err(HandshakeRefusal::LegacyAfterKeyIssued));
+        assert!(!auth.accepts("PRODLOG_STOP_CAPTURE", None));
+    }
+
+    #[test]
+    fn test_handshake_needs_a_fresh_nonce() {
+        let mut auth = SessionAuth::new(false);
+        assert_eq!(auth.handshake(true, None), Err(HandshakeRefusal::MissingNonce));
+        assert_eq!(auth.handshake(true, Some("0123")), Err(HandshakeRefusal::MissingNonce));
+        assert_eq!(auth.handshake(true, Some(&"x".repeat(32))), Err(HandshakeRefusal::MissingNonce));
+        let key = auth.handshake(true, Some(NONCE)).unwrap().unwrap();
+        assert_eq!(auth.handshake(true, Some(NONCE)), Err(HandshakeRefusal::NonceReused));
+        // Each script gets a key of its own, which the session's secret can't be read from
+        let other_key = auth.handshake(true, Some(OTHER_NONCE)).unwrap().unwrap();
+        assert_ne!(key, other_key);
+        assert!(!key.contains(&auth.secret.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+        // and can't sign the commands of another script
+        assert!(!auth.accepts("PRODLOG_STOP_CAPTURE", Some(&sign(&other_key, NONCE, 1, "PRODLOG_STOP_CAPTURE"))));
+        assert!(auth.accepts("PRODLOG_STOP_CAPTURE", Some(&sign(&other_key, OTHER_NONCE, 1, "PRODLOG_STOP_CAPTURE"))));
+        // Nor can the key of a script from another session
+        let key_elsewhere = SessionAuth::new(false).handshake(true, Some(NONCE)).unwrap().unwrap();
+        assert!(!auth.accepts("PRODLOG_STOP_CAPTURE", Some(&sign(&key_elsewhere, NONCE, 1, "PRODLOG_STOP_CAPTURE"))));
+    }
+
+    #[test]
+    fn test_replayed_commands_are_refused() {
+        let mut auth = SessionAuth::new(false);
+        let key = auth.handshake(true, Some(NONCE)).unwrap().unwrap();
+        let start = sign(&key, NONCE, 1, "START CAPTURE RUN:aGk=");
+        let stop = sign(&key, NONCE, 2, "STOP CAPTURE RUN:MA==");
+        // Signed for another message, or with the counter changed
+        assert!(!auth.accepts("STOP CAPTURE RUN:MA==", Some(&start)));
+        assert!(!auth.accepts("START CAPTURE RUN:aGk=", Some(&start.replacen(".1.", ".5.", 1))));
+        assert!(auth.accepts("START CAPTURE RUN:aGk=", Some(&start)));
+        assert!(!auth.accepts("START CAPTURE RUN:aGk=", Some(&start)));
+        assert!(auth.accepts("STOP CAPTURE RUN:MA==", Some(&stop)));
+        // Older commands stay refused once newer ones were accepted
+        assert!(!auth.accepts("START CAPTURE RUN:aGk=", Some(&start)));
+        assert!(!auth.accepts("STOP CAPTURE RUN:MA==", Some(&stop)));
+        assert!(!auth.accepts("START CAPTURE RUN:aGk=", Some(&sign(&key, NONCE, 0, "START CAPTURE RUN:aGk="))));
+        assert!(auth.accepts("START CAPTURE RUN:aGk=", Some(&sign(&key, NONCE, 3, "START CAPTURE RUN:aGk="))));
+    }
+
     #[test]
     fn test_unescape_and_unquote_cmd_simple() {
         // The command received from the prodlog script will have every element quoted and spaces and backslashes escaped.
@@ -167,4 +568,80 @@ mod tests {
         assert_eq!(unescape_and_unquote_cmd("'echo' 'hello\\ world'"), "echo 'hello world'");
         assert_eq!(unescape_and_unquote_cmd("'echo' '\\\\'"), "echo '\\'");
     }
+
+    #[test]
+    fn test_redact_password_split_over_output_events() {
+        let output = |offset_ms, data: &str| CaptureEvent {
+            offset_ms,
+            kind: CaptureEventKind::Output(data.as_bytes().to_vec()),
+        };
+        let mut events = vec![
+            output(0, "login: se"),
+            CaptureEvent { offset_ms: 5, kind: CaptureEventKind::Resize { cols: 80, rows: 24 } },
+            output(10, "cr"),
+            output(20, "et\r\nok"),
+            output(30, "done"),
+        ];
+        assert!(redact_passwords_from_events(&mut events, &["secret".to_string()]));
+        assert_eq!(events, vec![
+            output(0, "login: [REDACTED]\r\nok"),
+            CaptureEvent { offset_ms: 5, kind: CaptureEventKind::Resize { cols: 80, rows: 24 } },
+            output(30, "done"),
+        ]);
+    }
+
+    #[test]
+    fn test_named_args_repeated_and_prefixed() {
+        let args: Vec<String> = ["id=1", "fact.os=Debian 12", "tag=db", "fact.git.head=abc", "fact=x", "tag=rollback", "positional"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let named_args = NamedArgs::parse(&args);
+        assert_eq!(named_args.get("id"), Some("1"));
+        assert_eq!(
+            named_args.with_prefix("fact.").collect::<Vec<_>>(),
+            vec![("os", "Debian 12"), ("git.head", "abc")]
+        );
+        assert_eq!(named_args.get_all("tag").collect::<Vec<_>>(), vec!["db", "rollback"]);
+    }
+
+    #[test]
+    fn test_parse_major_minor_version() {
+        assert_eq!(parse_major_minor_version("2.10.0"), Some((2, 10)));
+        assert_eq!(parse_major_minor_version("2.9"), Some((2, 9)));
+        assert_eq!(parse_major_minor_version("2"), None);
+        assert_eq!(parse_major_minor_version("x.1.0"), None);
+    }
+
+    #[test]
+    fn test_hmac_matches_the_prodlog_script() {
+        // What the hmac_sha256 function of the prodlog script prints for this key and
+        // message, checked with python's hmac module
+        let key = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let mac = "161fdc7361120bc34383e9105fffa8d93ca3788d2504ac04452dbbf652bc2aa1";
+        assert_eq!(hmac_sha256_hex(key.as_bytes(), "nonce.1.START CAPTURE RUN:aGk="), mac);
+    }
+
+    #[test]
+    fn test_redact_password_typed_as_keystrokes() {
+        let input = |offset_ms, data: &str| CaptureEvent {
+            offset_ms,
+            kind: CaptureEventKind::Input(data.as_bytes().to_vec()),
+        };
+        let output = |offset_ms, data: &str| CaptureEvent {
+            offset_ms,
+            kind: CaptureEventKind::Output(data.as_bytes().to_vec()),
+        };
+        // Each keystroke is echoed back by the terminal
+        let mut events = vec![
+            input(0, "p"), output(1, "p"),
+            input(10, "w"), output(11, "w"),
+            input(20, "\r"), output(21, "\r\n"),
+        ];
+        assert!(redact_passwords_from_events(&mut events, &["pw".to_string()]));
+        assert_eq!(events, vec![
+            input(0, "[REDACTED]"), output(1, "[REDACTED]"),
+            input(20, "\r"), output(21, "\r\n"),
+        ]);
+    }
 }
@@ -1,13 +1,17 @@
 use nix::sys::wait::waitpid;
 use sinks::Sink;
 use termion::color::Color;
+use std::collections::{ BTreeMap, HashMap };
 use std::fs::File;
 use std::io::{ Read, Stdout, Write };
-use std::os::fd::{ AsRawFd, RawFd };
+use std::os::fd::{ AsRawFd, BorrowedFd, RawFd };
 use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
 use termion::raw::{ IntoRawMode, RawTerminal };
 use termion::input::TermReadEventsAndRaw;
 use nix::pty::{ ForkptyResult, Winsize };
+use nix::sys::termios::{ tcgetattr, LocalFlags };
 use nix::ioctl_write_ptr_bad;
 use termion::terminal_size;
 use tokio::sync::{ mpsc, RwLock };
@@ -17,20 +21,27 @@ use std::ffi::CString;
 use chrono::Utc;
 use termion::{ color, style };
 use std::fs;
-use std::path::PathBuf;
-use dirs;
+use std::path::{ Path, PathBuf };
 use uuid::Uuid;
-use whoami;
-use model::{ CaptureType, CaptureV2_4 };
+use model::{ CaptureEvent, CaptureEventKind, CaptureStatus, CaptureType, CaptureV2_4, EditedFile, Session };
 
-use crate::config::get_config;
-use crate::helpers::unescape_and_unquote_cmd;
+use crate::config::{ get_config, Command };
+use crate::escape::{ EscapeCommand, EscapeHandler, InputAction };
+use crate::helpers::{ unescape_and_unquote_cmd, NamedArgs };
+use crate::output_limits::{ OutputCompactor, OutputLimits };
+use crate::shell_marks::{ ShellMark, ShellMarkParser };
+use crate::ui::live::LiveHub;
 
 mod ui;
 mod sinks;
 mod helpers;
 mod config;
 mod model;
+mod cast;
+mod noop;
+mod escape;
+mod shell_marks;
+mod output_limits;
 
 const PRODLOG_CMD_PREFIX: &[u8] = "\x1A(dd0d3038-1d43-11f0-9761-022486cd4c38) PRODLOG:".as_bytes();
 const CMD_CHECK_IS_ACTIVE: &str = "IS CURRENTLY INACTIVE";
@@ -43,11 +54,63 @@ const CMD_TASK_LIST: &str = "TASK LIST";
 const CMD_TASK_START_NEW: &str = "TASK START NEW";
 const CMD_SET_ACTIVE_TASK: &str = "TASK SET ACTIVE";
 const CMD_UNSET_ACTIVE_TASK: &str = "TASK UNSET ACTIVE";
-const REPLY_YES_PRODLOG_IS_RUNNING: &[u8] = "PRODLOG IS RUNNING\n".as_bytes();
+const CMD_FILE_CHUNK: &str = "FILE CHUNK";
+const REPLY_YES_PRODLOG_IS_RUNNING: &str = "PRODLOG IS RUNNING";
+const ARG_CAPTURE_ID: &str = "id";
+const ARG_PARENT_CAPTURE_ID: &str = "parent";
+const ARG_CAPABILITIES: &str = "caps";
+const ARG_NONCE: &str = "nonce";
+const ARG_CONTENT_TRANSFER_ID: &str = "content";
+const ARG_CONTENT_SHA256: &str = "sha256";
+// Multi-file edits send the name of each file after the first one as file.N, along
+// with content.N and sha256.N.
+const ARG_FILENAME: &str = "file";
+// Facts about the remote environment are sent as fact.<key>, e.g. fact.kernel. Any
+// key is accepted, so the script can report new facts without a recorder update.
+const ARG_FACT_PREFIX: &str = "fact.";
+// Tags and custom fields given with prodlog run -t/-f. A tag can be given more than once.
+const ARG_TAG: &str = "tag";
+const ARG_CUSTOM_FIELD_PREFIX: &str = "field.";
+// Protocol features a prodlog script can support. Scripts announce theirs in the
+// handshake, so we can work with scripts older or newer than ourselves.
+const CAP_CAPTURE_ID: &str = "capture-id";
+const CAP_SESSION_MAC: &str = "mac";
+const CAP_SESSION_NONCE: &str = "nonce";
+const CAP_FILE_CHUNKS: &str = "file-chunks";
+const CAP_MULTI_FILE: &str = "multi-file";
+const CAP_FACTS: &str = "facts";
+const CAP_TAGS: &str = "tags";
+const CAPABILITIES: &[(&str, &str)] = &[
+    (CAP_CAPTURE_ID, "telling nested captures apart"),
+    (CAP_SESSION_MAC, "authenticating its commands"),
+    (CAP_SESSION_NONCE, "signing its commands so they can't be replayed"),
+    (CAP_FILE_CHUNKS, "sending large files in checked chunks"),
+    (CAP_MULTI_FILE, "editing several files at once"),
+    (CAP_FACTS, "recording facts about the remote environment"),
+    (CAP_TAGS, "tagging entries"),
+];
+// Output is written to the sink when this much is pending, and otherwise every
+// EVENT_FLUSH_INTERVAL.
+const EVENT_FLUSH_BYTES: usize = 64 * 1024;
+const EVENT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// Output that couldn't be written is kept and written with the next flush, but only
+// up to this much, so a sink that stays down doesn't use up all memory.
+const MAX_UNWRITTEN_EVENT_BYTES: usize = 64 * 1024 * 1024;
+
+struct ReceivedCommand {
+    cmd: String,
+    args: Vec<String>,
+    // What followed the @ of a signed command, see SessionAuth::accepts.
+    signature: Option<String>,
+    // What the signature covers: the command and its encoded arguments.
+    signed_message: String,
+    // The command as it appeared in the output, without the prefix and terminator.
+    raw: String,
+}
 
 enum StreamState {
     InProgress(String),
-    Completed(String, Vec<String>, usize),
+    Completed(ReceivedCommand, usize),
 }
 
 enum StdoutHandlerState {
@@ -55,27 +118,268 @@ enum StdoutHandlerState {
     MatchingPrefix(usize),
     ReadingProdlogCommand(StreamState),
 }
+struct ActiveCapture {
+    // The id the remote script uses to refer to this capture. Older scripts don't send one.
+    remote_id: Option<String>,
+    // Its events are the ones that haven't been written to the sink yet.
+    capture: CaptureV2_4,
+    pending_bytes: usize,
+    // Whether the last write to the sink failed, which is then only retried every
+    // EVENT_FLUSH_INTERVAL.
+    write_failed: bool,
+    source: CaptureSource,
+    output: OutputCompactor,
+    // How many of its events were written to the sink. What the output limits hold
+    // back is stored after them while the capture runs, so a crash doesn't lose it.
+    written_events: usize,
+    // Whether what's held back changed since it was stored, and whether it's stored, so
+    // that the events after the written ones are replaced rather than appended to.
+    held_changed: bool,
+    held_stored: bool,
+}
+
+#[derive(PartialEq)]
+enum CaptureSource {
+    // A prodlog command from the prodlog script
+    Script,
+    // A shell integration mark
+    ShellMarks,
+    // An escape command typed by the user
+    Manual,
+}
+
+impl ActiveCapture {
+    fn new(remote_id: Option<String>, capture: CaptureV2_4, source: CaptureSource, output: OutputCompactor) -> Self {
+        ActiveCapture {
+            remote_id,
+            capture,
+            pending_bytes: 0,
+            write_failed: false,
+            source,
+            output,
+            written_events: 0,
+            held_changed: false,
+            held_stored: false,
+        }
+    }
+
+    fn offset_ms(&self) -> u64 {
+        Utc::now()
+            .signed_duration_since(self.capture.start_time)
+            .num_milliseconds()
+            .max(0) as u64
+    }
+
+    fn record_event(&mut self, kind: CaptureEventKind) {
+        for event in self.output.push(CaptureEvent { offset_ms: self.offset_ms(), kind }) {
+            self.push_event(event);
+        }
+        self.held_changed |= self.output.is_holding_back();
+    }
+
+    fn push_event(&mut self, event: CaptureEvent) {
+        if let CaptureEventKind::Output(data) | CaptureEventKind::Input(data) = &event.kind {
+            self.pending_bytes += data.len();
+        }
+        self.capture.events.push(event);
+    }
+
+    // Writes the rest of the events when the capture ends, including the output that
+    // was held back by the output limits.
+    fn finish_events(&mut self, sink: &dyn sinks::Sink) {
+        for event in self.output.finish(self.offset_ms()) {
+            self.push_event(event);
+        }
+        self.held_changed = false;
+        self.flush_events(sink);
+        if !self.capture.events.is_empty() {
+            print_prodlog_warning(
+                &format!("Couldn't write the last {} bytes of output of {} to the sink", self.pending_bytes, self.capture.uuid)
+            );
+            self.capture.events.clear();
+            self.pending_bytes = 0;
+        }
+    }
+
+    fn flush_events(&mut self, sink: &dyn sinks::Sink) {
+        if !self.capture.events.is_empty() {
+            let written = if self.held_stored {
+                sink.replace_events_after(self.capture.uuid, self.written_events, &self.capture.events)
+            } else {
+                sink.append_events(self.capture.uuid, &self.capture.events)
+            };
+            match written {
+                Ok(()) => {
+                    self.write_failed = false;
+                    self.written_events += self.capture.events.len();
+                    if self.held_stored {
+                        self.held_stored = false;
+                        self.held_changed = self.output.is_holding_back();
+                    }
+                }
+                Err(e) => {
+                    self.warn_write_failed(&e);
+                    if self.pending_bytes < MAX_UNWRITTEN_EVENT_BYTES {
+                        return;
+                    }
+                    print_prodlog_warning(
+                        &format!("Dropping {} bytes of output of {} that couldn't be written to the sink", self.pending_bytes, self.capture.uuid)
+                    );
+                }
+            }
+            self.capture.events.clear();
+            self.pending_bytes = 0;
+        }
+        if self.held_changed {
+            let held = self.output.held(self.offset_ms());
+            match sink.replace_events_after(self.capture.uuid, self.written_events, &held) {
+                Ok(()) => {
+                    self.write_failed = false;
+                    self.held_changed = false;
+                    self.held_stored = true;
+                }
+                Err(e) => self.warn_write_failed(&e),
+            }
+        }
+    }
+
+    fn warn_write_failed(&mut self, e: &std::io::Error) {
+        if !self.write_failed {
+            print_prodlog_warning(&format!("Error writing output to sink, will retry: {}", e));
+            self.write_failed = true;
+        }
+    }
+}
+
+// The recording of the whole session, when running with --record-session.
+struct RecordedSession {
+    // Its events are the ones that haven't been written to the sink yet.
+    session: Session,
+    pending_bytes: usize,
+    write_failed: bool,
+}
+
+impl RecordedSession {
+    fn start(sink: &dyn sinks::Sink) -> Self {
+        let mut session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: Utc::now(),
+            duration_ms: 0,
+            status: CaptureStatus::InProgress,
+            local_user: whoami::username(),
+            cmd: shell_words::join(get_config().command_line().unwrap_or_default()),
+            events: StdoutHandler::initial_events(),
+        };
+        if let Err(e) = sink.begin_session(&session) {
+            print_prodlog_warning(&format!("Error writing session to sink: {}", e));
+        }
+        session.events.clear();
+        RecordedSession { session, pending_bytes: 0, write_failed: false }
+    }
+
+    fn record_event(&mut self, kind: CaptureEventKind) {
+        let offset_ms = Utc::now()
+            .signed_duration_since(self.session.start_time)
+            .num_milliseconds()
+            .max(0) as u64;
+        if let CaptureEventKind::Output(data) | CaptureEventKind::Input(data) = &kind {
+            self.pending_bytes += data.len();
+        }
+        self.session.events.push(CaptureEvent { offset_ms, kind });
+    }
+
+    fn flush_events(&mut self, sink: &dyn sinks::Sink) {
+        if self.session.events.is_empty() {
+            return;
+        }
+        match sink.append_session_events(self.session.uuid, &self.session.events) {
+            Ok(()) => self.write_failed = false,
+            Err(e) => {
+                if !self.write_failed {
+                    print_prodlog_warning(&format!("Error writing session output to sink, will retry: {}", e));
+                    self.write_failed = true;
+                }
+                if self.pending_bytes < MAX_UNWRITTEN_EVENT_BYTES {
+                    return;
+                }
+                print_prodlog_warning(
+                    &format!("Dropping {} bytes of session output that couldn't be written to the sink", self.pending_bytes)
+                );
+            }
+        }
+        self.session.events.clear();
+        self.pending_bytes = 0;
+    }
+
+    fn finish(&mut self, sink: &dyn sinks::Sink) {
+        self.flush_events(sink);
+        if !self.session.events.is_empty() {
+            print_prodlog_warning(
+                &format!("Couldn't write the last {} bytes of session output to the sink", self.pending_bytes)
+            );
+            self.session.events.clear();
+        }
+        self.session.status = CaptureStatus::Completed;
+        self.session.duration_ms = Utc::now()
+            .signed_duration_since(self.session.start_time)
+            .num_milliseconds()
+            .max(0) as u64;
+        if let Err(e) = sink.finalize_session(&self.session) {
+            print_prodlog_warning(&format!("Error writing session to sink: {}", e));
+        }
+    }
+}
+
+// A file the prodlog script is sending with FILE CHUNK commands, before the START or
+// STOP CAPTURE EDIT that refers to it.
+#[derive(Default)]
+struct FileTransfer {
+    content: Vec<u8>,
+    next_seq: u64,
+    // Sequence numbers that were skipped, which means chunks were lost.
+    missing_chunks: Vec<u64>,
+}
+
 struct StdoutHandler {
-    stdout: RawTerminal<Stdout>,
+    // The terminal in raw mode, which is restored when this is dropped.
+    stdout: Box<dyn Write + Send>,
     child_stdin_tx: mpsc::Sender<Vec<u8>>,
-    capturing: Option<CaptureV2_4>,
+    // Captures can be nested when a captured command runs `prodlog` again, so all
+    // active captures are kept here, innermost last.
+    capturing: Vec<ActiveCapture>,
     state: StdoutHandlerState,
     sink: Box<dyn sinks::Sink>,
+    // Has the key handed to the prodlog script during the handshake. Other commands
+    // must carry a MAC made with it, so text that merely looks like a command is ignored.
+    auth: helpers::SessionAuth,
+    master_fd: RawFd,
+    // Files being received, by transfer id.
+    file_transfers: HashMap<String, FileTransfer>,
+    // Set when hidden input was recorded, so the rest of it isn't recorded again and
+    // the mask doesn't give away how long e.g. a password was. Reset by new output.
+    input_masked: bool,
+    // Mirrors the session to viewers in the web UI.
+    live_hub: Arc<LiveHub>,
+    // Set when recording the whole session.
+    session: Option<RecordedSession>,
+    // Set when captures are started by shell integration marks.
+    shell_marks: Option<ShellMarkParser>,
+    // The host and working directory the shell last reported.
+    shell_location: (String, String),
+    // Labels the entries captured here, when several terminals record to one prodlog.
+    terminal: String,
+    output_limits: OutputLimits,
 }
 
 // TODO unify these different ways of printing messages
 fn prodlog_print<C: Color>(msg: &str, color: C) {
     print!(
-        "{}\n\r",
-        format!(
-            "{}{}{}{}{}{}",
-            style::Bold,
-            color::Fg(color),
-            style::Blink,
-            "PRODLOG: ",
-            style::Reset,
-            msg
-        )
+        "{}{}{}PRODLOG: {}{}\n\r",
+        style::Bold,
+        color::Fg(color),
+        style::Blink,
+        style::Reset,
+        msg
     );
 }
 
@@ -92,24 +396,452 @@ fn print_prodlog_message(msg: &str) {
     prodlog_print(msg, color::Green);
 }
 
+// Like a message, but the user types the answer on the same line.
+fn print_prodlog_prompt(msg: &str) {
+    print!("{}{}{}PRODLOG: {}{}", style::Bold, color::Fg(color::Green), style::Blink, style::Reset, msg);
+    let _ = std::io::stdout().flush();
+}
+
 impl StdoutHandler {
     fn new(
         child_stdin_tx: mpsc::Sender<Vec<u8>>,
         stdout: RawTerminal<Stdout>,
-        sink: Box<dyn sinks::Sink>
+        sink: Box<dyn sinks::Sink>,
+        master_fd: RawFd,
+        live_hub: Arc<LiveHub>,
+        terminal: String
     ) -> Self {
-        Self { child_stdin_tx, stdout, capturing: None, state: StdoutHandlerState::Normal, sink }
+        Self {
+            child_stdin_tx,
+            stdout: Box::new(stdout),
+            capturing: Vec::new(),
+            state: StdoutHandlerState::Normal,
+            sink,
+            auth: helpers::SessionAuth::new(get_config().allow_legacy_scripts),
+            file_transfers: HashMap::new(),
+            master_fd,
+            input_masked: false,
+            live_hub,
+            session: None,
+            shell_marks: get_config().shell_integration.then(ShellMarkParser::default),
+            shell_location: (String::new(), String::new()),
+            terminal,
+            output_limits: get_config().output_limits(),
+        }
     }
 
     fn write_and_flush(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
-        self.stdout.write(buf)?;
+        let marks = match self.shell_marks.as_mut() {
+            Some(parser) => parser.feed(buf),
+            None => Vec::new(),
+        };
+        // Split the output at the marks, so a command's capture starts and ends exactly there
+        let mut start = 0;
+        for (end, mark) in marks {
+            self.output(&buf[start..end])?;
+            start = end;
+            self.handle_shell_mark(mark)?;
+        }
+        self.output(&buf[start..])
+    }
+
+    fn output(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        self.stdout.write_all(buf)?;
         self.stdout.flush()?;
-        if let Some(capture) = &mut self.capturing {
-            capture.captured_output.extend_from_slice(buf);
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.live_hub.output(buf);
+        if let Some(session) = self.session.as_mut() {
+            session.record_event(CaptureEventKind::Output(buf.to_vec()));
+            if session.pending_bytes >= EVENT_FLUSH_BYTES && !session.write_failed {
+                session.flush_events(self.sink.as_ref());
+            }
+        }
+        self.input_masked = false;
+        for active in self.capturing.iter_mut() {
+            active.record_event(CaptureEventKind::Output(buf.to_vec()));
+            if active.pending_bytes >= EVENT_FLUSH_BYTES && !active.write_failed {
+                active.flush_events(self.sink.as_ref());
+            }
+        }
+        Ok(())
+    }
+
+    fn record_input(&mut self, buf: &[u8], hidden: bool) {
+        let kind = if !hidden {
+            CaptureEventKind::Input(buf.to_vec())
+        } else if !self.input_masked {
+            self.input_masked = true;
+            CaptureEventKind::MaskedInput
+        } else {
+            return;
+        };
+        if let Some(session) = self.session.as_mut() {
+            session.record_event(kind.clone());
+        }
+        for active in self.capturing.iter_mut() {
+            active.record_event(kind.clone());
+        }
+    }
+
+    fn receive_file_chunk(&mut self, transfer_id: &str, seq: u64, chunk: &[u8]) {
+        let transfer = self.file_transfers.entry(transfer_id.to_string()).or_default();
+        if seq < transfer.next_seq {
+            // A chunk we already have, e.g. sent twice. Ignore it.
+            return;
+        }
+        transfer.missing_chunks.extend(transfer.next_seq..seq);
+        transfer.content.extend_from_slice(chunk);
+        transfer.next_seq = seq + 1;
+    }
+
+    /// Returns the content of the `index`th file sent along with a START or STOP CAPTURE
+    /// EDIT. Newer scripts send it in chunks beforehand, and refer to it with a transfer
+    /// id and its sha256. If it didn't arrive intact, the second value says what went wrong.
+    fn receive_file_content(
+        &mut self,
+        inline_content: &str,
+        named_args: &NamedArgs,
+        index: usize
+    ) -> (Vec<u8>, Option<String>) {
+        let transfer_id = match named_args.get(&file_arg(ARG_CONTENT_TRANSFER_ID, index)) {
+            Some(transfer_id) => transfer_id,
+            None => return (helpers::base64_decode(inline_content), None),
+        };
+        // No chunks are sent for empty or missing files.
+        let transfer = self.file_transfers.remove(transfer_id).unwrap_or_default();
+        let error = if !transfer.missing_chunks.is_empty() {
+            Some(format!("chunks {:?} were lost", transfer.missing_chunks))
+        } else {
+            match named_args.get(&file_arg(ARG_CONTENT_SHA256, index)) {
+                Some(sha256) if sha256 == helpers::sha256_hex(&transfer.content) => None,
+                Some(_) => Some("checksum mismatch".to_string()),
+                None => Some("no checksum was sent".to_string()),
+            }
+        };
+        (transfer.content, error)
+    }
+
+    /// Adds the files after the first one of a multi-file edit to `capture`.
+    fn receive_additional_original_files(&mut self, capture: &mut CaptureV2_4, named_args: &NamedArgs) {
+        let mut index = 1;
+        while let Some(filename) = named_args.get(&file_arg(ARG_FILENAME, index)) {
+            let (original_content, content_error) = self.receive_file_content("", named_args, index);
+            if let Some(error) = content_error {
+                Self::add_content_error(capture, "original content", filename, &error);
+            }
+            capture.additional_files.push(EditedFile {
+                filename: filename.to_string(),
+                original_content,
+                edited_content: Vec::new(),
+                original_hash: None,
+                edited_hash: None,
+            });
+            index += 1;
+        }
+    }
+
+    fn receive_additional_edited_files(&mut self, capture: &mut CaptureV2_4, named_args: &NamedArgs) {
+        for index in 1..=capture.additional_files.len() {
+            let (edited_content, content_error) = self.receive_file_content("", named_args, index);
+            let filename = capture.additional_files[index - 1].filename.clone();
+            if let Some(error) = content_error {
+                Self::add_content_error(capture, "edited content", &filename, &error);
+            }
+            capture.additional_files[index - 1].edited_content = edited_content;
+        }
+    }
+
+    fn add_content_error(capture: &mut CaptureV2_4, what: &str, filename: &str, error: &str) {
+        print_prodlog_warning(&format!("The {} of {} didn't arrive intact: {}", what, filename, error));
+        let error = format!("{} of {}: {}", what, filename, error);
+        capture.content_error = Some(match capture.content_error.take() {
+            Some(previous_errors) => format!("{}; {}", previous_errors, error),
+            None => error,
+        });
+    }
+
+    fn record_resize(&mut self, cols: u16, rows: u16) {
+        self.live_hub.resize(cols, rows);
+        if let Some(session) = self.session.as_mut() {
+            session.record_event(CaptureEventKind::Resize { cols, rows });
+        }
+        for active in self.capturing.iter_mut() {
+            active.record_event(CaptureEventKind::Resize { cols, rows });
+        }
+    }
+
+    fn flush_all_captures(&mut self) {
+        if let Some(session) = self.session.as_mut() {
+            session.flush_events(self.sink.as_ref());
+        }
+        for active in self.capturing.iter_mut() {
+            active.flush_events(self.sink.as_ref());
+        }
+    }
+
+    fn push_capture(&mut self, mut capture: CaptureV2_4, named_args: &NamedArgs) {
+        let remote_id = named_args.get(ARG_CAPTURE_ID).map(str::to_string);
+        let parent_id = named_args.get(ARG_PARENT_CAPTURE_ID);
+        let parent_index = match parent_id {
+            Some(parent_id) => self.capturing
+                .iter()
+                .rposition(|active| active.remote_id.as_deref() == Some(parent_id)),
+            // Older scripts don't tell us their parent, so assume they're nested in the innermost capture.
+            None if remote_id.is_none() => self.capturing.len().checked_sub(1),
+            // Otherwise it was run in a shell command or manual capture, if any.
+            None => self.capturing.iter().rposition(|active| active.source != CaptureSource::Script),
+        };
+        if remote_id.is_some() && (parent_id.is_none() || parent_index.is_some()) {
+            // Scripts that send ids tell us where this capture belongs. Captures nested
+            // deeper than that were never stopped, e.g. because the connection dropped.
+            let keep = parent_index.map_or(0, |index| index + 1);
+            self.interrupt_captures_from(keep, "a new capture was started before it was stopped");
+        }
+        capture.parent_uuid = parent_index.map(|index| self.capturing[index].capture.uuid);
+        capture.facts = named_args
+            .with_prefix(ARG_FACT_PREFIX)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        capture.tags = named_args.get_all(ARG_TAG).map(str::to_string).collect();
+        capture.custom_fields = named_args
+            .with_prefix(ARG_CUSTOM_FIELD_PREFIX)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.begin_capture(capture, remote_id, CaptureSource::Script);
+        self.drop_abandoned_file_transfers();
+    }
+
+    /// Forgets the files that were being sent for captures that aren't running, e.g.
+    /// because the script was killed while sending them. Scripts start the ids of the
+    /// transfers with the id of their capture, and send the files of a START or STOP
+    /// CAPTURE EDIT right before it.
+    fn drop_abandoned_file_transfers(&mut self) {
+        let capturing = &self.capturing;
+        let before = self.file_transfers.len();
+        self.file_transfers.retain(|transfer_id, _| {
+            capturing
+                .iter()
+                .filter_map(|active| active.remote_id.as_deref())
+                .any(|id| transfer_id.strip_prefix(id).is_some_and(|rest| rest.starts_with('-')))
+        });
+        if self.file_transfers.len() < before {
+            print_prodlog_warning(
+                &format!("Dropping {} files whose sending was never finished", before - self.file_transfers.len())
+            );
+        }
+    }
+
+    fn begin_capture(&mut self, mut capture: CaptureV2_4, remote_id: Option<String>, source: CaptureSource) {
+        // Manual captures are described rather than named by their command
+        if capture.capture_type == CaptureType::Run && source != CaptureSource::Manual {
+            capture.is_noop = noop::matching_rule(&capture).is_some();
+        }
+        capture.status = CaptureStatus::InProgress;
+        capture.terminal = self.terminal.clone();
+        if let Err(e) = self.sink.begin_entry(&capture) {
+            print_prodlog_warning(&format!("Error writing to sink: {}", e));
+        }
+        self.live_hub.capture_started(&capture);
+        let output = OutputCompactor::new(self.output_limits);
+        let mut active = ActiveCapture::new(remote_id, capture, source, output);
+        active.flush_events(self.sink.as_ref());
+        self.capturing.push(active);
+    }
+
+    fn handle_shell_mark(&mut self, mark: ShellMark) -> Result<(), std::io::Error> {
+        match mark {
+            ShellMark::WorkingDirectory { host, path } => {
+                self.shell_location = (host, path);
+            }
+            ShellMark::CommandExecuted { command_line } if !command_line.is_empty() => {
+                let (host, cwd) = &self.shell_location;
+                let mut capture = Self::start_capturing_run(host, cwd, &command_line, "", "")?;
+                // Commands run from a shell inside another capture, e.g. ssh, belong to it
+                capture.parent_uuid = self.capturing.last().map(|active| active.capture.uuid);
+                self.begin_capture(capture, None, CaptureSource::ShellMarks);
+            }
+            ShellMark::CommandFinished { exit_code } => {
+                let Some(index) = self.capturing.iter().rposition(|active| active.source == CaptureSource::ShellMarks) else {
+                    // A command that started before the recording did, or an empty command line
+                    return Ok(());
+                };
+                // Any output can contain a mark, so marks only ever end captures that marks
+                // started, and never interrupt the authenticated captures of the prodlog
+                // script or those made by hand.
+                if let Some(nested) = self.capturing[index + 1..].iter().find(|active| active.source != CaptureSource::ShellMarks) {
+                    print_prodlog_warning(
+                        &format!(
+                            "Ignoring the end of shell command {}, because the capture of {} inside it is still running",
+                            self.capturing[index].capture.cmd,
+                            nested.capture.cmd
+                        )
+                    );
+                    return Ok(());
+                }
+                self.stop_capture_at(index, exit_code.unwrap_or(-1), "the shell command that ran it finished")?;
+            }
+            _ => (),
         }
         Ok(())
     }
 
+    /// Stops the capture at `index`, and interrupts the ones nested in it.
+    fn stop_capture_at(&mut self, index: usize, exit_code: i32, reason: &str) -> Result<CaptureV2_4, std::io::Error> {
+        self.interrupt_captures_from(index + 1, reason);
+        let mut active = self.capturing.remove(index);
+        active.finish_events(self.sink.as_ref());
+        Self::stop_capturing_run(&mut active.capture, exit_code, &mut self.sink)?;
+        self.live_hub.capture_stopped(&active.capture);
+        Ok(active.capture)
+    }
+
+    fn run_escape_command(&mut self, command: EscapeCommand) -> Result<(), std::io::Error> {
+        match command {
+            EscapeCommand::StartCapture(description) => self.start_manual_capture(&description)?,
+            EscapeCommand::StopCapture => self.stop_manual_capture()?,
+            EscapeCommand::ListTasks => self.list_tasks(),
+            EscapeCommand::SetTask(task) if task.is_empty() => self.deactivate_task(),
+            EscapeCommand::SetTask(task) =>
+                match task.parse::<i64>() {
+                    Ok(task_id) => self.activate_task(task_id),
+                    Err(_) => self.start_new_task(&task),
+                }
+            EscapeCommand::AddNote(text) => self.add_note(&text)?,
+            EscapeCommand::Status => self.print_status(),
+            EscapeCommand::Help => {
+                for line in escape::help(&get_config().escape_char) {
+                    print_prodlog_message(&line);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_manual_capture(&mut self, description: &str) -> Result<(), std::io::Error> {
+        if description.is_empty() {
+            print_prodlog_warning("Not capturing without a description");
+            return Ok(());
+        }
+        let (host, cwd) = &self.shell_location;
+        let mut capture = Self::start_capturing_run(host, cwd, description, "", "")?;
+        capture.parent_uuid = self.capturing.last().map(|active| active.capture.uuid);
+        self.begin_capture(capture, None, CaptureSource::Manual);
+        print_prodlog_message(&format!("Capturing everything on screen as: {}", description));
+        Ok(())
+    }
+
+    fn stop_manual_capture(&mut self) -> Result<(), std::io::Error> {
+        let Some(index) = self.capturing.iter().rposition(|active| active.source == CaptureSource::Manual) else {
+            print_prodlog_warning("No manual capture is running");
+            return Ok(());
+        };
+        let capture = self.stop_capture_at(index, 0, "the manual capture around it was stopped")?;
+        print_prodlog_message(&format!("Stopped capturing {}", capture.cmd));
+        Ok(())
+    }
+
+    fn add_note(&mut self, text: &str) -> Result<(), std::io::Error> {
+        if text.is_empty() {
+            print_prodlog_warning("Not adding an empty note");
+            return Ok(());
+        }
+        let (host, cwd) = &self.shell_location;
+        let mut note = Self::start_capturing_run(host, cwd, "", text, "")?;
+        note.capture_type = CaptureType::Note;
+        note.exit_code = 0;
+        note.events.clear();
+        note.terminal = self.terminal.clone();
+        // A note made during a capture is about it
+        note.parent_uuid = self.capturing.last().map(|active| active.capture.uuid);
+        match self.sink.add_new_entry(&note) {
+            Ok(_) => print_prodlog_message("Note added"),
+            Err(e) => print_prodlog_warning(&format!("Error writing note to sink: {}", e)),
+        }
+        Ok(())
+    }
+
+    fn print_status(&mut self) {
+        if self.capturing.is_empty() {
+            print_prodlog_message("Not capturing");
+        }
+        for active in self.capturing.iter() {
+            let capture = &active.capture;
+            let source = match active.source {
+                CaptureSource::Script => "prodlog",
+                CaptureSource::ShellMarks => "shell integration",
+                CaptureSource::Manual => "manual",
+            };
+            let seconds = Utc::now().signed_duration_since(capture.start_time).num_seconds();
+            let location = if capture.host.is_empty() {
+                String::new()
+            } else {
+                format!(" on {}:{}", capture.host, capture.cwd)
+            };
+            print_prodlog_message(&format!("Capturing {}{} for {}s ({})", capture.cmd, location, seconds, source));
+        }
+        self.print_active_task();
+        if let Some(session) = self.session.as_ref() {
+            print_prodlog_message(&format!("Recording the whole session as {}", session.session.uuid));
+        }
+        if self.shell_marks.is_some() {
+            print_prodlog_message("Capturing commands from shell integration marks");
+        }
+        print_prodlog_message(&format!("Web UI on http://localhost:{}", get_config().port));
+    }
+
+    fn pop_capture(&mut self, named_args: &NamedArgs) -> Option<CaptureV2_4> {
+        if let Some(id) = named_args.get(ARG_CAPTURE_ID) {
+            let index = self.capturing
+                .iter()
+                .rposition(|active| active.remote_id.as_deref() == Some(id))?;
+            self.interrupt_captures_from(index + 1, "its parent capture stopped first");
+        }
+        let mut active = self.capturing.pop()?;
+        active.finish_events(self.sink.as_ref());
+        Some(active.capture)
+    }
+
+    /// Saves the captures at `index` and above as interrupted, innermost first.
+    fn interrupt_captures_from(&mut self, index: usize, reason: &str) {
+        if index >= self.capturing.len() {
+            return;
+        }
+        for mut active in self.capturing.split_off(index).into_iter().rev() {
+            active.finish_events(self.sink.as_ref());
+            Self::interrupt_capture(&mut active.capture, reason, &mut self.sink);
+            self.live_hub.capture_stopped(&active.capture);
+        }
+        self.drop_abandoned_file_transfers();
+    }
+
+    fn interrupt_all_captures(&mut self, reason: &str) {
+        self.interrupt_captures_from(0, reason);
+    }
+
+    fn start_recording_session(&mut self) {
+        let session = RecordedSession::start(self.sink.as_ref());
+        print_prodlog_message(&format!("Recording the whole session as {}", session.session.uuid));
+        self.session = Some(session);
+    }
+
+    fn finish_recording_session(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            session.finish(self.sink.as_ref());
+        }
+    }
+
+    fn initial_events() -> Vec<CaptureEvent> {
+        // Record the terminal size at the start, so a replay knows what the first
+        // output was rendered for.
+        match terminal_size() {
+            Ok((cols, rows)) if cols > 0 && rows > 0 =>
+                vec![CaptureEvent { offset_ms: 0, kind: CaptureEventKind::Resize { cols, rows } }],
+            _ => Vec::new(),
+        }
+    }
+
     fn start_capturing_run(
         host: &str,
         cwd: &str,
@@ -130,15 +862,26 @@ impl StdoutHandler {
             message: message.to_string(),
             is_noop: false,
             exit_code: -1,
+            status: CaptureStatus::Completed,
             local_user: whoami::username(),
             remote_user: remote_user.to_string(),
             filename: "".to_string(),
             terminal_rows: 0,
             terminal_cols: 0,
             task_id: None,
+            parent_uuid: None,
+            facts: BTreeMap::new(),
+            tags: Vec::new(),
+            custom_fields: BTreeMap::new(),
+            additional_files: Vec::new(),
+            content_error: None,
+            terminal: String::new(),
             captured_output: Vec::new(),
             original_content: "".as_bytes().to_vec(),
             edited_content: "".as_bytes().to_vec(),
+            original_hash: None,
+            edited_hash: None,
+            events: Self::initial_events(),
         })
     }
 
@@ -154,7 +897,8 @@ impl StdoutHandler {
         let (cols, rows) = terminal_size()?;
         capture.terminal_cols = cols;
         capture.terminal_rows = rows;
-        match sink.add_new_entry(capture) {
+        capture.status = CaptureStatus::Completed;
+        match sink.finalize_entry(capture) {
             Ok(_) => (),
             Err(e) => print_prodlog_message(&format!("Error writing to sink: {}", e)),
         }
@@ -162,6 +906,30 @@ impl StdoutHandler {
         Ok(())
     }
 
+    fn interrupt_capture(capture: &mut CaptureV2_4, reason: &str, sink: &mut Box<dyn sinks::Sink>) {
+        print_prodlog_warning(
+            &format!(
+                "Capture of {} on {}:{} was interrupted because {}. Saving what was captured so far.",
+                capture.cmd,
+                capture.host,
+                capture.cwd,
+                reason
+            )
+        );
+        capture.status = CaptureStatus::Interrupted;
+        capture.duration_ms = Utc::now()
+            .signed_duration_since(capture.start_time)
+            .num_milliseconds() as u64;
+        if let Ok((cols, rows)) = terminal_size() {
+            capture.terminal_cols = cols;
+            capture.terminal_rows = rows;
+        }
+        match sink.finalize_entry(capture) {
+            Ok(_) => (),
+            Err(e) => print_prodlog_message(&format!("Error writing to sink: {}", e)),
+        }
+    }
+
     fn start_capturing_edit(
         host: &str,
         cwd: &str,
@@ -185,14 +953,25 @@ impl StdoutHandler {
             duration_ms: 0,
             is_noop: false,
             exit_code: -1,
+            status: CaptureStatus::Completed,
             local_user: whoami::username(),
             remote_user: remote_user.to_string(),
             filename: filename.to_string(),
-            original_content: original_content,
+            original_content,
             edited_content: "".as_bytes().to_vec(),
+            original_hash: None,
+            edited_hash: None,
             terminal_rows: 0,
             terminal_cols: 0,
             task_id: None,
+            parent_uuid: None,
+            facts: BTreeMap::new(),
+            tags: Vec::new(),
+            custom_fields: BTreeMap::new(),
+            additional_files: Vec::new(),
+            content_error: None,
+            terminal: String::new(),
+            events: Self::initial_events(),
         })
     }
 
@@ -207,7 +986,10 @@ impl StdoutHandler {
             .signed_duration_since(capture.start_time)
             .num_milliseconds() as u64;
         capture.edited_content = edited_content;
-        match sink.add_new_entry(capture) {
+        capture.status = CaptureStatus::Completed;
+        // Whether an edit changed anything is only known now
+        capture.is_noop = noop::matching_rule(capture).is_some();
+        match sink.finalize_entry(capture) {
             Ok(_) => (),
             Err(e) => print_prodlog_message(&format!("Error writing to sink: {}", e)),
         }
@@ -215,6 +997,90 @@ impl StdoutHandler {
         Ok(())
     }
 
+    fn print_active_task(&mut self) {
+        if let Ok(Some(task_id)) = self.sink.get_active_task() {
+            if let Ok(task) = self.sink.get_task_by_id(task_id) {
+                if let Some(task) = task {
+                    let name = task.name.clone();
+                    print_prodlog_message(&format!("Active task: {}", name));
+                } else {
+                    print_prodlog_warning(&format!("Active task set to id {}, but no task with that id found", task_id));
+                }
+            }
+        }
+    }
+
+    fn start_new_task(&mut self, task_name: &str) {
+        // Create and activate a new task
+        if let Ok(task_id) = self.sink.create_task(task_name) {
+            // Set it as active
+            if self.sink.set_active_task(Some(task_id)).is_ok() {
+                print_prodlog_message(&format!("Created and activated task: {}", task_name));
+            } else {
+                print_prodlog_message("Error: Failed to set active task");
+            }
+        } else {
+            print_prodlog_message("Error: Failed to create task");
+        }
+    }
+
+    fn list_tasks(&mut self) {
+        if let Ok(tasks) = self.sink.get_all_tasks() {
+            let active_task_id = self.sink.get_active_task().unwrap_or(None);
+            if tasks.is_empty() {
+                print_prodlog_message("No recent tasks found");
+            } else {
+                print_prodlog_message("Recent tasks:");
+                // Show the 10 tasks with highest ID
+                let mut sorted_tasks: Vec<_> = tasks.into_iter().collect();
+                sorted_tasks.sort_by_key(|task| std::cmp::Reverse(task.id));
+                for task in sorted_tasks.into_iter().take(10) {
+                    if Some(task.id) == active_task_id {
+                        print_prodlog_message(&format!(" (ACTIVE) {}: {}", task.id, task.name));
+                    } else {
+                        print_prodlog_message(&format!("          {}: {}", task.id, task.name));
+                    }
+                }
+            }
+        }
+    }
+
+    fn activate_task(&mut self, task_id: i64) {
+        if let Ok(task) = self.sink.get_task_by_id(task_id) {
+            if let Some(task) = task {
+                if self.sink.set_active_task(Some(task_id)).is_ok() {
+                    print_prodlog_message(&format!("Activated task: {}", task.name));
+                } else {
+                    print_prodlog_message("Error: Failed to set active task");
+                }
+            } else {
+                print_prodlog_warning(&format!("No task with id {} found", task_id));
+            }
+        } else {
+            print_prodlog_warning(&format!("Error retrieving task with id {}", task_id));
+        }
+    }
+
+    fn deactivate_task(&mut self) {
+        if let Ok(previously_active_task_id) = self.sink.get_active_task() {
+            if let Some(previously_active_task_id) = previously_active_task_id {
+                if self.sink.set_active_task(None).is_ok() {
+                    if let Ok(Some(task)) = self.sink.get_task_by_id(previously_active_task_id) {
+                        print_prodlog_message(&format!("Deactivated task: {}. No task is active now.", task.name));
+                    } else {
+                        print_prodlog_message("Deactivated active task. No task is active now.");
+                    }
+                } else {
+                    print_prodlog_warning("Error: Failed to unset active task");
+                }
+            } else {
+                print_prodlog_message("No task was active, nothing to unset.");
+            }
+        } else {
+            print_prodlog_warning("Error getting currently active task");
+        }
+    }
+
     fn read_until_terminator(
         &self,
         buffer: &[u8],
@@ -228,7 +1094,7 @@ impl StdoutHandler {
                 pos += 1;
             }
             let new_value =
-                partial.to_owned() + &String::from_utf8_lossy(&buffer[start..pos]).to_string();
+                partial.to_owned() + &String::from_utf8_lossy(&buffer[start..pos]);
             if pos == n {
                 // Ran out of data, wait for next chunk
                 StreamState::InProgress(new_value)
@@ -236,16 +1102,28 @@ impl StdoutHandler {
                 // Skip terminator
                 pos += 1;
                 let mut split = new_value.splitn(2, ':');
-                let cmd = split.next().unwrap_or("").to_string();
-                let rest = split.next().unwrap_or("");
-                let args: Vec<String> = if rest.is_empty() {
-                    Vec::new()
-                } else {
-                    rest.split(':')
-                        .map(|s| helpers::base64_decode_string(s))
-                        .collect()
+                let cmd_and_mac = split.next().unwrap_or("");
+                let rest = split.next();
+                // Signed commands look like CMD@SIGNATURE:ARG1:ARG2, where the signature
+                // covers CMD:ARG1:ARG2.
+                let (cmd, signature) = match cmd_and_mac.split_once('@') {
+                    Some((cmd, signature)) => (cmd, Some(signature.to_string())),
+                    None => (cmd_and_mac, None),
+                };
+                let signed_message = match rest {
+                    Some(rest) => format!("{}:{}", cmd, rest),
+                    None => cmd.to_string(),
                 };
-                StreamState::Completed(cmd, args, pos)
+                let args: Vec<String> = match rest {
+                    None | Some("") => Vec::new(),
+                    Some(rest) => rest.split(':')
+                        .map(helpers::base64_decode_string)
+                        .collect(),
+                };
+                StreamState::Completed(
+                    ReceivedCommand { cmd: cmd.to_string(), args, signature, signed_message, raw: new_value.clone() },
+                    pos
+                )
             }
         } else {
             prodlog_panic("Invalid state");
@@ -294,50 +1172,104 @@ impl StdoutHandler {
                     }
                 }
                 StdoutHandlerState::ReadingProdlogCommand(stream_state) => {
-                    let stream_state = self.read_until_terminator(buffer, pos, n, &stream_state);
+                    let stream_state = self.read_until_terminator(buffer, pos, n, stream_state);
                     match stream_state {
                         StreamState::InProgress(_) => {
                             self.state = StdoutHandlerState::ReadingProdlogCommand(stream_state);
                             pos = n;
                         }
-                        StreamState::Completed(cmd, args, new_pos) => {
+                        StreamState::Completed(ReceivedCommand { cmd, args, signature, signed_message, raw }, new_pos) => {
                             pos = new_pos;
+                            match cmd.as_str() {
+                                CMD_CHECK_IS_ACTIVE | CMD_ARE_YOU_RUNNING => (),
+                                _ if self.auth.accepts(&signed_message, signature.as_deref()) => (),
+                                _ => {
+                                    // Probably a file or log that contains a prodlog command
+                                    // being printed. Show it, but don't act on it.
+                                    print_prodlog_warning(
+                                        &format!("Ignoring prodlog command without a valid session MAC, or that was seen before: {}", cmd)
+                                    );
+                                    self.write_and_flush(PRODLOG_CMD_PREFIX)?;
+                                    self.write_and_flush(raw.as_bytes())?;
+                                    self.write_and_flush(b";")?;
+                                    self.state = StdoutHandlerState::Normal;
+                                    continue;
+                                }
+                            }
                             match cmd.as_str() {
                                 CMD_CHECK_IS_ACTIVE => {
                                     print_prodlog_message("Prodlog is currently active!");
-                                    // Show current active task if any
-                                    if let Ok(Some(task_id)) = self.sink.get_active_task() {
-                                        if let Ok(task) = self.sink.get_task_by_id(task_id) {
-                                            if let Some(task) = task {
-                                                let name = task.name.clone();
-                                                print_prodlog_message(&format!("Active task: {}", name));
-                                            } else {
-                                                print_prodlog_warning(&format!("Active task set to id {}, but no task with that id found", task_id));
-                                            }
-                                        }
-                                    }
+                                    self.print_active_task();
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_ARE_YOU_RUNNING => {
                                     if let Some(version) = args.get(0) {
-                                        if !helpers::compare_major_minor_versions(version, env!("CARGO_PKG_VERSION")) {
+                                        let named_args = NamedArgs::parse(args.get(1..).unwrap_or_default());
+                                        let capabilities = script_capabilities(version, named_args.get(ARG_CAPABILITIES));
+                                        let has_mac = capabilities.iter().any(|cap| cap == CAP_SESSION_MAC);
+                                        if !is_same_major_version(version, env!("CARGO_PKG_VERSION")) {
                                             print_prodlog_message(
                                                 &format!(
-                                                    "Error: Unsupported version: {} (expected major.minor to match {})",
+                                                    "Error: Unsupported version: {} (expected major version to match {})",
                                                     version,
                                                     env!("CARGO_PKG_VERSION")
                                                 )
                                             );
-                                        } else {
-                                            print_prodlog_message(
-                                                "Telling server side prodlog recording is active:"
+                                        } else if has_mac && !is_echo_off(self.master_fd) {
+                                            // The prodlog script turns echo off before asking, so the
+                                            // key in our reply doesn't show up on the terminal.
+                                            print_prodlog_warning(
+                                                "Ignoring prodlog handshake because the terminal is echoing input"
                                             );
-                                            // TODO: figure out why async send doesn't work here. It works fine in run_parent. Are we deadlocking?
-                                            self.child_stdin_tx
-                                                .blocking_send(
-                                                    REPLY_YES_PRODLOG_IS_RUNNING.to_vec()
-                                                )
-                                                .unwrap();
+                                        } else {
+                                            match self.auth.handshake(has_mac, named_args.get(ARG_NONCE)) {
+                                                Err(helpers::HandshakeRefusal::NonceReused) => print_prodlog_warning(
+                                                    "Ignoring prodlog handshake that was seen before, probably a file or log being printed"
+                                                ),
+                                                Err(refusal) => {
+                                                    let reason = match refusal {
+                                                        helpers::HandshakeRefusal::LegacyNotAllowed =>
+                                                            "Please update it, or start prodlog_record with --allow-legacy-scripts.",
+                                                        helpers::HandshakeRefusal::LegacyAfterKeyIssued =>
+                                                            "A newer script already connected in this session, so this may not be a real handshake.",
+                                                        // Older scripts that sign their commands, but without a nonce
+                                                        helpers::HandshakeRefusal::MissingNonce | helpers::HandshakeRefusal::NonceReused =>
+                                                            "Please update it.",
+                                                    };
+                                                    print_prodlog_warning(
+                                                        &format!(
+                                                            "Refusing prodlog script version {} because it can't authenticate its commands. {}",
+                                                            version,
+                                                            reason
+                                                        )
+                                                    );
+                                                }
+                                                Ok(script_key) => {
+                                                    report_missing_capabilities(version, &capabilities);
+                                                    print_prodlog_message(
+                                                        "Telling server side prodlog recording is active:"
+                                                    );
+                                                    // Older scripts expect just the reply, without a key or our capabilities.
+                                                    let reply = if let Some(script_key) = script_key {
+                                                        let our_capabilities: Vec<&str> = CAPABILITIES
+                                                            .iter()
+                                                            .map(|(cap, _)| *cap)
+                                                            .collect();
+                                                        format!(
+                                                            "{} {} {}\n",
+                                                            REPLY_YES_PRODLOG_IS_RUNNING,
+                                                            script_key,
+                                                            our_capabilities.join(",")
+                                                        )
+                                                    } else {
+                                                        format!("{}\n", REPLY_YES_PRODLOG_IS_RUNNING)
+                                                    };
+                                                    // TODO: figure out why async send doesn't work here. It works fine in run_parent. Are we deadlocking?
+                                                    self.child_stdin_tx
+                                                        .blocking_send(reply.into_bytes())
+                                                        .unwrap();
+                                                }
+                                            }
                                         }
                                     } else {
                                         print_prodlog_message("Error: Missing version argument");
@@ -357,17 +1289,21 @@ impl StdoutHandler {
                                         )
                                     {
                                         let cmd = unescape_and_unquote_cmd(raw_cmd);
+                                        let named_args = NamedArgs::parse(args.get(5..).unwrap_or_default());
+                                        let capture = Self::start_capturing_run(host, cwd, &cmd, message, remote_user)?;
+                                        self.push_capture(capture, &named_args);
+                                        let nested = self.capturing
+                                            .last()
+                                            .is_some_and(|active| active.capture.parent_uuid.is_some());
                                         print_prodlog_message(
                                             &format!(
-                                                "Starting capture of {} on {}:{}",
+                                                "Starting {}capture of {} on {}:{}",
+                                                if nested { "nested " } else { "" },
                                                 cmd,
                                                 host,
                                                 cwd
                                             )
                                         );
-                                        self.capturing = Some(
-                                            Self::start_capturing_run(host, cwd, &cmd, message, remote_user)?
-                                        );
                                         self.state = StdoutHandlerState::Normal;
                                         pos = new_pos;
                                     } else {
@@ -383,7 +1319,8 @@ impl StdoutHandler {
                                         .get(0)
                                         .and_then(|s| s.parse::<i32>().ok())
                                         .unwrap_or(1000);
-                                    if let Some(capture) = &mut self.capturing {
+                                    let named_args = NamedArgs::parse(args.get(1..).unwrap_or_default());
+                                    if let Some(mut capture) = self.pop_capture(&named_args) {
                                         print_prodlog_message(
                                             &format!(
                                                 "Stopping capture of {} on {}:{} with exit code {}",
@@ -394,16 +1331,16 @@ impl StdoutHandler {
                                             )
                                         );
                                         Self::stop_capturing_run(
-                                            capture,
+                                            &mut capture,
                                             exit_code,
                                             &mut self.sink
                                         )?;
+                                        self.live_hub.capture_stopped(&capture);
                                     } else {
                                         print_prodlog_message(
                                             "Warning: Tried to stop capture, but no capture was active"
                                         );
                                     }
-                                    self.capturing = None;
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_START_CAPTURE_EDIT => {
@@ -425,26 +1362,36 @@ impl StdoutHandler {
                                             args.get(5),
                                             args.get(6))
                                     {
+                                        let named_args = NamedArgs::parse(args.get(7..).unwrap_or_default());
+                                        let (original_content, content_error) =
+                                            self.receive_file_content(original_content, &named_args, 0);
+                                        let mut capture = Self::start_capturing_edit(
+                                            host,
+                                            cwd,
+                                            cmd,
+                                            message,
+                                            remote_user,
+                                            filename,
+                                            original_content
+                                        )?;
+                                        if let Some(error) = content_error {
+                                            Self::add_content_error(&mut capture, "original content", filename, &error);
+                                        }
+                                        self.receive_additional_original_files(&mut capture, &named_args);
+                                        let file_count = 1 + capture.additional_files.len();
+                                        self.push_capture(capture, &named_args);
+                                        let nested = self.capturing
+                                            .last()
+                                            .is_some_and(|active| active.capture.parent_uuid.is_some());
                                         print_prodlog_message(
                                             &format!(
-                                                "Starting capture of editing file {} on {}",
+                                                "Starting {}capture of editing file {}{} on {}",
+                                                if nested { "nested " } else { "" },
                                                 filename,
+                                                if file_count > 1 { format!(" and {} more", file_count - 1) } else { "".to_string() },
                                                 host
                                             )
                                         );
-                                        let original_content =
-                                            helpers::base64_decode(original_content);
-                                        self.capturing = Some(
-                                            Self::start_capturing_edit(
-                                                host,
-                                                cwd,
-                                                cmd,
-                                                message,
-                                                remote_user,
-                                                filename,
-                                                original_content
-                                            )?
-                                        );
                                         self.state = StdoutHandlerState::Normal;
                                         pos = new_pos;
                                     } else {
@@ -462,86 +1409,72 @@ impl StdoutHandler {
                                         .and_then(|s| s.parse::<i32>().ok())
                                         .unwrap_or(1000);
                                     let edited_content = args.get(1).unwrap_or(&empty);
-                                    let edited_content = helpers::base64_decode(edited_content);
-                                    if let Some(capture) = &mut self.capturing {
+                                    let named_args = NamedArgs::parse(args.get(2..).unwrap_or_default());
+                                    let (edited_content, content_error) =
+                                        self.receive_file_content(edited_content, &named_args, 0);
+                                    if let Some(mut capture) = self.pop_capture(&named_args) {
+                                        if let Some(error) = content_error {
+                                            let filename = capture.filename.clone();
+                                            Self::add_content_error(&mut capture, "edited content", &filename, &error);
+                                        }
+                                        self.receive_additional_edited_files(&mut capture, &named_args);
                                         print_prodlog_message(
                                             &format!(
-                                                "Stopping capture of editing file {} on {} with exit code {}",
+                                                "Stopping capture of editing file {}{} on {} with exit code {}",
                                                 capture.filename,
+                                                if capture.additional_files.is_empty() {
+                                                    "".to_string()
+                                                } else {
+                                                    format!(" and {} more", capture.additional_files.len())
+                                                },
                                                 capture.host,
                                                 exit_code
                                             )
                                         );
                                         Self::stop_capturing_edit(
-                                            capture,
+                                            &mut capture,
                                             exit_code,
                                             edited_content,
                                             &mut self.sink
                                         )?;
+                                        self.live_hub.capture_stopped(&capture);
                                     } else {
                                         print_prodlog_message(
                                             "Warning: Tried to stop capture, but no capture was active"
                                         );
                                     }
-                                    self.capturing = None;
+                                    self.state = StdoutHandlerState::Normal;
+                                }
+                                CMD_FILE_CHUNK => {
+                                    if
+                                        let (Some(transfer_id), Some(Ok(seq)), Some(chunk)) = (
+                                            args.get(0),
+                                            args.get(1).map(|seq| seq.parse::<u64>()),
+                                            args.get(2),
+                                        )
+                                    {
+                                        self.receive_file_chunk(transfer_id, seq, &helpers::base64_decode(chunk));
+                                    } else {
+                                        print_prodlog_message("Error: Missing arguments for FILE CHUNK");
+                                    }
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_TASK_START_NEW => {
                                     if let Some(task_name) = args.get(0) {
-                                        // Create and activate a new task
-                                        if let Ok(task_id) = self.sink.create_task(task_name) {
-                                            // Set it as active
-                                            if let Ok(_) = self.sink.set_active_task(Some(task_id)) {
-                                                print_prodlog_message(&format!("Created and activated task: {}", task_name));
-                                            } else {
-                                                print_prodlog_message("Error: Failed to set active task");
-                                            }
-                                        } else {
-                                            print_prodlog_message("Error: Failed to create task");
-                                        }
-                                        
+                                        self.start_new_task(task_name);
                                     } else {
                                         print_prodlog_message("Error: Task name required");
                                     }
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_TASK_LIST => {
-                                    if let Ok(tasks) = self.sink.get_all_tasks() {
-                                        let active_task_id = self.sink.get_active_task().unwrap_or(None);
-                                        if tasks.is_empty() {
-                                            print_prodlog_message("No recent tasks found");
-                                        } else {
-                                            print_prodlog_message("Recent tasks:");
-                                            // Show the 10 tasks with highest ID
-                                            let mut sorted_tasks: Vec<_> = tasks.into_iter().collect();
-                                            sorted_tasks.sort_by_key(|task| std::cmp::Reverse(task.id));
-                                            for task in sorted_tasks.into_iter().take(10) {
-                                                if Some(task.id) == active_task_id {
-                                                    print_prodlog_message(&format!(" (ACTIVE) {}: {}", task.id, task.name));
-                                                } else {
-                                                    print_prodlog_message(&format!("          {}: {}", task.id, task.name));
-                                                }
-                                            }
-                                        }
-                                    }                                    
+                                    self.list_tasks();
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_SET_ACTIVE_TASK => {
                                     if let Some(task_id_str) = args.get(0) {
                                         if let Ok(task_id) = task_id_str.parse::<i64>() {
-                                            if let Ok(task) = self.sink.get_task_by_id(task_id) {
-                                                if let Some(task) = task {
-                                                    if let Ok(_) = self.sink.set_active_task(Some(task_id)) {
-                                                        print_prodlog_message(&format!("Activated task: {}", task.name));
-                                                    } else {
-                                                        print_prodlog_message("Error: Failed to set active task");
-                                                    }
-                                                } else {
-                                                    print_prodlog_warning(&format!("No task with id {} found", task_id));
-                                                }
-                                            } else {
-                                                print_prodlog_warning(&format!("Error retrieving task with id {}", task_id));
-                                            }
+                                            self.activate_task(task_id);
                                         } else {
                                             print_prodlog_message(&format!("Error: Couldn't parse task ID {}", task_id_str));
                                         }
@@ -551,29 +1484,13 @@ impl StdoutHandler {
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 CMD_UNSET_ACTIVE_TASK => {
-                                    if let Ok(previously_active_task_id) = self.sink.get_active_task() {
-                                        if let Some(previously_active_task_id) = previously_active_task_id {
-                                            if let Ok(_) = self.sink.set_active_task(None) {
-                                                if let Ok(Some(task)) = self.sink.get_task_by_id(previously_active_task_id) {
-                                                    print_prodlog_message(&format!("Deactivated task: {}. No task is active now.", task.name));
-                                                } else {
-                                                    print_prodlog_message("Deactivated active task. No task is active now.");
-                                                }
-                                            } else {
-                                                print_prodlog_warning("Error: Failed to unset active task");
-                                            }
-                                        } else {
-                                            print_prodlog_message("No task was active, nothing to unset.");
-                                        }
-                                    } else {
-                                        print_prodlog_warning(&format!("Error getting currently active task"));
-                                    }
+                                    self.deactivate_task();
                                     self.state = StdoutHandlerState::Normal;
                                 }
                                 _ => {
                                     // Unknown command. Just print what we saw on the child's stdout.
                                     self.write_and_flush(PRODLOG_CMD_PREFIX)?;
-                                    self.write_and_flush(cmd.as_bytes())?;
+                                    self.write_and_flush(raw.as_bytes())?;
                                     self.write_and_flush(b";")?;
                                     self.state = StdoutHandlerState::Normal;
                                 }
@@ -587,21 +1504,73 @@ impl StdoutHandler {
     }
 }
 
-fn run_child() -> Result<(), std::io::Error> {
-    let cmdline = get_config().cmd.clone();
-    let parts: Vec<&str> = cmdline.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty shell command"));
+/// The name of a named argument for the `index`th file of an edit. The first file
+/// has no suffix, so single file edits look the same as before.
+fn file_arg(name: &str, index: usize) -> String {
+    if index == 0 {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, index)
+    }
+}
+
+/// The features a prodlog script supports. Scripts from before capabilities were
+/// announced in the handshake are judged by their version.
+fn script_capabilities(version: &str, announced: Option<&str>) -> Vec<String> {
+    match announced {
+        Some(announced) => announced
+            .split(',')
+            .filter(|cap| !cap.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => {
+            let version = helpers::parse_major_minor_version(version).unwrap_or_default();
+            let mut capabilities = Vec::new();
+            if version >= (2, 9) {
+                capabilities.push(CAP_CAPTURE_ID.to_string());
+            }
+            if version >= (2, 11) {
+                capabilities.push(CAP_SESSION_MAC.to_string());
+            }
+            capabilities
+        }
     }
-    let args: Vec<CString> = parts
+}
+
+fn is_same_major_version(version1: &str, version2: &str) -> bool {
+    match (helpers::parse_major_minor_version(version1), helpers::parse_major_minor_version(version2)) {
+        (Some((major1, _)), Some((major2, _))) => major1 == major2,
+        _ => false,
+    }
+}
+
+fn report_missing_capabilities(version: &str, capabilities: &[String]) {
+    let missing: Vec<&str> = CAPABILITIES
         .iter()
-        .map(|&s| CString::new(s).expect("CString::new failed"))
-        .collect();    
+        .filter(|(cap, _)| !capabilities.iter().any(|c| c == cap))
+        .map(|(_, description)| *description)
+        .collect();
+    if !missing.is_empty() {
+        print_prodlog_warning(
+            &format!(
+                "The prodlog script (version {}) doesn't support {}. Please update it.",
+                version,
+                missing.join(", ")
+            )
+        );
+    }
+}
+
+fn run_child(command_line: &[String]) -> Result<(), std::io::Error> {
+    let args: Vec<CString> = command_line
+        .iter()
+        .map(|s| CString::new(s.as_str()).expect("CString::new failed"))
+        .collect();
     execvp(&args[0], &args)?;
     Ok(())
 }
 
-fn set_winsize(fd: RawFd) -> Result<(), std::io::Error> {
+fn set_winsize(fd: RawFd) -> Result<(u16, u16), std::io::Error> {
     // Get the current terminal size
     let (cols, rows) = terminal_size()?;
     let winsize = Winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
@@ -609,18 +1578,91 @@ fn set_winsize(fd: RawFd) -> Result<(), std::io::Error> {
         ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, Winsize);
         tiocswinsz(fd, &winsize as *const _ as *mut _)?;
     }
+    Ok((cols, rows))
+}
+
+/// Whether what's typed on the pty right now should be kept out of the capture.
+/// Password prompts turn off echo but keep the terminal in canonical (line) mode.
+/// Full-screen programs like vi turn off both, and echo what's typed themselves.
+fn is_input_hidden(fd: RawFd) -> bool {
+    match local_flags(fd) {
+        Some(flags) => !flags.contains(LocalFlags::ECHO) && flags.contains(LocalFlags::ICANON),
+        // If we can't tell, err on the side of not recording secrets
+        None => true,
+    }
+}
+
+fn is_echo_off(fd: RawFd) -> bool {
+    local_flags(fd).is_some_and(|flags| !flags.contains(LocalFlags::ECHO))
+}
+
+fn local_flags(fd: RawFd) -> Option<LocalFlags> {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    tcgetattr(fd).ok().map(|termios| termios.local_flags)
+}
+
+// Tells apart the terminals recording to the same prodlog, e.g. "pts/3 ssh db1".
+fn default_terminal_name(command_line: &[String]) -> String {
+    let command = shell_words::join(command_line);
+    match nix::unistd::ttyname(std::io::stdin()) {
+        Ok(tty) => format!("{} {}", tty.to_string_lossy().trim_start_matches("/dev/"), command),
+        Err(_) => command,
+    }
+}
+
+/// Makes this the primary instance, which listens on `socket_path` for the instances
+/// started later and serves the web UI. Fails if another instance is listening already.
+fn start_primary(
+    prodlog_dir: &PathBuf,
+    socket_path: &Path,
+    live_hub: Arc<LiveHub>,
+    listening: &AtomicBool
+) -> Result<(), std::io::Error> {
+    match sinks::remote::listen(socket_path) {
+        Ok(listener) => {
+            listening.store(true, Ordering::SeqCst);
+            let server_sink: Arc<Box<dyn Sink>> = Arc::new(get_sink(prodlog_dir));
+            tokio::spawn(sinks::remote::serve(listener, server_sink));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => return Err(e),
+        Err(e) => {
+            print_prodlog_warning(&format!("Can't listen for other prodlog_record instances: {}", e));
+        }
+    }
+
+    // Start the UI in a separate task
+    let ui_port = get_config().port;
+    let ui_sink = get_sink(prodlog_dir);
+    tokio::spawn(async move {
+        let sink: Arc<RwLock<Box<dyn Sink>>> = Arc::new(RwLock::new(ui_sink));
+        ui::run_ui(sink, live_hub, ui_port).await;
+    });
     Ok(())
 }
 
 fn get_sink(prodlog_dir: &PathBuf) -> Box<dyn sinks::Sink> {
     fs::create_dir_all(prodlog_dir).expect("Failed to create directory");
-    let sqlite_file = prodlog_dir.join("prodlog.sqlite");
+    let primary: Box<dyn sinks::Sink> = if let Some(database_url) = &get_config().database_url {
+        Box::new(sinks::postgres::PostgresSink::new(database_url))
+    } else {
+        let sqlite_file = prodlog_dir.join("prodlog.sqlite");
+        Box::new(sinks::sqlite::SqliteSink::new(&sqlite_file))
+    };
+    if get_config().secondary_sinks.is_empty() {
+        return primary;
+    }
 
-    Box::new(sinks::sqlite::SqliteSink::new(&sqlite_file))
+    match sinks::fanout::FanoutSink::new(primary, prodlog_dir, &get_config().secondary_sinks) {
+        Ok(fanout_sink) => Box::new(fanout_sink),
+        Err(e) => prodlog_panic(&format!("Error setting up the secondary sinks: {}", e)),
+    }
 }
 
 async fn run_parent(
     sink: Box<dyn sinks::Sink>,
+    live_hub: Arc<LiveHub>,
+    escape_char: Option<u8>,
+    terminal: String,
     child: nix::unistd::Pid,
     master: std::os::fd::OwnedFd
 ) -> Result<(), std::io::Error> {
@@ -641,38 +1683,78 @@ async fn run_parent(
     // Forward whatever bytes appear on the channel to the child's stdin.
     let _stdin_sender_handle = tokio::spawn(async move {
         while let Some(data) = child_stdin_rx.recv().await {
-            raw_master_write.write(&data).unwrap();
+            raw_master_write.write_all(&data).unwrap();
             raw_master_write.flush().unwrap();
         }
     });
 
-    // Read our stdin and forward it to the child.
+    // The handler is shared with the input thread and the window size listener, which
+    // record input and resizes in the active captures.
+    let stream_handler = Arc::new(
+        std::sync::Mutex::new(StdoutHandler::new(child_stdin_tx2, raw_stdout, sink, master_fd, live_hub, terminal))
+    );
+    let stream_handler2 = stream_handler.clone();
+    let stream_handler6 = stream_handler.clone();
+    if get_config().record_session {
+        stream_handler.lock().unwrap().start_recording_session();
+    }
+
+    // Read our stdin and forward it to the child, except for escape commands.
     let _stdin_reader_thread = tokio::task::spawn_blocking(move || {
         let stdin = std::io::stdin();
+        let mut escape_handler = escape_char.map(EscapeHandler::new);
         for event in stdin.events_and_raw() {
             let (_, raw) = event.unwrap();
-            if child_stdin_tx.blocking_send(raw).is_err() {
-                eprintln!("Input thread: Tokio receiver dropped.");
-                break; // Exit the thread
+            let actions = match escape_handler.as_mut() {
+                Some(escape_handler) => escape_handler.feed(&raw),
+                None => vec![InputAction::Forward(raw)],
+            };
+            for action in actions {
+                match action {
+                    InputAction::Forward(raw) => {
+                        let hidden = is_input_hidden(master_fd);
+                        stream_handler6.lock().unwrap().record_input(&raw, hidden);
+                        if child_stdin_tx.blocking_send(raw).is_err() {
+                            eprintln!("Input thread: Tokio receiver dropped.");
+                            return; // Exit the thread
+                        }
+                    }
+                    InputAction::Prompt(prompt) => print_prodlog_prompt(prompt),
+                    InputAction::Echo(text) => {
+                        let mut stdout = std::io::stdout();
+                        let _ = stdout.write_all(&text).and_then(|_| stdout.flush());
+                    }
+                    InputAction::Command(command) => {
+                        if let Err(e) = stream_handler6.lock().unwrap().run_escape_command(command) {
+                            print_prodlog_warning(&format!("Error running escape command: {}", e));
+                        }
+                    }
+                }
             }
         }
     });
 
+    let stream_handler3 = stream_handler.clone();
+    let stream_handler4 = stream_handler.clone();
+    let stream_handler5 = stream_handler.clone();
+
     // Start forwarding the child's stdout to our stdout.
-    let _forward_stdout = tokio::task::spawn_blocking(move || {
+    let forward_stdout = tokio::task::spawn_blocking(move || {
         let mut buffer = [0; 1024];
-        let mut stream_handler = StdoutHandler::new(child_stdin_tx2, raw_stdout, sink);
         loop {
             let n = raw_master_read.read(&mut buffer);
             if let Ok(n) = n {
                 if n == 0 {
                     break; // EOF reached
                 }
-                stream_handler.process(&buffer, n).unwrap();
+                stream_handler.lock().unwrap().process(&buffer, n).unwrap();
             } else {
                 break;
             }
         }
+        if let Ok(mut stream_handler) = stream_handler.lock() {
+            stream_handler.interrupt_all_captures("the terminal session ended");
+        }
     });
 
     // Start listening for window size changes and forward them to the child.
@@ -681,8 +1763,37 @@ async fn run_parent(
         set_winsize(master_fd).unwrap();
         loop {
             sigwinch_stream.recv().await;
-            set_winsize(master_fd).unwrap();
+            let (cols, rows) = set_winsize(master_fd).unwrap();
+            stream_handler2.lock().unwrap().record_resize(cols, rows);
+        }
+    });
+
+    // Write pending output to the sink regularly, so it survives a crash even if the
+    // command goes quiet.
+    let _flush_ticker = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVENT_FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Ok(mut stream_handler) = stream_handler5.lock() {
+                stream_handler.flush_all_captures();
+            }
+        }
+    });
+
+    // Save any active captures if we get killed, since that's exactly the kind of
+    // session we want a record of.
+    let _shutdown_listener = tokio::spawn(async move {
+        let mut sighup_stream = signal(SignalKind::hangup()).unwrap();
+        let mut sigterm_stream = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = sighup_stream.recv() => {}
+            _ = sigterm_stream.recv() => {}
+        }
+        if let Ok(mut stream_handler) = stream_handler3.lock() {
+            stream_handler.interrupt_all_captures("prodlog_record was shut down");
+            stream_handler.finish_recording_session();
         }
+        std::process::exit(1);
     });
 
     // Wait for the child to exit.
@@ -691,6 +1802,14 @@ async fn run_parent(
     });
     wait_child_exit.await.unwrap();
 
+    // Give the forwarding thread a moment to process the child's last output. It may
+    // not see EOF if a background process still has the pty open.
+    let _ = tokio::time::timeout(Duration::from_millis(500), forward_stdout).await;
+    if let Ok(mut stream_handler) = stream_handler4.lock() {
+        stream_handler.interrupt_all_captures("the terminal session ended");
+        stream_handler.finish_recording_session();
+    }
+
     Ok(())
 }
 
@@ -720,6 +1839,11 @@ fn import(import_file: &str, sink: &mut Box<dyn sinks::Sink>) -> Result<(), std:
     let entries = source_sink.get_entries(&sinks::Filters::default())?;
     print_prodlog_message(&format!("Found {} entries to import", entries.len()));
     for entry in entries {
+        // Listed entries come without their output
+        let entry = match source_sink.get_entry_by_id(entry.uuid)? {
+            Some(entry) => entry,
+            None => continue,
+        };
         if let Err(e) = sink.add_new_entry(&entry) {
             print_prodlog_warning(
                 &format!("Error writing entry {} to sink: {}", entry.uuid, e)
@@ -731,6 +1855,27 @@ fn import(import_file: &str, sink: &mut Box<dyn sinks::Sink>) -> Result<(), std:
     Ok(())
 }
 
+fn replay(uuid: &str, speed: f64, sink: &dyn sinks::Sink) -> Result<(), std::io::Error> {
+    if speed <= 0.0 {
+        prodlog_panic(&format!("Error: Replay speed must be positive, got {}", speed));
+    }
+    let uuid = match Uuid::parse_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => prodlog_panic(&format!("Error: Invalid UUID {}", uuid)),
+    };
+    let entry = match sink.get_entry_by_id(uuid)? {
+        Some(entry) => entry,
+        None => prodlog_panic(&format!("Error: Entry {} not found", uuid)),
+    };
+
+    print_prodlog_message(
+        &format!("Replaying {} on {}:{} at {}x speed", entry.cmd, entry.host, entry.cwd, speed)
+    );
+    cast::replay(&entry, speed)?;
+    print_prodlog_message("Replay done.");
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     // Get the log directory path
@@ -742,33 +1887,75 @@ async fn main() {
         home_dir.join(get_config().dir.clone())
     };
     print_prodlog_message(&format!("prodlog logging to {:?}", prodlog_dir));
+    if get_config().database_url.is_some() {
+        print_prodlog_message("Storing entries in the PostgreSQL database of --database-url");
+    }
 
     // Create the directory doesn't exist
     let mut sink = get_sink(&prodlog_dir);
+    noop::init_rules(&prodlog_dir, get_config().noop_rules.as_deref());
+
+    if let Some(Command::Replay { uuid, speed }) = &get_config().command {
+        if let Err(e) = replay(uuid, *speed, sink.as_ref()) {
+            prodlog_panic(&format!("Error replaying entry: {}", e));
+        }
+        return;
+    }
 
-    // Import a prodlog json or sqlite file if specified2
+    // Import a prodlog sqlite file if specified
     if let Some(import_file) = &get_config().import {
-        import(&import_file, &mut sink).unwrap();
+        import(import_file, &mut sink).unwrap();
     }
 
-    // Start the UI in a separate task
-    let ui_port = get_config().port;
-    tokio::spawn(async move {
-        let sqlite_file = prodlog_dir.join("prodlog.sqlite");
-        let sink: Arc<RwLock<Box<dyn Sink>>> = Arc::new(
-            RwLock::new(Box::new(sinks::sqlite::SqliteSink::new(&sqlite_file)))
-        );
-        ui::run_ui(sink, ui_port).await;
-    });
+    let escape_char = match escape::parse_escape_char(&get_config().escape_char) {
+        Ok(escape_char) => escape_char,
+        Err(e) => prodlog_panic(&e),
+    };
+    let command_line = match get_config().command_line() {
+        Ok(command_line) => command_line,
+        Err(e) => prodlog_panic(&e),
+    };
+
+    let terminal = get_config().terminal_name.clone().unwrap_or_else(|| default_terminal_name(&command_line));
+
+    // The first instance listens for the ones started later, which then write through it
+    // so that its UI shows the entries of all terminals.
+    let socket_path = prodlog_dir.join(sinks::remote::SOCKET_FILE);
+    let listening = Arc::new(AtomicBool::new(false));
+    let live_hub = Arc::new(LiveHub::new(terminal_size().ok().filter(|&(cols, rows)| cols > 0 && rows > 0)));
+    let take_over = {
+        let (prodlog_dir, socket_path, live_hub, listening) = (prodlog_dir.clone(), socket_path.clone(), live_hub.clone(), listening.clone());
+        let runtime = tokio::runtime::Handle::current();
+        move || {
+            let _runtime = runtime.enter();
+            start_primary(&prodlog_dir, &socket_path, live_hub.clone(), &listening)
+                .ok()
+                .map(|_| get_sink(&prodlog_dir))
+        }
+    };
+    match sinks::remote::RemoteSink::connect(&socket_path, take_over) {
+        Ok(remote_sink) => {
+            print_prodlog_message("Attached to the prodlog_record instance that's already running, see its web UI");
+            sink = Box::new(remote_sink);
+        }
+        Err(_) => {
+            if let Err(e) = start_primary(&prodlog_dir, &socket_path, live_hub.clone(), &listening) {
+                print_prodlog_warning(&format!("Can't attach to or listen for other prodlog_record instances: {}", e));
+            }
+        }
+    }
 
     let mut is_child = "";
     let result = match (unsafe { nix::pty::forkpty(None, None) }).unwrap() {
         ForkptyResult::Child => {
             is_child = "CHILD PROCESS ";
-            run_child()
+            run_child(&command_line)
         },
-        ForkptyResult::Parent { child, master } => { run_parent(sink, child, master).await }
+        ForkptyResult::Parent { child, master } => { run_parent(sink, live_hub, escape_char, terminal, child, master).await }
     };
+    if listening.load(Ordering::SeqCst) && is_child.is_empty() {
+        let _ = fs::remove_file(&socket_path);
+    }
     if let Err(e) = result {
         prodlog_panic(&format!("PRODLOG {}EXITING WITH ERROR: {}", is_child, e));
     } else {
@@ -776,3 +1963,229 @@ async fn main() {
         std::process::exit(0);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::sqlite::SqliteSink;
+
+    fn handler(prodlog_file: &Path) -> StdoutHandler {
+        StdoutHandler {
+            child_stdin_tx: mpsc::channel(1).0,
+            stdout: Box::new(std::io::sink()),
+            capturing: Vec::new(),
+            state: StdoutHandlerState::Normal,
+            sink: Box::new(SqliteSink::open(prodlog_file).unwrap()),
+            auth: helpers::SessionAuth::new(false),
+            file_transfers: HashMap::new(),
+            master_fd: -1,
+            input_masked: false,
+            live_hub: Arc::new(LiveHub::new(None)),
+            session: None,
+            shell_marks: None,
+            shell_location: (String::new(), String::new()),
+            terminal: String::new(),
+            output_limits: OutputLimits::default(),
+        }
+    }
+
+    fn push(handler: &mut StdoutHandler, cmd: &str, args: &[&str]) -> Uuid {
+        let capture = StdoutHandler::start_capturing_run("web1", "/srv", cmd, "", "root").unwrap();
+        let uuid = capture.uuid;
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        handler.push_capture(capture, &NamedArgs::parse(&args));
+        uuid
+    }
+
+    fn status(handler: &StdoutHandler, uuid: Uuid) -> CaptureStatus {
+        handler.sink.get_entry_by_id(uuid).unwrap().unwrap().status
+    }
+
+    #[test]
+    fn test_nested_captures() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let mut handler = handler(&path);
+        let deploy = push(&mut handler, "deploy.sh", &["id=a"]);
+        let migrate = push(&mut handler, "migrate", &["id=b", "parent=a"]);
+        assert_eq!(handler.capturing[1].capture.parent_uuid, Some(deploy));
+        let popped = handler.pop_capture(&NamedArgs::parse(&["id=b".to_string()])).unwrap();
+        assert!(popped.uuid == migrate && handler.capturing.len() == 1);
+
+        // Starting a sibling interrupts the captures that were never stopped
+        let restart = push(&mut handler, "restart", &["id=c", "parent=a"]);
+        let reload = push(&mut handler, "reload", &["id=d", "parent=c"]);
+        let check = push(&mut handler, "check", &["id=e", "parent=a"]);
+        let stack: Vec<Uuid> = handler.capturing.iter().map(|active| active.capture.uuid).collect();
+        assert_eq!(stack, [deploy, check]);
+        assert!(status(&handler, restart) == CaptureStatus::Interrupted && status(&handler, reload) == CaptureStatus::Interrupted);
+        assert!(status(&handler, check) == CaptureStatus::InProgress);
+
+        // Stopping the parent first interrupts its children
+        let popped = handler.pop_capture(&NamedArgs::parse(&["id=a".to_string()])).unwrap();
+        assert!(popped.uuid == deploy && handler.capturing.is_empty());
+        assert!(status(&handler, check) == CaptureStatus::Interrupted);
+        assert!(handler.pop_capture(&NamedArgs::parse(&["id=a".to_string()])).is_none());
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_transfers() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let mut handler = handler(&path);
+        let receive = |handler: &mut StdoutHandler, id: &str, sha256: &str| {
+            let args = NamedArgs::parse(&[format!("content={}", id), format!("sha256={}", sha256)]);
+            handler.receive_file_content("", &args, 0)
+        };
+        let sha256 = helpers::sha256_hex(b"listen 80;\n");
+        handler.receive_file_chunk("1", 0, b"listen ");
+        handler.receive_file_chunk("1", 0, b"listen ");
+        handler.receive_file_chunk("1", 1, b"80;\n");
+        assert_eq!(receive(&mut handler, "1", &sha256), (b"listen 80;\n".to_vec(), None));
+
+        handler.receive_file_chunk("2", 0, b"listen ");
+        handler.receive_file_chunk("2", 1, b"443;\n");
+        assert_eq!(receive(&mut handler, "2", &sha256).1.as_deref(), Some("checksum mismatch"));
+        handler.receive_file_chunk("3", 0, b"listen ");
+        handler.receive_file_chunk("3", 2, b"\n");
+        assert_eq!(receive(&mut handler, "3", &sha256).1.as_deref(), Some("chunks [1] were lost"));
+        assert!(handler.file_transfers.is_empty());
+
+        // Files of a capture that was never started, or was interrupted, are dropped
+        push(&mut handler, "vim", &["id=a"]);
+        handler.receive_file_chunk("a-edited.0", 0, b"listen ");
+        handler.receive_file_chunk("b-original.0", 0, b"listen ");
+        push(&mut handler, "vim", &["id=c", "parent=a"]);
+        assert_eq!(handler.file_transfers.keys().collect::<Vec<_>>(), ["a-edited.0"]);
+        handler.interrupt_all_captures("the session ended");
+        assert!(handler.file_transfers.is_empty());
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_held_output_is_stored() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let mut handler = handler(&path);
+        handler.output_limits = OutputLimits { head_tail: Some((5, 6)), collapse_redraws: false };
+        let uuid = push(&mut handler, "journalctl", &["id=a"]);
+        let stored = |handler: &StdoutHandler| {
+            String::from_utf8(handler.sink.get_entry_by_id(uuid).unwrap().unwrap().output_from_events()).unwrap()
+        };
+        let marker = |dropped, total| {
+            format!("\r\n\x1b[7m[prodlog: {} bytes of output dropped, {} bytes in total]\x1b[0m\r\n", dropped, total)
+        };
+
+        // The tail is stored while the capture runs, in place of the one stored before
+        handler.output(b"head\none\ntwo\n").unwrap();
+        handler.flush_all_captures();
+        assert_eq!(stored(&handler), format!("head\n{}two\n", marker(4, 13)));
+        handler.output(b"three\n").unwrap();
+        handler.flush_all_captures();
+        assert_eq!(stored(&handler), format!("head\n{}three\n", marker(8, 19)));
+        handler.output(b"four\n").unwrap();
+        handler.pop_capture(&NamedArgs::parse(&["id=a".to_string()])).unwrap();
+        assert_eq!(stored(&handler), format!("head\n{}four\n", marker(14, 24)));
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // A prodlog command as the script prints it, signed if given a key and a nonce
+    fn command(cmd: &str, args: &[&str], signed_with: Option<(&str, &str, u64)>) -> Vec<u8> {
+        use base64::{ Engine as _, engine::general_purpose };
+        let encoded_args: String = args
+            .iter()
+            .map(|arg| format!(":{}", general_purpose::STANDARD.encode(arg)))
+            .collect();
+        let signature = match signed_with {
+            Some((key, nonce, counter)) => {
+                let signed = format!("{}.{}", nonce, counter);
+                let mac = helpers::hmac_sha256_hex(key.as_bytes(), &format!("{}.{}{}", signed, cmd, encoded_args));
+                format!("@{}.{}", signed, mac)
+            }
+            None => String::new(),
+        };
+        let mut command = PRODLOG_CMD_PREFIX.to_vec();
+        command.extend_from_slice(format!("{}{}{};", cmd, signature, encoded_args).as_bytes());
+        command
+    }
+
+    #[test]
+    fn test_handshake_while_another_program_holds_the_pty() {
+        use nix::sys::termios::{ tcsetattr, SetArg };
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let mut handler = handler(&path);
+        let (child_stdin_tx, mut child_stdin_rx) = mpsc::channel(10);
+        handler.child_stdin_tx = child_stdin_tx;
+        let pty = nix::pty::openpty(None, None).unwrap();
+        handler.master_fd = pty.master.as_raw_fd();
+        let set_echo = |echo: bool| {
+            let mut termios = tcgetattr(&pty.slave).unwrap();
+            termios.local_flags.set(LocalFlags::ECHO, echo);
+            tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).unwrap();
+        };
+        // Returns the key of the reply that was typed into the pty, if any
+        let mut process = |handler: &mut StdoutHandler, command: &[u8]| {
+            handler.process(command, command.len()).unwrap();
+            child_stdin_rx.try_recv().ok().map(|reply| {
+                let reply = String::from_utf8(reply).unwrap();
+                reply.strip_prefix(REPLY_YES_PRODLOG_IS_RUNNING).unwrap().split(' ').nth(1).unwrap().to_string()
+            })
+        };
+        let handshake = |nonce: &str| {
+            command(CMD_ARE_YOU_RUNNING, &[env!("CARGO_PKG_VERSION"), "caps=mac,nonce", &format!("nonce={}", nonce)], None)
+        };
+        let start = |ids: &[&str], signed_with| {
+            command(CMD_START_CAPTURE_RUN, &[&["web1", "/srv", "'cat' 'notes'", "", "root"], ids].concat(), signed_with)
+        };
+
+        // The script turns echo off and gets a key for its nonce
+        let nonce = "00112233445566778899aabbccddeeff";
+        set_echo(false);
+        let key = process(&mut handler, &handshake(nonce)).unwrap();
+        let signed_start = start(&["id=a"], Some((&key, nonce, 1)));
+        assert!(process(&mut handler, &signed_start).is_none());
+        assert_eq!(handler.capturing.len(), 1);
+
+        // The command it runs prints the same handshake and command again, e.g. a pager
+        // showing a recorded session, which has echo off too
+        assert!(process(&mut handler, &handshake(nonce)).is_none());
+        assert!(process(&mut handler, &signed_start).is_none());
+        assert_eq!(handler.capturing.len(), 1);
+
+        // A handshake with a fresh nonce that isn't from the script is only answered with
+        // echo off, and with a key that's no good for signing the script's commands
+        let other_nonce = "ffeeddccbbaa99887766554433221100";
+        set_echo(true);
+        assert!(process(&mut handler, &handshake(other_nonce)).is_none());
+        set_echo(false);
+        let other_key = process(&mut handler, &handshake(other_nonce)).unwrap();
+        assert_ne!(other_key, key);
+        assert!(process(&mut handler, &start(&["id=b", "parent=a"], Some((&other_key, nonce, 2)))).is_none());
+        assert_eq!(handler.capturing.len(), 1);
+        assert!(process(&mut handler, &start(&["id=b", "parent=a"], Some((&key, nonce, 2)))).is_none());
+        assert_eq!(handler.capturing.len(), 2);
+        handler.interrupt_all_captures("the session ended");
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupt_captures_from() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let mut handler = handler(&path);
+        let outer = push(&mut handler, "deploy.sh", &[]);
+        // Older scripts send no ids, so their captures nest in the innermost one
+        let inner = push(&mut handler, "migrate", &[]);
+        assert_eq!(handler.capturing[1].capture.parent_uuid, Some(outer));
+
+        handler.interrupt_captures_from(2, "nothing");
+        assert_eq!(handler.capturing.len(), 2);
+        handler.interrupt_captures_from(1, "the connection dropped");
+        assert!(status(&handler, inner) == CaptureStatus::Interrupted && status(&handler, outer) == CaptureStatus::InProgress);
+        handler.interrupt_all_captures("the session ended");
+        assert!(handler.capturing.is_empty() && status(&handler, outer) == CaptureStatus::Interrupted);
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
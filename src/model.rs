@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use chrono::{ DateTime, Utc };
 use serde::{ Deserialize, Serialize };
 use serde_with::serde_as;
@@ -8,6 +10,57 @@ use uuid::Uuid;
 pub enum CaptureType {
     Run,
     Edit,
+    Note,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+pub enum CaptureStatus {
+    #[default]
+    Completed,
+    // The capture never received its STOP, e.g. because the connection dropped or
+    // prodlog_record was shut down. The output is what was captured until then.
+    Interrupted,
+    // The capture is still running, or prodlog_record died without getting a chance
+    // to save it as interrupted. The output is what was captured so far.
+    InProgress,
+}
+
+impl CaptureStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptureStatus::Completed => "completed",
+            CaptureStatus::Interrupted => "interrupted",
+            CaptureStatus::InProgress => "in_progress",
+        }
+    }
+
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "interrupted" => CaptureStatus::Interrupted,
+            "in_progress" => CaptureStatus::InProgress,
+            _ => CaptureStatus::Completed,
+        }
+    }
+}
+
+/// Something that happened on the terminal while a capture was running.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum CaptureEventKind {
+    Output(#[serde_as(as = "Base64")] Vec<u8>),
+    // Keystrokes the operator sent to the command.
+    Input(#[serde_as(as = "Base64")] Vec<u8>),
+    // Input typed while the terminal didn't echo it, e.g. at a password prompt. Only
+    // the fact that something was typed is recorded.
+    MaskedInput,
+    Resize { cols: u16, rows: u16 },
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CaptureEvent {
+    /// Milliseconds since the capture's start_time.
+    pub offset_ms: u64,
+    pub kind: CaptureEventKind,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -17,6 +70,23 @@ pub struct Task {
     pub created_at: DateTime<Utc>,
 }
 
+/// A file of an edit capture, other than the first one.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EditedFile {
+    pub filename: String,
+    #[serde_as(as = "Base64")]
+    pub original_content: Vec<u8>,
+    #[serde_as(as = "Base64")]
+    pub edited_content: Vec<u8>,
+    // The SHA-256 of the contents, which can be fetched from /api/blob/<hash>. Set when
+    // the entry is read from the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_hash: Option<String>,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CaptureV2_4 {
@@ -30,18 +100,152 @@ pub struct CaptureV2_4 {
     pub message: String,
     pub is_noop: bool,
     pub exit_code: i32,
+    #[serde(default)]
+    pub status: CaptureStatus,
     pub local_user: String,
     pub remote_user: String,
     pub filename: String,
     pub terminal_rows: u16,
     pub terminal_cols: u16,
     pub task_id: Option<i64>,
+    // The capture this one was started from, when `prodlog` is run by a captured command.
+    #[serde(default)]
+    pub parent_uuid: Option<Uuid>,
     #[serde_as(as = "Base64")]
     pub captured_output: Vec<u8>,
     #[serde_as(as = "Base64")]
     pub original_content: Vec<u8>,
     #[serde_as(as = "Base64")]
     pub edited_content: Vec<u8>,
+    // Like those of EditedFile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_hash: Option<String>,
+    // Facts about the environment the capture ran in, e.g. the OS release or the git
+    // HEAD of cwd, as reported by the prodlog script.
+    #[serde(default)]
+    pub facts: BTreeMap<String, String>,
+    // Set by the operator, with `prodlog run -t`/`-f` or through the UI.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, String>,
+    // When several files are edited at once, filename, original_content and
+    // edited_content are those of the first file, and the others are here.
+    #[serde(default)]
+    pub additional_files: Vec<EditedFile>,
+    // Set when original_content or edited_content didn't arrive intact from the
+    // prodlog script, describing what went wrong.
+    #[serde(default)]
+    pub content_error: Option<String>,
+    // The terminal, i.e. the prodlog_record instance, the entry was captured in.
+    #[serde(default)]
+    pub terminal: String,
+    // Timed output chunks and resizes. Not part of the JSON representation since
+    // it duplicates captured_output; use the asciicast export to get the timing.
+    #[serde(skip)]
+    pub events: Vec<CaptureEvent>,
+}
+
+impl CaptureV2_4 {
+    /// Concatenates the output events, which is what captured_output contains
+    /// for entries that were recorded with timing information.
+    pub fn output_from_events(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        for event in &self.events {
+            if let CaptureEventKind::Output(data) = &event.kind {
+                output.extend_from_slice(data);
+            }
+        }
+        output
+    }
+
+    /// All files of an edit capture, starting with the first one.
+    pub fn edited_files(&self) -> Vec<EditedFile> {
+        let first = EditedFile {
+            filename: self.filename.clone(),
+            original_content: self.original_content.clone(),
+            edited_content: self.edited_content.clone(),
+            original_hash: self.original_hash.clone(),
+            edited_hash: self.edited_hash.clone(),
+        };
+        std::iter::once(first).chain(self.additional_files.iter().cloned()).collect()
+    }
+}
+
+/// A recording of a whole terminal session, made when prodlog_record runs with
+/// --record-session. Parts of it can be promoted to entries afterwards.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub uuid: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub duration_ms: u64,
+    // InProgress while prodlog_record runs, or if it died before the session ended
+    pub status: CaptureStatus,
+    pub local_user: String,
+    // The command prodlog_record started, e.g. `ssh <host>`
+    pub cmd: String,
+    // Only the events that haven't been written to the sink yet while recording
+    #[serde(skip)]
+    pub events: Vec<CaptureEvent>,
+}
+
+impl Session {
+    /// Turns the part of the session from `from_ms` to `to_ms` into a run entry.
+    /// `events` are the session's events in that range, optionally preceded by the
+    /// last resize before it. What was run where isn't known, so host, cwd and cmd
+    /// are left for the caller to fill in.
+    pub fn slice_to_capture(&self, events: Vec<CaptureEvent>, from_ms: u64, to_ms: u64) -> CaptureV2_4 {
+        let events: Vec<CaptureEvent> = events
+            .into_iter()
+            .filter(|event| event.offset_ms <= to_ms)
+            .map(|event| CaptureEvent { offset_ms: event.offset_ms.saturating_sub(from_ms), kind: event.kind })
+            .collect();
+        let (terminal_cols, terminal_rows) = events
+            .iter()
+            .rev()
+            .find_map(|event| match event.kind {
+                CaptureEventKind::Resize { cols, rows } => Some((cols, rows)),
+                _ => None,
+            })
+            .unwrap_or((0, 0));
+        let mut capture = CaptureV2_4 {
+            capture_type: CaptureType::Run,
+            uuid: Uuid::new_v4(),
+            host: String::new(),
+            cwd: String::new(),
+            cmd: String::new(),
+            start_time: self.start_time + chrono::Duration::milliseconds(from_ms as i64),
+            duration_ms: to_ms.saturating_sub(from_ms),
+            message: String::new(),
+            is_noop: false,
+            // The exit code wasn't recorded
+            exit_code: -1,
+            status: CaptureStatus::Completed,
+            local_user: self.local_user.clone(),
+            remote_user: String::new(),
+            filename: String::new(),
+            terminal_rows,
+            terminal_cols,
+            task_id: None,
+            parent_uuid: None,
+            facts: BTreeMap::new(),
+            tags: Vec::new(),
+            custom_fields: BTreeMap::new(),
+            additional_files: Vec::new(),
+            content_error: None,
+            terminal: String::new(),
+            captured_output: Vec::new(),
+            original_content: Vec::new(),
+            edited_content: Vec::new(),
+            original_hash: None,
+            edited_hash: None,
+            events,
+        };
+        capture.captured_output = capture.output_from_events();
+        capture
+    }
 }
 
 // Lightweight version for index page - excludes large content fields
@@ -57,12 +261,26 @@ pub struct CaptureV2_4Summary {
     pub message: String,
     pub is_noop: bool,
     pub exit_code: i32,
+    pub status: CaptureStatus,
     pub local_user: String,
     pub remote_user: String,
     pub filename: String,
     pub terminal_rows: u16,
     pub terminal_cols: u16,
     pub task_id: Option<i64>,
+    pub parent_uuid: Option<Uuid>,
+    pub content_error: Option<String>,
+    pub tags: Vec<String>,
+    pub custom_fields: BTreeMap<String, String>,
+    pub terminal: String,
+}
+
+// An entry with the entries that were captured while it was running.
+#[derive(Serialize, Clone)]
+pub struct CaptureTreeNode {
+    #[serde(flatten)]
+    pub entry: CaptureV2_4Summary,
+    pub children: Vec<CaptureTreeNode>,
 }
 
 impl From<&CaptureV2_4> for CaptureV2_4Summary {
@@ -78,12 +296,52 @@ impl From<&CaptureV2_4> for CaptureV2_4Summary {
             message: entry.message.clone(),
             is_noop: entry.is_noop,
             exit_code: entry.exit_code,
+            status: entry.status.clone(),
             local_user: entry.local_user.clone(),
             remote_user: entry.remote_user.clone(),
             filename: entry.filename.clone(),
             terminal_rows: entry.terminal_rows,
             terminal_cols: entry.terminal_cols,
             task_id: entry.task_id,
+            content_error: entry.content_error.clone(),
+            parent_uuid: entry.parent_uuid,
+            tags: entry.tags.clone(),
+            custom_fields: entry.custom_fields.clone(),
+            terminal: entry.terminal.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_slice_to_capture() {
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: Utc::now(),
+            duration_ms: 10_000,
+            status: CaptureStatus::Completed,
+            local_user: "alice".to_string(),
+            cmd: "ssh db1".to_string(),
+            events: Vec::new(),
+        };
+        // As returned for 2000..5000: the last resize before the range, then the range itself
+        let events = vec![
+            CaptureEvent { offset_ms: 500, kind: CaptureEventKind::Resize { cols: 100, rows: 30 } },
+            CaptureEvent { offset_ms: 2000, kind: CaptureEventKind::Output(b"$ rm -rf /tmp/x\r\n".to_vec()) },
+            CaptureEvent { offset_ms: 4500, kind: CaptureEventKind::Output(b"$ ".to_vec()) },
+        ];
+        let capture = session.slice_to_capture(events, 2000, 5000);
+        assert_eq!(capture.start_time, session.start_time + chrono::Duration::milliseconds(2000));
+        assert_eq!(capture.duration_ms, 3000);
+        assert_eq!((capture.terminal_cols, capture.terminal_rows), (100, 30));
+        assert_eq!(
+            capture.events.iter().map(|event| event.offset_ms).collect::<Vec<_>>(),
+            vec![0, 0, 2500]
+        );
+        assert_eq!(capture.captured_output, b"$ rm -rf /tmp/x\r\n$ ".to_vec());
+        assert_eq!(capture.local_user, "alice");
+    }
+}
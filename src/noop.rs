@@ -0,0 +1,179 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::model::{ CaptureStatus, CaptureType, CaptureV2_4 };
+use crate::{ print_prodlog_message, prodlog_panic };
+
+/// A rule that marks captures as no-op, so entries like `ls` or `df -h` don't need
+/// to be marked by hand.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NoopRule {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: NoopRuleKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NoopRuleKind {
+    /// Commands matching one of these patterns, where `*` matches anything, e.g. "systemctl status *".
+    CommandPattern { patterns: Vec<String> },
+    /// Commands running one of these binaries, whatever their arguments.
+    ReadOnlyBinary { binaries: Vec<String> },
+    /// Edits that didn't change any of the files.
+    UnchangedEdit,
+}
+
+const NOOP_RULES_FILE: &str = "noop_rules.json";
+
+static NOOP_RULES: OnceLock<Vec<NoopRule>> = OnceLock::new();
+
+fn default_rules() -> Vec<NoopRule> {
+    let binaries = [
+        "ls", "cat", "less", "more", "head", "tail", "df", "du", "free", "uptime", "whoami", "id",
+        "pwd", "ps", "uname", "which", "stat", "wc", "w",
+    ];
+    let patterns = [
+        "systemctl status*",
+        "systemctl list-*",
+        "service * status",
+        "git status*",
+        "git log*",
+        "git diff*",
+        "git show*",
+        "kubectl get *",
+        "kubectl describe *",
+        "kubectl logs *",
+        "docker ps*",
+        "docker logs *",
+    ];
+    vec![
+        NoopRule {
+            name: "read-only-binaries".to_string(),
+            kind: NoopRuleKind::ReadOnlyBinary { binaries: binaries.iter().map(|b| b.to_string()).collect() },
+        },
+        NoopRule {
+            name: "status-commands".to_string(),
+            kind: NoopRuleKind::CommandPattern { patterns: patterns.iter().map(|p| p.to_string()).collect() },
+        },
+        NoopRule { name: "unchanged-edits".to_string(), kind: NoopRuleKind::UnchangedEdit }
+    ]
+}
+
+/// Loads the rules from `file`, or from noop_rules.json in the prodlog directory. The
+/// default rules are used if there is no such file.
+pub fn init_rules(prodlog_dir: &Path, file: Option<&Path>) {
+    let path = file.map(Path::to_path_buf).unwrap_or_else(|| prodlog_dir.join(NOOP_RULES_FILE));
+    let rules = match std::fs::read_to_string(&path) {
+        Ok(json) =>
+            match serde_json::from_str(&json) {
+                Ok(rules) => {
+                    print_prodlog_message(&format!("Using no-op rules from {:?}", path));
+                    rules
+                }
+                Err(e) => prodlog_panic(&format!("Error parsing no-op rules in {:?}: {}", path, e)),
+            }
+        Err(e) if file.is_none() && e.kind() == std::io::ErrorKind::NotFound => default_rules(),
+        Err(e) => prodlog_panic(&format!("Error reading no-op rules from {:?}: {}", path, e)),
+    };
+    let _ = NOOP_RULES.set(rules);
+}
+
+pub fn get_rules() -> &'static [NoopRule] {
+    NOOP_RULES.get_or_init(default_rules)
+}
+
+/// Returns the first rule that marks `capture` as no-op, if any.
+pub fn matching_rule(capture: &CaptureV2_4) -> Option<&'static NoopRule> {
+    get_rules()
+        .iter()
+        .find(|rule| rule.matches(capture))
+}
+
+impl NoopRule {
+    pub fn matches(&self, capture: &CaptureV2_4) -> bool {
+        match &self.kind {
+            NoopRuleKind::CommandPattern { patterns } =>
+                capture.capture_type == CaptureType::Run &&
+                    patterns.iter().any(|pattern| wildcard_match(pattern, without_sudo(&capture.cmd))),
+            NoopRuleKind::ReadOnlyBinary { binaries } => {
+                if capture.capture_type != CaptureType::Run {
+                    return false;
+                }
+                match command_binary(&capture.cmd) {
+                    Some(binary) => binaries.iter().any(|b| b == binary),
+                    None => false,
+                }
+            }
+            NoopRuleKind::UnchangedEdit =>
+                capture.capture_type == CaptureType::Edit &&
+                    capture.status == CaptureStatus::Completed &&
+                    capture.content_error.is_none() &&
+                    capture.original_content == capture.edited_content &&
+                    capture.additional_files.iter().all(|file| file.original_content == file.edited_content),
+        }
+    }
+}
+
+fn without_sudo(cmd: &str) -> &str {
+    let cmd = cmd.trim_start();
+    match cmd.strip_prefix("sudo ") {
+        Some(rest) => rest.trim_start(),
+        None => cmd,
+    }
+}
+
+/// The name of the binary a command runs, skipping sudo and environment assignments.
+fn command_binary(cmd: &str) -> Option<&str> {
+    let binary = without_sudo(cmd)
+        .split_whitespace()
+        .find(|word| !word.contains('='))?;
+    Some(binary.rsplit('/').next().unwrap_or(binary))
+}
+
+/// Matches `text` against `pattern`, where `*` matches any sequence of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    // No `*` in the pattern
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("systemctl status*", "systemctl status nginx"));
+        assert!(wildcard_match("systemctl status*", "systemctl status"));
+        assert!(!wildcard_match("systemctl status*", "systemctl restart nginx"));
+        assert!(wildcard_match("service * status", "service nginx status"));
+        assert!(!wildcard_match("service * status", "service nginx stop"));
+        assert!(wildcard_match("df", "df"));
+        assert!(!wildcard_match("df", "df -h"));
+    }
+
+    #[test]
+    fn test_command_binary() {
+        assert_eq!(command_binary("ls -la"), Some("ls"));
+        assert_eq!(command_binary("sudo /usr/bin/cat /etc/shadow"), Some("cat"));
+        assert_eq!(command_binary("LANG=C df -h"), Some("df"));
+        assert_eq!(command_binary(""), None);
+    }
+}
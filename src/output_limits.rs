@@ -0,0 +1,313 @@
+use std::collections::VecDeque;
+
+use crate::model::{ CaptureEvent, CaptureEventKind };
+
+// A line that doesn't end is recorded anyway once it's this long, e.g. for full screen
+// programs that never print a newline.
+const MAX_HELD_LINE: usize = 16 * 1024;
+
+/// How much of the output of each capture is kept.
+#[derive(Clone, Copy, Default)]
+pub struct OutputLimits {
+    /// Keep only the first and the last this many bytes of long output.
+    pub head_tail: Option<(usize, usize)>,
+    /// Keep only the final state of lines redrawn with carriage returns, like progress bars.
+    pub collapse_redraws: bool,
+}
+
+/// Applies the output limits to the events of a capture as they're recorded.
+pub struct OutputCompactor {
+    limits: OutputLimits,
+    // The output since the last newline when collapsing redraws, which a carriage
+    // return may still redraw.
+    line: Vec<u8>,
+    // The output after collapsing redraws, like dropped_bytes, so the marker adds up.
+    total_bytes: usize,
+    head_bytes: usize,
+    // Set once the head is full. The events after it are held back, and the oldest of
+    // them dropped to keep the tail within its limit.
+    in_tail: bool,
+    tail: VecDeque<CaptureEvent>,
+    tail_bytes: usize,
+    dropped_bytes: usize,
+    // What was typed while the output in front of it was dropped, which goes with it.
+    dropped_inputs: usize,
+    // The last resize dropped from the tail, which still sets the terminal size for
+    // the output after it.
+    dropped_resize: Option<CaptureEvent>,
+}
+
+impl OutputCompactor {
+    pub fn new(limits: OutputLimits) -> Self {
+        OutputCompactor {
+            limits,
+            line: Vec::new(),
+            total_bytes: 0,
+            head_bytes: 0,
+            in_tail: false,
+            tail: VecDeque::new(),
+            tail_bytes: 0,
+            dropped_bytes: 0,
+            dropped_inputs: 0,
+            dropped_resize: None,
+        }
+    }
+
+    /// Returns the events to record now, which may be none while output is held back.
+    pub fn push(&mut self, event: CaptureEvent) -> Vec<CaptureEvent> {
+        let mut ready = Vec::new();
+        let offset_ms = event.offset_ms;
+        match event.kind {
+            CaptureEventKind::Output(data) if self.limits.collapse_redraws => {
+                self.line.extend_from_slice(&data);
+                let complete = self.line.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                let rest = self.line.split_off(complete);
+                let lines = std::mem::replace(&mut self.line, collapse_redraws(&rest));
+                if !lines.is_empty() {
+                    self.limit(output_event(offset_ms, collapse_redraws(&lines)), &mut ready);
+                }
+                if self.line.len() >= MAX_HELD_LINE {
+                    self.flush_line(offset_ms, &mut ready);
+                }
+            }
+            CaptureEventKind::Output(_) => {
+                self.limit(event, &mut ready);
+            }
+            _ => {
+                // E.g. what's typed at a prompt goes after the prompt
+                self.flush_line(offset_ms, &mut ready);
+                self.limit(event, &mut ready);
+            }
+        }
+        ready
+    }
+
+    /// Whether events are held back, which are then only returned by `finish`.
+    pub fn is_holding_back(&self) -> bool {
+        self.in_tail
+    }
+
+    /// The events held back so far, with a marker in place of the dropped output if
+    /// any, as `finish` would return them now. They're stored while the capture runs,
+    /// so a crash doesn't lose them.
+    pub fn held(&self, offset_ms: u64) -> Vec<CaptureEvent> {
+        let mut held = Vec::new();
+        let start_offset_ms = self.tail.front().map_or(offset_ms, |event| event.offset_ms);
+        if self.dropped_bytes > 0 || self.dropped_inputs > 0 {
+            let inputs = match self.dropped_inputs {
+                0 => String::new(),
+                1 => " and 1 input".to_string(),
+                n => format!(" and {} inputs", n),
+            };
+            let marker = format!(
+                "\r\n\x1b[7m[prodlog: {} bytes of output{} dropped, {} bytes in total]\x1b[0m\r\n",
+                self.dropped_bytes,
+                inputs,
+                self.total_bytes
+            );
+            held.push(output_event(start_offset_ms, marker.into_bytes()));
+        }
+        if let Some(resize) = &self.dropped_resize {
+            held.push(CaptureEvent { offset_ms: start_offset_ms, kind: resize.kind.clone() });
+        }
+        held.extend(self.tail.iter().cloned());
+        held
+    }
+
+    /// Returns the events that were held back, with a marker in place of the dropped
+    /// output if any.
+    pub fn finish(&mut self, offset_ms: u64) -> Vec<CaptureEvent> {
+        let mut ready = Vec::new();
+        self.flush_line(offset_ms, &mut ready);
+        ready.extend(self.held(offset_ms));
+        self.tail.clear();
+        self.tail_bytes = 0;
+        self.dropped_bytes = 0;
+        self.dropped_inputs = 0;
+        self.dropped_resize = None;
+        ready
+    }
+
+    fn flush_line(&mut self, offset_ms: u64, ready: &mut Vec<CaptureEvent>) {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.limit(output_event(offset_ms, line), ready);
+        }
+    }
+
+    fn limit(&mut self, mut event: CaptureEvent, ready: &mut Vec<CaptureEvent>) {
+        if let CaptureEventKind::Output(data) = &event.kind {
+            self.total_bytes += data.len();
+        }
+        let Some((head_limit, tail_limit)) = self.limits.head_tail else {
+            ready.push(event);
+            return;
+        };
+        if !self.in_tail {
+            let CaptureEventKind::Output(data) = &mut event.kind else {
+                ready.push(event);
+                return;
+            };
+            if self.head_bytes + data.len() <= head_limit {
+                self.head_bytes += data.len();
+                ready.push(event);
+                return;
+            }
+            let rest = data.split_off(head_limit - self.head_bytes);
+            self.head_bytes = head_limit;
+            self.in_tail = true;
+            if !data.is_empty() {
+                ready.push(output_event(event.offset_ms, std::mem::take(data)));
+            }
+            *data = rest;
+        }
+
+        if let CaptureEventKind::Output(data) = &event.kind {
+            self.tail_bytes += data.len();
+        }
+        self.tail.push_back(event);
+        while self.tail_bytes > tail_limit {
+            let excess = self.tail_bytes - tail_limit;
+            let front = self.tail.front_mut().unwrap();
+            match &mut front.kind {
+                CaptureEventKind::Output(data) if data.len() > excess => {
+                    // Start the tail at a line if there's one, rather than halfway
+                    // through a line or escape sequence
+                    let start = data[excess..].iter().position(|&b| b == b'\n').map_or(excess, |i| excess + i + 1);
+                    data.drain(..start);
+                    self.tail_bytes -= start;
+                    self.dropped_bytes += start;
+                    if data.is_empty() {
+                        self.tail.pop_front();
+                    }
+                }
+                CaptureEventKind::Output(data) => {
+                    self.tail_bytes -= data.len();
+                    self.dropped_bytes += data.len();
+                    self.tail.pop_front();
+                }
+                CaptureEventKind::Resize { .. } => {
+                    self.dropped_resize = self.tail.pop_front();
+                }
+                CaptureEventKind::Input(_) | CaptureEventKind::MaskedInput => {
+                    self.dropped_inputs += 1;
+                    self.tail.pop_front();
+                }
+            }
+        }
+    }
+}
+
+fn output_event(offset_ms: u64, data: Vec<u8>) -> CaptureEvent {
+    CaptureEvent { offset_ms, kind: CaptureEventKind::Output(data) }
+}
+
+/// Keeps only what's shown after the last redraw of each line, for lines redrawn with
+/// a carriage return. A carriage return at the very end may still turn out to end the
+/// line, so it's kept.
+pub fn collapse_redraws(output: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(output.len());
+    for line in output.split_inclusive(|&b| b == b'\n') {
+        let redraw = line.windows(2).rposition(|pair| pair[0] == b'\r' && pair[1] != b'\n');
+        match redraw.and_then(|redraw| Some((redraw, escape_sequences(&line[..redraw])?))) {
+            Some((redraw, sequences)) => {
+                collapsed.extend_from_slice(&sequences);
+                collapsed.extend_from_slice(&line[redraw + 1..]);
+            }
+            None => collapsed.extend_from_slice(line),
+        }
+    }
+    collapsed
+}
+
+// The escape sequences in the redrawn part of a line, which still apply after the
+// redraw, e.g. colours. None if any of them moves the cursor to another line or changes
+// other lines, as full screen programs do: they also use carriage returns, so their
+// output isn't collapsed.
+fn escape_sequences(text: &[u8]) -> Option<Vec<u8>> {
+    let mut sequences = Vec::new();
+    let mut pos = 0;
+    while let Some(esc) = text[pos..].iter().position(|&b| b == 0x1b) {
+        let start = pos + esc;
+        if text.get(start + 1) != Some(&b'[') {
+            return None;
+        }
+        let params_len = text[start + 2..].iter().position(|&b| !(b.is_ascii_digit() || b == b';' || b == b'?'))?;
+        let end = start + 2 + params_len;
+        // Colours, erasing in the line, and modes like hiding the cursor
+        if !matches!(text[end], b'm' | b'K' | b'h' | b'l') {
+            return None;
+        }
+        sequences.extend_from_slice(&text[start..=end]);
+        pos = end + 1;
+    }
+    Some(sequences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(events: &[CaptureEvent]) -> String {
+        let bytes: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                CaptureEventKind::Output(data) => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_collapse_redraws() {
+        assert_eq!(collapse_redraws(b"\x1b[32m 10%\r 50%\r100%\r\ndone\r"), b"\x1b[32m100%\r\ndone\r");
+        // Cursor movement means it's not a progress bar
+        assert_eq!(collapse_redraws(b"\x1b[2;1Ha\rb\n"), b"\x1b[2;1Ha\rb\n");
+
+        let mut compactor = OutputCompactor::new(OutputLimits { head_tail: None, collapse_redraws: true });
+        assert_eq!(output(&compactor.push(output_event(0, b"start\n 10%".to_vec()))), "start\n");
+        assert_eq!(output(&compactor.push(output_event(1, b"\r 50%\r".to_vec()))), "");
+        assert_eq!(output(&compactor.push(output_event(2, b"100%\r\n".to_vec()))), "100%\r\n");
+        assert_eq!(output(&compactor.finish(3)), "");
+    }
+
+    #[test]
+    fn test_head_and_tail() {
+        let mut compactor = OutputCompactor::new(OutputLimits { head_tail: Some((6, 6)), collapse_redraws: false });
+        let mut events = compactor.push(output_event(0, b"head\nmid".to_vec()));
+        events.extend(compactor.push(CaptureEvent { offset_ms: 1, kind: CaptureEventKind::MaskedInput }));
+        events.extend(compactor.push(output_event(2, b"dle\nta".to_vec())));
+        assert_eq!(output(&events), "head\nm");
+        events.extend(compactor.push(output_event(3, b"il\n".to_vec())));
+        // The tail is dropped along with what was typed in it, and can be stored as
+        // it's held back
+        let held = "\r\n\x1b[7m[prodlog: 6 bytes of output and 1 input dropped, 17 bytes in total]\x1b[0m\r\ntail\n";
+        assert!(compactor.is_holding_back());
+        assert_eq!(output(&compactor.held(4)), held);
+        events.extend(compactor.finish(4));
+        assert_eq!(output(&events), format!("head\nm{}", held));
+    }
+
+    #[test]
+    fn test_tail_keeps_terminal_size() {
+        let mut compactor = OutputCompactor::new(OutputLimits { head_tail: Some((0, 4)), collapse_redraws: true });
+        let mut events = compactor.push(CaptureEvent { offset_ms: 0, kind: CaptureEventKind::Resize { cols: 120, rows: 40 } });
+        events.extend(compactor.push(output_event(1, b" 10%\r 50%\r100%\n".to_vec())));
+        events.extend(compactor.push(CaptureEvent { offset_ms: 2, kind: CaptureEventKind::Resize { cols: 100, rows: 50 } }));
+        events.extend(compactor.push(output_event(3, b"0123456789\n".to_vec())));
+        events.extend(compactor.push(output_event(4, b"ok\n".to_vec())));
+        events.extend(compactor.finish(5));
+        // The size is restored at the start of the tail, and the bytes add up after collapsing
+        assert_eq!(
+            events.iter().map(|event| event.kind.clone()).collect::<Vec<_>>(),
+            [
+                CaptureEventKind::Resize { cols: 120, rows: 40 },
+                CaptureEventKind::Output(b"\r\n\x1b[7m[prodlog: 16 bytes of output dropped, 19 bytes in total]\x1b[0m\r\n".to_vec()),
+                CaptureEventKind::Resize { cols: 100, rows: 50 },
+                CaptureEventKind::Output(b"ok\n".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use vte::{ Parser, Perform };
+
+const MAX_TYPED_CHARS: usize = 4096;
+
+/// What shells with shell integration tell the terminal, using OSC 133 semantic
+/// prompt marks and OSC 7 for the working directory.
+#[derive(Debug, PartialEq)]
+pub enum ShellMark {
+    /// OSC 133;A, the shell is about to print its prompt.
+    PromptStart,
+    /// OSC 133;B, the prompt was printed and the user types a command.
+    CommandStart,
+    /// OSC 133;C, the command was entered and starts running.
+    CommandExecuted { command_line: String },
+    /// OSC 133;D, the command finished, usually with its exit status.
+    CommandFinished { exit_code: Option<i32> },
+    /// OSC 7, the working directory as a file:// URL.
+    WorkingDirectory { host: String, path: String },
+}
+
+/// Finds shell marks in the output of the session. The output itself is passed on
+/// unchanged, so the marks still reach the terminal.
+pub struct ShellMarkParser {
+    parser: Parser,
+    performer: MarkCollector,
+}
+
+#[derive(Default)]
+struct MarkCollector {
+    mark: Option<ShellMark>,
+    // What was echoed since the CommandStart mark. Not all shells put the command line
+    // in the CommandExecuted mark, so we fall back to what the user typed.
+    typed: Option<String>,
+}
+
+impl Perform for MarkCollector {
+    fn print(&mut self, c: char) {
+        if let Some(typed) = self.typed.as_mut() {
+            if typed.len() < MAX_TYPED_CHARS {
+                typed.push(c);
+            }
+        }
+    }
+
+    fn execute(&mut self, byte: u8) {
+        if let Some(typed) = self.typed.as_mut() {
+            match byte {
+                // Backspace, when the user corrects a typo
+                0x08 => {
+                    typed.pop();
+                }
+                b'\n' => typed.push('\n'),
+                _ => (),
+            }
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        let mark = match parse_mark(params) {
+            Some(mark) => mark,
+            None => {
+                return;
+            }
+        };
+        self.mark = Some(match mark {
+            ShellMark::PromptStart => {
+                self.typed = None;
+                mark
+            }
+            ShellMark::CommandStart => {
+                self.typed = Some(String::new());
+                mark
+            }
+            ShellMark::CommandExecuted { command_line } if command_line.is_empty() => {
+                let typed = self.typed.take().unwrap_or_default();
+                ShellMark::CommandExecuted { command_line: typed.trim().to_string() }
+            }
+            ShellMark::CommandExecuted { .. } => {
+                self.typed = None;
+                mark
+            }
+            _ => mark,
+        });
+    }
+
+    fn terminated(&self) -> bool {
+        self.mark.is_some()
+    }
+}
+
+impl Default for ShellMarkParser {
+    fn default() -> Self {
+        ShellMarkParser { parser: Parser::new(), performer: MarkCollector::default() }
+    }
+}
+
+impl ShellMarkParser {
+    /// Returns the marks in `buf`, each with the position in `buf` right after it.
+    /// Marks can be split over several calls.
+    pub fn feed(&mut self, buf: &[u8]) -> Vec<(usize, ShellMark)> {
+        let mut marks = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            pos += self.parser.advance_until_terminated(&mut self.performer, &buf[pos..]);
+            if let Some(mark) = self.performer.mark.take() {
+                marks.push((pos, mark));
+            }
+        }
+        marks
+    }
+}
+
+fn parse_mark(params: &[&[u8]]) -> Option<ShellMark> {
+    let text = |param: &[u8]| String::from_utf8_lossy(param).into_owned();
+    match params {
+        [b"133", kind, options @ ..] =>
+            match *kind {
+                b"A" => Some(ShellMark::PromptStart),
+                b"B" => Some(ShellMark::CommandStart),
+                b"C" => {
+                    // Options are key=value, but a cmdline= option isn't escaped so it
+                    // may contain semicolons itself.
+                    let options: Vec<String> = options.iter().map(|option| text(option)).collect();
+                    let command_line = match options.iter().position(|option| option.starts_with("cmdline=")) {
+                        Some(index) => options[index..].join(";")["cmdline=".len()..].to_string(),
+                        None =>
+                            options
+                                .iter()
+                                .find_map(|option| option.strip_prefix("cmdline_url="))
+                                .and_then(|url| urlencoding::decode(url).ok())
+                                .map(|cmdline| cmdline.into_owned())
+                                .unwrap_or_default(),
+                    };
+                    Some(ShellMark::CommandExecuted { command_line })
+                }
+                b"D" => {
+                    let exit_code = options.first().and_then(|code| text(code).parse().ok());
+                    Some(ShellMark::CommandFinished { exit_code })
+                }
+                _ => None,
+            }
+        [b"7", url @ ..] => {
+            let url = url.iter().map(|part| text(part)).collect::<Vec<_>>().join(";");
+            let (host, path) = url.strip_prefix("file://")?.split_once('/')?;
+            let path = urlencoding::decode(path).ok()?;
+            Some(ShellMark::WorkingDirectory { host: host.to_string(), path: format!("/{}", path) })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_marks() {
+        let mut parser = ShellMarkParser::default();
+        let output = b"\x1b]7;file://db1/srv/my%20app\x07\x1b]133;A\x07$ \x1b]133;B\x07\x1b]133;C;cmdline=echo a;b\x1b\\a\r\nb\r\n\x1b]133;D;2\x07";
+        let marks: Vec<ShellMark> = parser.feed(output).into_iter().map(|(_, mark)| mark).collect();
+        assert_eq!(marks, vec![
+            ShellMark::WorkingDirectory { host: "db1".to_string(), path: "/srv/my app".to_string() },
+            ShellMark::PromptStart,
+            ShellMark::CommandStart,
+            ShellMark::CommandExecuted { command_line: "echo a;b".to_string() },
+            ShellMark::CommandFinished { exit_code: Some(2) }
+        ]);
+    }
+
+    #[test]
+    fn test_shell_marks_typed_command_line() {
+        let mut parser = ShellMarkParser::default();
+        // Split in the middle of a mark, with the user correcting a typo
+        let marks = parser.feed(b"\x1b]133;B\x07lz\x08 \x08s -l\r\n\x1b]13");
+        assert_eq!(marks, vec![(8, ShellMark::CommandStart)]);
+        let marks = parser.feed(b"3;C\x07output");
+        assert_eq!(marks, vec![(4, ShellMark::CommandExecuted { command_line: "ls -l".to_string() })]);
+    }
+}
@@ -0,0 +1,859 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fs::File;
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use nix::fcntl::{ Flock, FlockArg };
+use rusqlite::{ params, Connection, OptionalExtension };
+use serde::{ Deserialize, Serialize };
+use uuid::Uuid;
+
+use crate::model::{ CaptureEvent, CaptureV2_4, Session, Task };
+use crate::{ print_prodlog_message, print_prodlog_warning };
+use super::jsonl::JsonlSink;
+use super::postgres::PostgresSink;
+use super::sqlite::SqliteSink;
+use super::{ Filters, Sink };
+
+// The queue in the prodlog directory that holds the writes for secondary sinks until
+// they're delivered.
+const QUEUE_FILE: &str = "secondary_queue.sqlite";
+// Held by the instance delivering the queue, so each secondary gets its writes in order.
+const LOCK_FILE: &str = "secondary_queue.lock";
+
+// The format of the writes in the queue, which outlives the prodlog_record that queued
+// them. A change that older writes can't be read with needs a new format, and a way to
+// deliver the writes in the old one, or they end up in the dead letters.
+const QUEUE_FORMAT: i64 = 1;
+
+// How often to look for writes queued by other instances while everything is delivered
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// A capture with its events, which aren't part of its JSON representation, and the
+// name of its task.
+#[derive(Serialize, Deserialize)]
+struct QueuedCapture {
+    capture: CaptureV2_4,
+    events: Vec<CaptureEvent>,
+    task: Option<String>,
+}
+
+impl QueuedCapture {
+    fn into_capture(self, sink: &dyn Sink) -> Result<CaptureV2_4, std::io::Error> {
+        let mut capture = self.capture;
+        capture.events = self.events;
+        capture.task_id = resolve_task(sink, self.task)?;
+        Ok(capture)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueuedSession {
+    session: Session,
+    events: Vec<CaptureEvent>,
+}
+
+impl From<&Session> for QueuedSession {
+    fn from(session: &Session) -> Self {
+        QueuedSession { session: session.clone(), events: session.events.clone() }
+    }
+}
+
+impl QueuedSession {
+    fn into_session(self) -> Session {
+        let mut session = self.session;
+        session.events = self.events;
+        session
+    }
+}
+
+// A write queued for the secondaries, one per writing Sink method, in QUEUE_FORMAT.
+// Secondaries number their tasks themselves, so tasks are referred to by name.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "write", rename_all = "snake_case")]
+enum QueuedWrite {
+    AddNewEntry { capture: QueuedCapture },
+    UpdateEntry { capture: QueuedCapture },
+    RedactEntry { capture: QueuedCapture },
+    BeginEntry { capture: QueuedCapture },
+    AppendEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    ReplaceEventsAfter { uuid: Uuid, kept: usize, events: Vec<CaptureEvent> },
+    FinalizeEntry { capture: QueuedCapture },
+    CreateTask { name: String },
+    UpdateTaskName { old_name: String, name: String },
+    AssignEntriesToTask { entry_uuids: Vec<String>, task: Option<String> },
+    SetActiveTask { task: Option<String> },
+    SetEntryTags { uuid: Uuid, tags: Vec<String> },
+    SetEntryNoop { uuid: Uuid, is_noop: bool },
+    SetEntryCustomFields { uuid: Uuid, fields: BTreeMap<String, String> },
+    BeginSession { session: QueuedSession },
+    AppendSessionEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    RedactSessionEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    FinalizeSession { session: QueuedSession },
+}
+
+impl QueuedWrite {
+    fn apply(self, sink: &dyn Sink) -> Result<(), std::io::Error> {
+        match self {
+            QueuedWrite::AddNewEntry { capture } => sink.add_new_entry(&capture.into_capture(sink)?),
+            QueuedWrite::UpdateEntry { capture } => sink.update_entry(&capture.into_capture(sink)?),
+            QueuedWrite::RedactEntry { capture } => sink.redact_entry(&capture.into_capture(sink)?),
+            QueuedWrite::BeginEntry { capture } => sink.begin_entry(&capture.into_capture(sink)?),
+            QueuedWrite::AppendEvents { uuid, events } => sink.append_events(uuid, &events),
+            QueuedWrite::ReplaceEventsAfter { uuid, kept, events } => sink.replace_events_after(uuid, kept, &events),
+            QueuedWrite::FinalizeEntry { capture } => sink.finalize_entry(&capture.into_capture(sink)?),
+            // Delivering it again finds the task created the first time
+            QueuedWrite::CreateTask { name } => resolve_task_name(sink, &name).map(|_| ()),
+            QueuedWrite::UpdateTaskName { old_name, name } => sink.update_task_name(resolve_task_name(sink, &old_name)?, &name),
+            QueuedWrite::AssignEntriesToTask { entry_uuids, task } =>
+                sink.assign_entries_to_task(&entry_uuids, resolve_task(sink, task)?),
+            QueuedWrite::SetActiveTask { task } => sink.set_active_task(resolve_task(sink, task)?),
+            QueuedWrite::SetEntryTags { uuid, tags } => sink.set_entry_tags(uuid, &tags),
+            QueuedWrite::SetEntryNoop { uuid, is_noop } => sink.set_entry_noop(uuid, is_noop),
+            QueuedWrite::SetEntryCustomFields { uuid, fields } => sink.set_entry_custom_fields(uuid, &fields),
+            QueuedWrite::BeginSession { session } => sink.begin_session(&session.into_session()),
+            QueuedWrite::AppendSessionEvents { uuid, events } => sink.append_session_events(uuid, &events),
+            QueuedWrite::RedactSessionEvents { uuid, events } => sink.redact_session_events(uuid, &events),
+            QueuedWrite::FinalizeSession { session } => sink.finalize_session(&session.into_session()),
+        }
+    }
+}
+
+// The id of the task with this name in a secondary, which is created if there's none.
+// Tasks with the same name are one task in the secondaries.
+fn resolve_task_name(sink: &dyn Sink, name: &str) -> Result<i64, std::io::Error> {
+    let existing = sink
+        .get_all_tasks()?
+        .into_iter()
+        .filter(|task| task.name == name)
+        .map(|task| task.id)
+        .min();
+    match existing {
+        Some(id) => Ok(id),
+        None => sink.create_task(name),
+    }
+}
+
+fn resolve_task(sink: &dyn Sink, name: Option<String>) -> Result<Option<i64>, std::io::Error> {
+    name.map(|name| resolve_task_name(sink, &name)).transpose()
+}
+
+// Whether a write that failed would fail again however often it's retried, because the
+// secondary rejects it rather than because it can't be reached right now.
+fn is_permanent(e: &std::io::Error) -> bool {
+    if matches!(e.kind(), std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData) {
+        return true;
+    }
+    let Some(inner) = e.get_ref() else {
+        return false;
+    };
+    if let Some(e) = inner.downcast_ref::<rusqlite::Error>() {
+        return match e {
+            rusqlite::Error::SqliteFailure(e, _) => matches!(
+                e.code,
+                rusqlite::ErrorCode::ConstraintViolation | rusqlite::ErrorCode::TooBig | rusqlite::ErrorCode::TypeMismatch
+            ),
+            rusqlite::Error::ToSqlConversionFailure(_) |
+            rusqlite::Error::FromSqlConversionFailure(..) |
+            rusqlite::Error::IntegralValueOutOfRange(..) |
+            rusqlite::Error::InvalidColumnType(..) |
+            rusqlite::Error::Utf8Error(_) => true,
+            _ => false,
+        };
+    }
+    if let Some(e) = inner.downcast_ref::<postgres::Error>() {
+        // SQLSTATE classes 22 and 23, data exceptions and integrity constraint violations
+        return e.code().is_some_and(|state| matches!(&state.code()[..2], "22" | "23"));
+    }
+    inner.is::<serde_json::Error>()
+}
+
+// A sink given with --secondary-sink.
+#[derive(Clone, Debug, PartialEq)]
+enum Secondary {
+    // sqlite:<file>
+    Sqlite(PathBuf),
+    // postgres://...
+    Postgres(String),
+    // jsonl:<dir>
+    Jsonl(PathBuf),
+}
+
+impl Secondary {
+    fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(file) = spec.strip_prefix("sqlite:") {
+            Ok(Secondary::Sqlite(PathBuf::from(file)))
+        } else if spec.starts_with("postgres://") || spec.starts_with("postgresql://") {
+            Ok(Secondary::Postgres(spec.to_string()))
+        } else if let Some(dir) = spec.strip_prefix("jsonl:") {
+            Ok(Secondary::Jsonl(PathBuf::from(dir)))
+        } else {
+            Err(format!("Unknown secondary sink {:?}, expected sqlite:<file>, postgres://... or jsonl:<dir>", spec))
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn Sink>, std::io::Error> {
+        Ok(match self {
+            Secondary::Sqlite(file) => {
+                // Rather than waiting for the pool to time out, e.g. when the disk the
+                // file is on isn't mounted
+                let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                if !dir.is_dir() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?} doesn't exist", dir)));
+                }
+                Box::new(SqliteSink::open(file)?)
+            }
+            Secondary::Postgres(url) => Box::new(PostgresSink::connect(url)?),
+            Secondary::Jsonl(dir) => Box::new(JsonlSink::open(dir)?),
+        })
+    }
+}
+
+/// Writes to a primary sink, and queues every write for the secondary sinks. The queue
+/// is delivered in the background, so a secondary that's down or slow doesn't hold up
+/// the terminal, and its writes are retried until it's back, even after a restart.
+/// Everything is read from the primary.
+pub struct FanoutSink {
+    primary: Box<dyn Sink>,
+    secondaries: Vec<String>,
+    queue: Mutex<Connection>,
+    wake: mpsc::Sender<()>,
+    // The names of the tasks of the primary, by id, so writes can be queued with them
+    // while the primary is down.
+    task_names: Mutex<HashMap<i64, String>>,
+    // The events of a capture or session that were queued, but that the primary didn't
+    // take. They're appended again along with the ones after them, which are all that's
+    // queued then.
+    unacknowledged: Mutex<HashMap<Uuid, Vec<CaptureEvent>>>,
+}
+
+// Wakes up the thread that delivers the queue. There's one per process, shared by its
+// sinks, which all write to the same queue.
+static DELIVERY: OnceLock<mpsc::Sender<()>> = OnceLock::new();
+
+fn open_queue(queue_file: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(queue_file)?;
+    // Several instances write to the queue while one of them delivers it
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS queue (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sink TEXT NOT NULL,
+            request TEXT NOT NULL,
+            queued_at DATETIME NOT NULL,
+            format INTEGER NOT NULL
+        )",
+        []
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS queue_sink ON queue (sink, id)", [])?;
+    // Writes that can't be delivered, so they don't hold up the ones after them. They're
+    // kept to be looked into, and written by hand if need be.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dead_letters (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            sink TEXT NOT NULL,
+            request TEXT NOT NULL,
+            queued_at DATETIME NOT NULL,
+            format INTEGER NOT NULL,
+            failed_at DATETIME NOT NULL,
+            error TEXT NOT NULL
+        )",
+        []
+    )?;
+    Ok(conn)
+}
+
+impl FanoutSink {
+    /// `secondaries` are specs as accepted by `Secondary::parse`, and their writes are
+    /// queued in `prodlog_dir`.
+    pub fn new(primary: Box<dyn Sink>, prodlog_dir: &Path, secondaries: &[String]) -> Result<Self, std::io::Error> {
+        let parsed = secondaries
+            .iter()
+            .map(|spec| Secondary::parse(spec).map(|secondary| (spec.clone(), secondary)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let queue_file = prodlog_dir.join(QUEUE_FILE);
+        let queue = open_queue(&queue_file).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        let wake = DELIVERY.get_or_init(|| {
+            let (wake, woken) = mpsc::channel();
+            // Delivers what's left from earlier runs right away
+            let _ = wake.send(());
+            let lock_file = prodlog_dir.join(LOCK_FILE);
+            std::thread::spawn(move || deliver(&queue_file, &lock_file, parsed, woken));
+            wake
+        });
+        let task_names = primary
+            .get_all_tasks()
+            .map(|tasks| tasks.into_iter().map(|task| (task.id, task.name)).collect())
+            .unwrap_or_default();
+        Ok(FanoutSink {
+            primary,
+            secondaries: secondaries.to_vec(),
+            queue: Mutex::new(queue),
+            wake: wake.clone(),
+            task_names: Mutex::new(task_names),
+            unacknowledged: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Queues `request` for the secondaries before running `write` on the primary, so
+    /// the secondaries get it even if the primary fails.
+    fn write<T>(
+        &self,
+        request: QueuedWrite,
+        write: impl FnOnce(&dyn Sink) -> Result<T, std::io::Error>
+    ) -> Result<T, std::io::Error> {
+        self.queue(&request);
+        write(self.primary.as_ref())
+    }
+
+    /// Like write, for appending `events`. When the primary fails, the same events are
+    /// appended again later, and only the ones after them are queued then.
+    fn append(
+        &self,
+        uuid: Uuid,
+        events: &[CaptureEvent],
+        request: fn(Uuid, Vec<CaptureEvent>) -> QueuedWrite,
+        append: impl FnOnce(&dyn Sink) -> Result<(), std::io::Error>
+    ) -> Result<(), std::io::Error> {
+        let mut unacknowledged = self.unacknowledged.lock().unwrap();
+        let queued = unacknowledged
+            .remove(&uuid)
+            .filter(|queued| events.starts_with(queued))
+            .map_or(0, |queued| queued.len());
+        if queued < events.len() {
+            self.queue(&request(uuid, events[queued..].to_vec()));
+        }
+        let result = append(self.primary.as_ref());
+        if result.is_err() {
+            unacknowledged.insert(uuid, events.to_vec());
+        }
+        result
+    }
+
+    fn queue(&self, request: &QueuedWrite) {
+        if let Err(e) = self.enqueue(request) {
+            print_prodlog_warning(&format!("Can't queue a write for the secondary sinks: {}", e));
+        }
+        let _ = self.wake.send(());
+    }
+
+    // The name of a task of the primary, which is how the secondaries know it. Tasks
+    // that were created since this started are looked up in the primary.
+    fn task_name(&self, task_id: i64) -> Option<String> {
+        if let Some(name) = self.task_names.lock().unwrap().get(&task_id) {
+            return Some(name.clone());
+        }
+        let name = self.primary.get_task_by_id(task_id).ok().flatten()?.name;
+        self.task_names.lock().unwrap().insert(task_id, name.clone());
+        Some(name)
+    }
+
+    // Like task_name, but a task that can't be found is left out of the write, so the
+    // write still reaches the secondaries.
+    fn queued_task_name(&self, task_id: Option<i64>) -> Option<String> {
+        let task_id = task_id?;
+        let name = self.task_name(task_id);
+        if name.is_none() {
+            print_prodlog_warning(&format!("Can't find task {}, the secondary sinks get the write without it", task_id));
+        }
+        name
+    }
+
+    fn cache_task_names(&self, tasks: &[Task]) {
+        let mut task_names = self.task_names.lock().unwrap();
+        task_names.extend(tasks.iter().map(|task| (task.id, task.name.clone())));
+    }
+
+    fn queued_capture(&self, capture: &CaptureV2_4) -> QueuedCapture {
+        QueuedCapture { capture: capture.clone(), events: capture.events.clone(), task: self.queued_task_name(capture.task_id) }
+    }
+
+    // Drops the replacements of the events of an entry that weren't delivered yet and
+    // that a replacement after the first `kept` of them replaces in turn, so that the
+    // output held back by a long capture doesn't pile up while a secondary is down.
+    fn drop_replaced(&self, uuid: Uuid, kept: usize) -> Result<(), std::io::Error> {
+        self.queue
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM queue WHERE json_extract(request, '$.write') = 'replace_events_after'
+                 AND json_extract(request, '$.uuid') = ?1 AND json_extract(request, '$.kept') >= ?2",
+                params![uuid.to_string(), kept as i64]
+            )
+            .map(|_| ())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn enqueue(&self, request: &QueuedWrite) -> Result<(), std::io::Error> {
+        let request = serde_json::to_string(request)?;
+        let mut conn = self.queue.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        for spec in &self.secondaries {
+            tx.execute(
+                "INSERT INTO queue (sink, request, queued_at, format) VALUES (?1, ?2, ?3, ?4)",
+                params![spec, request, chrono::Utc::now(), QUEUE_FORMAT]
+            ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
+
+// Runs on a thread of its own until prodlog_record exits. Each secondary is retried on
+// its own schedule, so one that's down doesn't delay the others.
+fn deliver(queue_file: &Path, lock_file: &Path, secondaries: Vec<(String, Secondary)>, woken: mpsc::Receiver<()>) {
+    let queue = match open_queue(queue_file) {
+        Ok(queue) => queue,
+        Err(e) => {
+            print_prodlog_warning(&format!("Can't open {:?}, not writing to the secondary sinks: {}", queue_file, e));
+            return;
+        }
+    };
+    let mut sinks: HashMap<String, Box<dyn Sink>> = HashMap::new();
+    // When failing secondaries are retried next, and how long to wait after that
+    let mut retries: HashMap<String, (Instant, Duration)> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        let timeout = retries
+            .values()
+            .map(|(retry_at, _)| retry_at.saturating_duration_since(now))
+            .min()
+            .unwrap_or(POLL_INTERVAL)
+            .min(POLL_INTERVAL);
+        if let Err(RecvTimeoutError::Disconnected) = woken.recv_timeout(timeout) {
+            return;
+        }
+
+        let lock = File::create(lock_file)
+            .ok()
+            .and_then(|file| Flock::lock(file, FlockArg::LockExclusiveNonblock).ok());
+        let Some(_lock) = lock else {
+            // Another instance is delivering, it'll pick up our writes as well
+            for (retry_at, _) in retries.values_mut() {
+                *retry_at = (*retry_at).max(Instant::now() + POLL_INTERVAL);
+            }
+            continue;
+        };
+        for (spec, secondary) in &secondaries {
+            if retries.get(spec).is_some_and(|(retry_at, _)| Instant::now() < *retry_at) {
+                continue;
+            }
+            match deliver_pending(&queue, spec, secondary, &mut sinks) {
+                Ok(()) => {
+                    if retries.remove(spec).is_some() {
+                        print_prodlog_message(&format!("Caught up with secondary sink {}", spec));
+                    }
+                }
+                Err(e) => {
+                    sinks.remove(spec);
+                    let delay = match retries.get(spec) {
+                        Some((_, delay)) => (*delay * 2).min(MAX_RETRY_DELAY),
+                        None => {
+                            print_prodlog_warning(
+                                &format!("Can't write to secondary sink {} ({}), retrying in the background", spec, e)
+                            );
+                            MIN_RETRY_DELAY
+                        }
+                    };
+                    retries.insert(spec.clone(), (Instant::now() + delay, delay));
+                }
+            }
+        }
+    }
+}
+
+// Delivers the writes queued for one secondary, oldest first, and stops at the first
+// one that fails for now. A write is removed from the queue once the secondary took it,
+// or moved to the dead letters if it never will.
+fn deliver_pending(
+    queue: &Connection,
+    spec: &str,
+    secondary: &Secondary,
+    sinks: &mut HashMap<String, Box<dyn Sink>>
+) -> Result<(), std::io::Error> {
+    loop {
+        let next: Option<(i64, i64, String)> = queue
+            .query_row("SELECT id, format, request FROM queue WHERE sink = ?1 ORDER BY id LIMIT 1", params![spec], |row|
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            )
+            .optional()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let Some((id, format, request)) = next else {
+            return Ok(());
+        };
+        if format > QUEUE_FORMAT {
+            // Until the newer prodlog_record delivers them
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "its writes were queued by a newer prodlog_record"));
+        }
+        let write = match serde_json::from_str::<QueuedWrite>(&request) {
+            Ok(write) => write,
+            Err(e) => {
+                let error = format!("Can't be read: {}", e);
+                move_to_dead_letters(queue, spec, id, &error).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                continue;
+            }
+        };
+        let sink = match sinks.entry(spec.to_string()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(secondary.open()?),
+        };
+        match write.apply(sink.as_ref()) {
+            Ok(()) => {
+                queue
+                    .execute("DELETE FROM queue WHERE id = ?1", params![id])
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            }
+            Err(e) if is_permanent(&e) => {
+                move_to_dead_letters(queue, spec, id, &e.to_string()).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn move_to_dead_letters(queue: &Connection, spec: &str, id: i64, error: &str) -> rusqlite::Result<()> {
+    print_prodlog_warning(
+        &format!("A write for secondary sink {} can't be delivered, it's kept in the dead_letters table of {}: {}", spec, QUEUE_FILE, error)
+    );
+    let tx = queue.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO dead_letters (sink, request, queued_at, format, failed_at, error)
+         SELECT sink, request, queued_at, format, ?2, ?3 FROM queue WHERE id = ?1",
+        params![id, chrono::Utc::now(), error]
+    )?;
+    tx.execute("DELETE FROM queue WHERE id = ?1", params![id])?;
+    tx.commit()
+}
+
+impl Sink for FanoutSink {
+    fn add_new_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::AddNewEntry { capture: self.queued_capture(capture) }, |sink| sink.add_new_entry(capture))
+    }
+
+    fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::UpdateEntry { capture: self.queued_capture(capture) }, |sink| sink.update_entry(capture))
+    }
+
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::RedactEntry { capture: self.queued_capture(capture) }, |sink| sink.redact_entry(capture))
+    }
+
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::BeginEntry { capture: self.queued_capture(capture) }, |sink| sink.begin_entry(capture))
+    }
+
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.append(uuid, events, |uuid, events| QueuedWrite::AppendEvents { uuid, events }, |sink| sink.append_events(uuid, events))
+    }
+
+    fn replace_events_after(&self, uuid: Uuid, kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        if let Err(e) = self.drop_replaced(uuid, kept) {
+            print_prodlog_warning(&format!("Can't drop replaced writes from the queue of the secondary sinks: {}", e));
+        }
+        self.write(QueuedWrite::ReplaceEventsAfter { uuid, kept, events: events.to_vec() }, |sink|
+            sink.replace_events_after(uuid, kept, events)
+        )
+    }
+
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        // Events that weren't appended by now never will be
+        self.unacknowledged.lock().unwrap().remove(&capture.uuid);
+        self.write(QueuedWrite::FinalizeEntry { capture: self.queued_capture(capture) }, |sink| sink.finalize_entry(capture))
+    }
+
+    fn get_entries(&self, filters: &Filters) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        self.primary.get_entries(filters)
+    }
+
+    fn get_entry_by_id(&self, uuid: Uuid) -> Result<Option<CaptureV2_4>, std::io::Error> {
+        self.primary.get_entry_by_id(uuid)
+    }
+
+    fn get_child_entries(&self, parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        self.primary.get_child_entries(parent_uuid)
+    }
+
+    fn get_content_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.primary.get_content_blob(hash)
+    }
+
+    fn create_task(&self, name: &str) -> Result<i64, std::io::Error> {
+        let task_id = self.write(QueuedWrite::CreateTask { name: name.to_string() }, |sink| sink.create_task(name))?;
+        self.task_names.lock().unwrap().insert(task_id, name.to_string());
+        Ok(task_id)
+    }
+
+    fn get_all_tasks(&self) -> Result<Vec<Task>, std::io::Error> {
+        let tasks = self.primary.get_all_tasks()?;
+        self.cache_task_names(&tasks);
+        Ok(tasks)
+    }
+
+    fn get_task_by_id(&self, id: i64) -> Result<Option<Task>, std::io::Error> {
+        let task = self.primary.get_task_by_id(id)?;
+        self.cache_task_names(task.as_slice());
+        Ok(task)
+    }
+
+    fn update_task_name(&self, task_id: i64, name: &str) -> Result<(), std::io::Error> {
+        let Some(old_name) = self.task_name(task_id) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Task {} not found", task_id)));
+        };
+        self.write(QueuedWrite::UpdateTaskName { old_name, name: name.to_string() }, |sink| sink.update_task_name(task_id, name))?;
+        self.task_names.lock().unwrap().insert(task_id, name.to_string());
+        Ok(())
+    }
+
+    fn assign_entries_to_task(&self, entry_uuids: &[String], task_id: Option<i64>) -> Result<(), std::io::Error> {
+        let task = self.queued_task_name(task_id);
+        self.write(QueuedWrite::AssignEntriesToTask { entry_uuids: entry_uuids.to_vec(), task }, |sink|
+            sink.assign_entries_to_task(entry_uuids, task_id)
+        )
+    }
+
+    fn get_active_task(&self) -> Result<Option<i64>, std::io::Error> {
+        self.primary.get_active_task()
+    }
+
+    fn set_active_task(&self, task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::SetActiveTask { task: self.queued_task_name(task_id) }, |sink| sink.set_active_task(task_id))
+    }
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::SetEntryTags { uuid, tags: tags.to_vec() }, |sink| sink.set_entry_tags(uuid, tags))
+    }
+
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::SetEntryNoop { uuid, is_noop }, |sink| sink.set_entry_noop(uuid, is_noop))
+    }
+
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::SetEntryCustomFields { uuid, fields: fields.clone() }, |sink|
+            sink.set_entry_custom_fields(uuid, fields)
+        )
+    }
+
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error> {
+        self.primary.get_all_tags()
+    }
+
+    fn begin_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::BeginSession { session: session.into() }, |sink| sink.begin_session(session))
+    }
+
+    fn append_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.append(uuid, events, |uuid, events| QueuedWrite::AppendSessionEvents { uuid, events }, |sink|
+            sink.append_session_events(uuid, events)
+        )
+    }
+
+    fn redact_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.write(QueuedWrite::RedactSessionEvents { uuid, events: events.to_vec() }, |sink|
+            sink.redact_session_events(uuid, events)
+        )
+    }
+
+    fn finalize_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.unacknowledged.lock().unwrap().remove(&session.uuid);
+        self.write(QueuedWrite::FinalizeSession { session: session.into() }, |sink| sink.finalize_session(session))
+    }
+
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error> {
+        self.primary.get_sessions()
+    }
+
+    fn get_session_by_id(&self, uuid: Uuid) -> Result<Option<Session>, std::io::Error> {
+        self.primary.get_session_by_id(uuid)
+    }
+
+    fn get_session_events(&self, uuid: Uuid, from_ms: u64, to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error> {
+        self.primary.get_session_events(uuid, from_ms, to_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::model::{ CaptureEventKind, CaptureStatus };
+
+    fn capture() -> CaptureV2_4 {
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: Utc::now(),
+            duration_ms: 1000,
+            status: CaptureStatus::Completed,
+            local_user: "alice".to_string(),
+            cmd: "bash".to_string(),
+            events: vec![CaptureEvent { offset_ms: 0, kind: CaptureEventKind::Output(b"$ uptime\r\n".to_vec()) }],
+        };
+        session.slice_to_capture(session.events.clone(), 0, 1000)
+    }
+
+    // Without the delivery thread of FanoutSink::new, so the test delivers the queue itself
+    fn fanout(dir: &Path, primary: Box<dyn Sink>, spec: &str) -> FanoutSink {
+        FanoutSink {
+            primary,
+            secondaries: vec![spec.to_string()],
+            queue: Mutex::new(open_queue(&dir.join(QUEUE_FILE)).unwrap()),
+            wake: mpsc::channel().0,
+            task_names: Mutex::new(HashMap::new()),
+            unacknowledged: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn test_failing_secondary_is_retried() {
+        let dir = std::env::temp_dir().join(format!("prodlog-fanout-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let queue = open_queue(&dir.join(QUEUE_FILE)).unwrap();
+        // The secondary can't be opened until its directory exists
+        let spec = format!("sqlite:{}", dir.join("mirror").join("prodlog.sqlite").display());
+        let secondary = Secondary::parse(&spec).unwrap();
+
+        let capture = capture();
+        let queued = QueuedCapture { capture: capture.clone(), events: capture.events.clone(), task: None };
+        let request = serde_json::to_string(&QueuedWrite::AddNewEntry { capture: queued }).unwrap();
+        queue.execute(
+            "INSERT INTO queue (sink, request, queued_at, format) VALUES (?1, ?2, ?3, ?4)",
+            params![spec, request, Utc::now(), QUEUE_FORMAT]
+        ).unwrap();
+
+        let mut sinks = HashMap::new();
+        assert!(deliver_pending(&queue, &spec, &secondary, &mut sinks).is_err());
+        let pending = |queue: &Connection| {
+            queue.query_row("SELECT COUNT(*) FROM queue", [], |row| row.get::<_, i64>(0)).unwrap()
+        };
+        assert_eq!(pending(&queue), 1);
+
+        std::fs::create_dir_all(dir.join("mirror")).unwrap();
+        deliver_pending(&queue, &spec, &secondary, &mut sinks).unwrap();
+        assert_eq!(pending(&queue), 0);
+        assert!(sinks[&spec].get_entry_by_id(capture.uuid).unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tasks_are_resolved_by_name() {
+        let dir = std::env::temp_dir().join(format!("prodlog-fanout-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secondary = SqliteSink::open(&dir.join("prodlog.sqlite")).unwrap();
+        // The secondary numbers its tasks differently from the primary
+        secondary.create_task("other").unwrap();
+
+        let mut capture = capture();
+        capture.task_id = Some(1);
+        let queued = || QueuedCapture { capture: capture.clone(), events: capture.events.clone(), task: Some("deploy".to_string()) };
+        let writes = vec![
+            QueuedWrite::CreateTask { name: "deploy".to_string() },
+            QueuedWrite::CreateTask { name: "deploy".to_string() },
+            QueuedWrite::SetActiveTask { task: Some("deploy".to_string()) },
+            QueuedWrite::AddNewEntry { capture: queued() },
+            QueuedWrite::UpdateEntry { capture: queued() },
+        ];
+        for write in writes {
+            write.apply(&secondary).unwrap();
+        }
+
+        let tasks = secondary.get_all_tasks().unwrap();
+        let deploy: Vec<_> = tasks.iter().filter(|task| task.name == "deploy").collect();
+        assert_eq!((tasks.len(), deploy.len()), (2, 1));
+        assert_eq!(secondary.get_active_task().unwrap(), Some(deploy[0].id));
+        let entry = secondary.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        assert_eq!(entry.task_id, Some(deploy[0].id));
+        assert_ne!(entry.task_id, capture.task_id);
+
+        QueuedWrite::UpdateTaskName { old_name: "deploy".to_string(), name: "rollout".to_string() }.apply(&secondary).unwrap();
+        assert_eq!(secondary.get_task_by_id(deploy[0].id).unwrap().unwrap().name, "rollout");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tasks_are_named_while_the_primary_is_down() {
+        let dir = std::env::temp_dir().join(format!("prodlog-fanout-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = format!("sqlite:{}", dir.join("mirror.sqlite").display());
+        let mut fanout = fanout(&dir, Box::new(JsonlSink::open(&dir.join("audit")).unwrap()), &spec);
+        let task_id = fanout.create_task("deploy").unwrap();
+
+        // A primary that knows no tasks, and can't be written as its directory is gone
+        fanout.primary = Box::new(JsonlSink::open(&dir.join("gone")).unwrap());
+        std::fs::remove_dir_all(dir.join("gone")).unwrap();
+        let mut capture = capture();
+        capture.task_id = Some(task_id);
+        assert!(fanout.set_active_task(Some(task_id)).is_err());
+        assert!(fanout.add_new_entry(&capture).is_err());
+        assert!(fanout.update_entry(&capture).is_err());
+        let unknown = fanout.update_task_name(task_id + 1, "rollback").unwrap_err();
+        assert_eq!(unknown.kind(), std::io::ErrorKind::NotFound);
+
+        let mut sinks = HashMap::new();
+        deliver_pending(&fanout.queue.lock().unwrap(), &spec, &Secondary::parse(&spec).unwrap(), &mut sinks).unwrap();
+        let tasks = sinks[&spec].get_all_tasks().unwrap();
+        assert_eq!(tasks.iter().map(|task| task.name.as_str()).collect::<Vec<_>>(), ["deploy"]);
+        assert_eq!(sinks[&spec].get_entry_by_id(capture.uuid).unwrap().unwrap().task_id, Some(tasks[0].id));
+        drop(sinks);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retried_appends_are_queued_once() {
+        let dir = std::env::temp_dir().join(format!("prodlog-fanout-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = format!("sqlite:{}", dir.join("mirror.sqlite").display());
+        // The primary can't be written until its directory is back
+        let fanout = fanout(&dir, Box::new(JsonlSink::open(&dir.join("audit")).unwrap()), &spec);
+        std::fs::remove_dir_all(dir.join("audit")).unwrap();
+        let capture = capture();
+        let output = |data: &str| CaptureEvent { offset_ms: 10, kind: CaptureEventKind::Output(data.as_bytes().to_vec()) };
+
+        assert!(fanout.begin_entry(&capture).is_err());
+        assert!(fanout.append_events(capture.uuid, &[output("one ")]).is_err());
+        std::fs::create_dir_all(dir.join("audit")).unwrap();
+        fanout.append_events(capture.uuid, &[output("one "), output("two ")]).unwrap();
+        fanout.append_events(capture.uuid, &[output("three")]).unwrap();
+
+        let mut sinks = HashMap::new();
+        deliver_pending(&fanout.queue.lock().unwrap(), &spec, &Secondary::parse(&spec).unwrap(), &mut sinks).unwrap();
+        let events = sinks[&spec].get_entry_by_id(capture.uuid).unwrap().unwrap().events;
+        assert_eq!(events, [output("one "), output("two "), output("three")]);
+        drop(sinks);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replaced_events_are_dropped_from_the_queue() {
+        let dir = std::env::temp_dir().join(format!("prodlog-fanout-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = format!("sqlite:{}", dir.join("mirror.sqlite").display());
+        let fanout = fanout(&dir, Box::new(SqliteSink::open(&dir.join("prodlog.sqlite")).unwrap()), &spec);
+        let capture = capture();
+        let output = |data: &str| CaptureEvent { offset_ms: 10, kind: CaptureEventKind::Output(data.as_bytes().to_vec()) };
+
+        fanout.begin_entry(&capture).unwrap();
+        fanout.append_events(capture.uuid, &[output("head ")]).unwrap();
+        for tail in ["tail 1", "tail 2", "tail 3"] {
+            fanout.replace_events_after(capture.uuid, 1, &[output(tail)]).unwrap();
+        }
+        let queued: i64 = fanout.queue
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM queue WHERE request LIKE '%replace_events_after%'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(queued, 1);
+
+        let mut sinks = HashMap::new();
+        deliver_pending(&fanout.queue.lock().unwrap(), &spec, &Secondary::parse(&spec).unwrap(), &mut sinks).unwrap();
+        let events = [output("head "), output("tail 3")];
+        assert_eq!(sinks[&spec].get_entry_by_id(capture.uuid).unwrap().unwrap().events, events);
+        assert_eq!(fanout.get_entry_by_id(capture.uuid).unwrap().unwrap().events, events);
+        drop(sinks);
+        drop(fanout);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
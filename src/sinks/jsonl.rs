@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::fs::{ File, OpenOptions };
+use std::io::Write;
+use std::os::unix::fs::{ DirBuilderExt, OpenOptionsExt };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+
+use chrono::{ NaiveDate, Utc };
+use serde_json::{ json, Value };
+use uuid::Uuid;
+
+use crate::model::{ CaptureEvent, CaptureEventKind, CaptureV2_4, Session, Task };
+use super::{ Filters, Sink };
+
+// The tasks of the log, which numbers them itself as it has no database to do so.
+const TASKS_FILE: &str = "tasks.json";
+
+/// An append-only audit log of entries and task changes, as JSON Lines in a file per
+/// day (UTC), e.g. audit-2025-06-01.jsonl. Every line is synced to disk before the write
+/// counts as done, so the log holds everything that was recorded even when the
+/// database is lost or edited. Only its tasks can be read back by prodlog, so it's only
+/// useful as a secondary sink. The files are only readable by their owner, as they hold
+/// everything the terminal showed.
+pub struct JsonlSink {
+    dir: PathBuf,
+    // The file of the day that was last written to
+    file: Mutex<Option<(NaiveDate, File)>>,
+    tasks: Mutex<Vec<Task>>,
+}
+
+// The entry as JSON, with its output and file contents as text rather than base64, so
+// the log can be searched with grep.
+fn audit_entry(capture: &CaptureV2_4) -> Value {
+    let output = if capture.events.is_empty() { capture.captured_output.clone() } else { capture.output_from_events() };
+    let mut entry = serde_json::to_value(capture).unwrap_or(Value::Null);
+    entry["captured_output"] = String::from_utf8_lossy(&output).into();
+    entry["original_content"] = String::from_utf8_lossy(&capture.original_content).into();
+    entry["edited_content"] = String::from_utf8_lossy(&capture.edited_content).into();
+    entry["additional_files"] = capture.additional_files
+        .iter()
+        .map(|file| json!({
+            "filename": file.filename,
+            "original_content": String::from_utf8_lossy(&file.original_content),
+            "edited_content": String::from_utf8_lossy(&file.edited_content),
+        }))
+        .collect();
+    // What was typed, which isn't part of the output, and only that something was
+    // typed with echo off
+    if !capture.events.is_empty() {
+        entry["input"] = capture.events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                CaptureEventKind::Input(data) => Some(json!({ "offset_ms": event.offset_ms, "input": String::from_utf8_lossy(data) })),
+                CaptureEventKind::MaskedInput => Some(json!({ "offset_ms": event.offset_ms, "masked": true })),
+                _ => None,
+            })
+            .collect();
+    }
+    entry
+}
+
+// The file with the output that's held back from a running capture.
+fn held_file(uuid: Uuid) -> String {
+    format!("held-{}.json", uuid)
+}
+
+fn not_readable<T>() -> Result<T, std::io::Error> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "The audit log can't be read by prodlog"))
+}
+
+impl JsonlSink {
+    pub fn open(dir: &Path) -> Result<Self, std::io::Error> {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let tasks = match std::fs::read(dir.join(TASKS_FILE)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(JsonlSink { dir: dir.to_path_buf(), file: Mutex::new(None), tasks: Mutex::new(tasks) })
+    }
+
+    fn save_tasks(&self, tasks: &[Task]) -> Result<(), std::io::Error> {
+        self.replace_file(TASKS_FILE, &serde_json::to_vec(tasks)?)
+    }
+
+    // Replaces a file as a whole, so a crash leaves either the old or the new one.
+    fn replace_file(&self, name: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let new_file = self.dir.join(format!("{}.new", name));
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(0o600).open(&new_file)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&new_file, self.dir.join(name))?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    fn task_name(&self, task_id: Option<i64>) -> Option<String> {
+        let tasks = self.tasks.lock().unwrap();
+        task_id.and_then(|id| tasks.iter().find(|task| task.id == id).map(|task| task.name.clone()))
+    }
+
+    // Logs consecutive output or input of a capture as one line.
+    fn log_text(&self, uuid: Uuid, text: Option<(&str, Vec<u8>)>) -> Result<(), std::io::Error> {
+        match text {
+            Some((field, data)) =>
+                self.log(&format!("capture_{}", field), json!({ "uuid": uuid, field: String::from_utf8_lossy(&data) })),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends a line with the time, `event` and the fields of `fields`.
+    fn log(&self, event: &str, fields: Value) -> Result<(), std::io::Error> {
+        let now = Utc::now();
+        let mut line = serde_json::Map::new();
+        line.insert("time".to_string(), json!(now));
+        line.insert("event".to_string(), json!(event));
+        if let Value::Object(fields) = fields {
+            line.extend(fields);
+        }
+        let mut line = serde_json::to_string(&line)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        let today = now.date_naive();
+        if file.as_ref().map(|(date, _)| *date) != Some(today) {
+            let path = self.dir.join(format!("audit-{}.jsonl", today.format("%Y-%m-%d")));
+            let new_file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+            // So a crash can't lose the new file itself
+            File::open(&self.dir)?.sync_all()?;
+            *file = Some((today, new_file));
+        }
+        let (_, file) = file.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+impl Sink for JsonlSink {
+    fn add_new_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.log("entry_added", json!({ "entry": audit_entry(capture) }))
+    }
+
+    fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.log("entry_updated", json!({ "entry": audit_entry(capture) }))
+    }
+
+    // Lines written before the redaction still hold the password.
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.log("entry_redacted", json!({ "entry": audit_entry(capture) }))
+    }
+
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.log("capture_started", json!({ "entry": audit_entry(capture) }))
+    }
+
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        let mut text: Option<(&str, Vec<u8>)> = None;
+        for event in events {
+            let field = match &event.kind {
+                CaptureEventKind::Output(_) => "output",
+                CaptureEventKind::Input(_) => "input",
+                _ => "",
+            };
+            if text.as_ref().is_some_and(|(text_field, _)| *text_field != field) {
+                self.log_text(uuid, text.take())?;
+            }
+            match &event.kind {
+                CaptureEventKind::Output(data) | CaptureEventKind::Input(data) =>
+                    text.get_or_insert_with(|| (field, Vec::new())).1.extend_from_slice(data),
+                // Like in the database, only the fact that something was typed
+                CaptureEventKind::MaskedInput => self.log("capture_input", json!({ "uuid": uuid, "masked": true }))?,
+                CaptureEventKind::Resize { .. } => (),
+            }
+        }
+        self.log_text(uuid, text)
+    }
+
+    // The output held back by --output-tail changes until the capture ends, so rather
+    // than logging every version of it, the last one is kept in a file of its own and
+    // logged when the capture ends. After a crash, it's still in that file.
+    fn replace_events_after(&self, uuid: Uuid, _kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.replace_file(&held_file(uuid), &serde_json::to_vec(events)?)
+    }
+
+    // The output was logged as it arrived, so only the rest of the entry is logged here.
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let held_path = self.dir.join(held_file(capture.uuid));
+        match std::fs::read(&held_path) {
+            Ok(json) => {
+                self.append_events(capture.uuid, &serde_json::from_slice::<Vec<CaptureEvent>>(&json)?)?;
+                std::fs::remove_file(&held_path)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        self.log("capture_finished", json!({ "entry": audit_entry(capture) }))
+    }
+
+    fn get_entries(&self, _filters: &Filters) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        not_readable()
+    }
+
+    fn get_entry_by_id(&self, _uuid: Uuid) -> Result<Option<CaptureV2_4>, std::io::Error> {
+        not_readable()
+    }
+
+    fn get_child_entries(&self, _parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        not_readable()
+    }
+
+    fn get_content_blob(&self, _hash: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        not_readable()
+    }
+
+    fn create_task(&self, name: &str) -> Result<i64, std::io::Error> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let id = tasks.iter().map(|task| task.id).max().unwrap_or(0) + 1;
+        let mut new_tasks = tasks.clone();
+        new_tasks.push(Task { id, name: name.to_string(), created_at: Utc::now() });
+        self.save_tasks(&new_tasks)?;
+        *tasks = new_tasks;
+        drop(tasks);
+        self.log("task_created", json!({ "task_id": id, "name": name }))?;
+        Ok(id)
+    }
+
+    fn get_all_tasks(&self) -> Result<Vec<Task>, std::io::Error> {
+        Ok(self.tasks.lock().unwrap().clone())
+    }
+
+    fn get_task_by_id(&self, id: i64) -> Result<Option<Task>, std::io::Error> {
+        Ok(self.tasks.lock().unwrap().iter().find(|task| task.id == id).cloned())
+    }
+
+    fn update_task_name(&self, task_id: i64, name: &str) -> Result<(), std::io::Error> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let mut new_tasks = tasks.clone();
+        for task in new_tasks.iter_mut().filter(|task| task.id == task_id) {
+            task.name = name.to_string();
+        }
+        self.save_tasks(&new_tasks)?;
+        *tasks = new_tasks;
+        drop(tasks);
+        self.log("task_renamed", json!({ "task_id": task_id, "name": name }))
+    }
+
+    fn assign_entries_to_task(&self, entry_uuids: &[String], task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.log(
+            "entries_assigned_to_task",
+            json!({ "entry_uuids": entry_uuids, "task_id": task_id, "task_name": self.task_name(task_id) })
+        )
+    }
+
+    fn get_active_task(&self) -> Result<Option<i64>, std::io::Error> {
+        not_readable()
+    }
+
+    fn set_active_task(&self, task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.log("active_task_set", json!({ "task_id": task_id, "task_name": self.task_name(task_id) }))
+    }
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error> {
+        self.log("entry_tags_set", json!({ "uuid": uuid, "tags": tags }))
+    }
+
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error> {
+        self.log("entry_noop_set", json!({ "uuid": uuid, "is_noop": is_noop }))
+    }
+
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        self.log("entry_custom_fields_set", json!({ "uuid": uuid, "custom_fields": fields }))
+    }
+
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error> {
+        not_readable()
+    }
+
+    // Session recordings aren't entries, output only becomes part of the log once it's
+    // promoted to one.
+    fn begin_session(&self, _session: &Session) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn append_session_events(&self, _uuid: Uuid, _events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn finalize_session(&self, _session: &Session) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn redact_session_events(&self, _uuid: Uuid, _events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error> {
+        not_readable()
+    }
+
+    fn get_session_by_id(&self, _uuid: Uuid) -> Result<Option<Session>, std::io::Error> {
+        not_readable()
+    }
+
+    fn get_session_events(&self, _uuid: Uuid, _from_ms: u64, _to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error> {
+        not_readable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::model::CaptureStatus;
+
+    #[test]
+    fn test_audit_lines() {
+        let dir = std::env::temp_dir().join(format!("prodlog-audit-{}", Uuid::new_v4()));
+        let sink = JsonlSink::open(&dir).unwrap();
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: Utc::now(),
+            duration_ms: 1000,
+            status: CaptureStatus::Completed,
+            local_user: "alice".to_string(),
+            cmd: "bash".to_string(),
+            events: Vec::new(),
+        };
+        let mut capture = session.slice_to_capture(Vec::new(), 0, 1000);
+        let task_id = sink.create_task("deploy").unwrap();
+        sink.set_active_task(Some(task_id)).unwrap();
+        sink.begin_entry(&capture).unwrap();
+        let event = |offset_ms, kind| CaptureEvent { offset_ms, kind };
+        sink.append_events(capture.uuid, &[
+            event(10, CaptureEventKind::Output(b"password: hunter2\r\n".to_vec())),
+            event(20, CaptureEventKind::Output(b"sudo password: ".to_vec())),
+            event(30, CaptureEventKind::MaskedInput),
+            event(40, CaptureEventKind::Input(b"l".to_vec())),
+            event(50, CaptureEventKind::Input(b"s\r".to_vec())),
+        ]).unwrap();
+        capture.captured_output = b"password: [REDACTED]\r\n".to_vec();
+        sink.redact_entry(&capture).unwrap();
+
+        let file = dir.join(format!("audit-{}.jsonl", Utc::now().format("%Y-%m-%d")));
+        let lines: Vec<Value> = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let events: Vec<&str> = lines.iter().map(|line| line["event"].as_str().unwrap()).collect();
+        assert_eq!(
+            events,
+            ["task_created", "active_task_set", "capture_started", "capture_output", "capture_input", "capture_input", "entry_redacted"]
+        );
+        assert_eq!((lines[0]["task_id"].clone(), lines[1]["task_name"].clone()), (json!(1), json!("deploy")));
+        assert_eq!(lines[3]["output"], "password: hunter2\r\nsudo password: ");
+        assert_eq!((lines[4]["masked"].clone(), lines[5]["input"].clone()), (json!(true), json!("ls\r")));
+        assert_eq!(lines[6]["entry"]["captured_output"], "password: [REDACTED]\r\n");
+        assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Task ids are kept when the log is opened again
+        let sink = JsonlSink::open(&dir).unwrap();
+        assert_eq!(sink.create_task("rollback").unwrap(), 2);
+        assert_eq!(sink.get_task_by_id(1).unwrap().unwrap().name, "deploy");
+
+        // Only the last version of the output held back is logged, when the capture ends
+        sink.replace_events_after(capture.uuid, 5, &[event(60, CaptureEventKind::Output(b"tail 1\r\n".to_vec()))]).unwrap();
+        sink.replace_events_after(capture.uuid, 5, &[event(70, CaptureEventKind::Output(b"tail 2\r\n".to_vec()))]).unwrap();
+        sink.finalize_entry(&capture).unwrap();
+        let lines: Vec<Value> = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[lines.len() - 2]["output"], "tail 2\r\n");
+        assert_eq!(lines[lines.len() - 1]["event"], "capture_finished");
+        assert!(!dir.join(held_file(capture.uuid)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
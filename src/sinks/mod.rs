@@ -1,11 +1,17 @@
-use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use serde::{ Deserialize, Serialize };
 use uuid::Uuid;
-use crate::model::CaptureV2_4;
+use crate::model::{ CaptureEvent, CaptureV2_4, Session };
 use crate::model::Task;
 
+pub mod fanout;
+pub mod jsonl;
+pub mod postgres;
+pub mod remote;
 pub mod sqlite;
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Filters {
     pub date_from: Option<String>,
     pub date_to: Option<String>,
@@ -13,14 +19,43 @@ pub struct Filters {
     pub search: Option<String>,
     pub search_content: Option<String>,
     pub show_noop: Option<bool>,
+    // "completed", "interrupted" or "in_progress"
+    pub status: Option<String>,
+    pub tag: Option<String>,
+    // "name=value" for entries with that custom field value, or just "name" for
+    // entries that have the custom field at all
+    pub field: Option<String>,
+    pub terminal: Option<String>,
 }
 
 pub trait Sink: Send + Sync {
     fn add_new_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error>;
 
+    // Saves what can be edited in the UI: the message, whether it's a no-op, the tags and
+    // the custom fields. The rest is left as it is, as the capture may still be running.
     fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error>;
+    // Like update_entry, for an entry that passwords were removed from, so the audit
+    // log can tell redactions apart from other changes.
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error>;
+
+    // Captures are written while they run, so a crash doesn't lose their output:
+    // begin_entry stores the entry when the capture starts, append_events adds
+    // output as it arrives and finalize_entry records how the capture ended.
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error>;
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error>;
+    // Replaces the events of an entry after the first `kept` with `events`. The output
+    // that --output-tail holds back is stored like this while the capture runs, and
+    // replaced by the final tail when it ends.
+    fn replace_events_after(&self, uuid: Uuid, kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error>;
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error>;
+
+    // Entries in lists come without their events, and so without the output of
+    // captures that were recorded as they ran. get_entry_by_id loads everything.
     fn get_entries(&self, filters: &Filters) -> Result<Vec<CaptureV2_4>, std::io::Error>;
     fn get_entry_by_id(&self, uuid: Uuid) -> Result<Option<CaptureV2_4>, std::io::Error>;
+    fn get_child_entries(&self, parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error>;
+    /// File contents by their SHA-256, as in the original_hash and edited_hash of entries.
+    fn get_content_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, std::io::Error>;
     fn create_task(&self, name: &str) -> Result<i64, std::io::Error>;
     fn get_all_tasks(&self) -> Result<Vec<Task>, std::io::Error>;
     fn get_task_by_id(&self, id: i64) -> Result<Option<Task>, std::io::Error>;
@@ -28,4 +63,51 @@ pub trait Sink: Send + Sync {
     fn assign_entries_to_task(&self, entry_uuids: &[String], task_id: Option<i64>) -> Result<(), std::io::Error>;
     fn get_active_task(&self) -> Result<Option<i64>, std::io::Error>;
     fn set_active_task(&self, task_id: Option<i64>) -> Result<(), std::io::Error>;
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error>;
+    // Marks an entry as no-op or not, without rewriting the rest of it.
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error>;
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error>;
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error>;
+
+    // Whole-session recordings, see --record-session. Sessions are written like
+    // captures: begun at the start, then events are appended as they arrive.
+    fn begin_session(&self, session: &Session) -> Result<(), std::io::Error>;
+    fn append_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error>;
+    fn finalize_session(&self, session: &Session) -> Result<(), std::io::Error>;
+    // Replaces all events of a session with `events`, that passwords were removed from.
+    fn redact_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error>;
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error>;
+    fn get_session_by_id(&self, uuid: Uuid) -> Result<Option<Session>, std::io::Error>;
+    // The events from `from_ms` to `to_ms`, preceded by the last resize before
+    // `from_ms`, so the output can be shown at the right terminal size.
+    fn get_session_events(&self, uuid: Uuid, from_ms: u64, to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error>;
+}
+
+/// Whether `text` occurs in what an entry ran, printed or edited, ignoring case like
+/// LIKE does. For the content search, which can't be done in SQL on compressed blobs.
+/// The output of entries listed without their events is the bulk of what's searched,
+/// so it's only loaded with `load_output` when nothing else matches.
+pub fn entry_contains<E>(
+    entry: &CaptureV2_4,
+    text: &str,
+    load_output: impl FnOnce() -> Result<Vec<u8>, E>
+) -> Result<bool, E> {
+    let text = text.as_bytes();
+    let contains = |haystack: &[u8]| {
+        text.is_empty() || haystack.windows(text.len()).any(|window| window.eq_ignore_ascii_case(text))
+    };
+    let found = contains(entry.cmd.as_bytes()) ||
+        contains(entry.message.as_bytes()) ||
+        contains(&entry.captured_output) ||
+        contains(&entry.original_content) ||
+        contains(&entry.edited_content) ||
+        entry.additional_files
+            .iter()
+            .any(|file| contains(file.filename.as_bytes()) || contains(&file.original_content) || contains(&file.edited_content)) ||
+        entry.facts.values().any(|value| contains(value.as_bytes()));
+    if found || !entry.events.is_empty() {
+        return Ok(found);
+    }
+    Ok(contains(&load_output()?))
 }
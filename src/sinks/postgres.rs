@@ -0,0 +1,1133 @@
+use chrono::{ DateTime, Duration, Utc };
+use postgres::types::ToSql;
+use postgres::{ GenericClient, Row };
+use postgres_native_tls::MakeTlsConnector;
+use r2d2_postgres::PostgresConnectionManager;
+use std::collections::{ BTreeMap, HashMap };
+use uuid::Uuid;
+use crate::{ helpers::sha256_hex, model::*, print_prodlog_message, prodlog_panic };
+use super::{ entry_contains, Filters, Sink };
+
+/// Stores entries in a PostgreSQL database, which can be shared by a whole team.
+pub struct PostgresSink {
+    // Only None while dropping, see with_client
+    pool: Option<r2d2::Pool<PostgresConnectionManager<MakeTlsConnector>>>,
+}
+
+// The postgres schema is versioned on its own, as it only needs migrating when it
+// changes itself. Changes bump this and add a migration from the previous version to
+// migrate().
+const SCHEMA_VERSION: i32 = 1;
+
+// Held while migrating, as several prodlog_record instances may start at once.
+const MIGRATION_LOCK: i64 = 0x70726f646c6f67;
+
+// Like the sqlite schema, except that file contents are in content_blobs from the
+// start. Blobs aren't compressed here, postgres already compresses large values.
+const SCHEMA: &str = "
+    CREATE TABLE prodlog_entries (
+        uuid UUID PRIMARY KEY,
+        capture_type TEXT NOT NULL,
+        host TEXT NOT NULL,
+        cwd TEXT NOT NULL,
+        cmd TEXT NOT NULL,
+        start_time TIMESTAMPTZ NOT NULL,
+        end_time TIMESTAMPTZ NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        message TEXT NOT NULL,
+        is_noop BOOLEAN NOT NULL,
+        exit_code INTEGER NOT NULL,
+        local_user TEXT NOT NULL,
+        remote_user TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        terminal_rows INTEGER NOT NULL,
+        terminal_cols INTEGER NOT NULL,
+        task_id BIGINT,
+        output BYTEA NOT NULL,
+        original_hash TEXT,
+        edited_hash TEXT,
+        parent_uuid UUID,
+        status TEXT NOT NULL DEFAULT 'completed',
+        content_error TEXT,
+        terminal TEXT NOT NULL DEFAULT ''
+    );
+    CREATE INDEX prodlog_entries_start_time ON prodlog_entries (start_time);
+    CREATE INDEX prodlog_entries_parent_uuid ON prodlog_entries (parent_uuid);
+    CREATE INDEX prodlog_entries_original_hash ON prodlog_entries (original_hash);
+    CREATE INDEX prodlog_entries_edited_hash ON prodlog_entries (edited_hash);
+    CREATE TABLE tasks (
+        id BIGSERIAL PRIMARY KEY,
+        name TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL
+    );
+    CREATE TABLE active_task (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        task_id BIGINT
+    );
+    INSERT INTO active_task (id, task_id) VALUES (1, NULL);
+    CREATE TABLE capture_events (
+        entry_uuid UUID NOT NULL,
+        seq BIGINT NOT NULL,
+        offset_ms BIGINT NOT NULL,
+        event_type TEXT NOT NULL,
+        data BYTEA,
+        PRIMARY KEY (entry_uuid, seq)
+    );
+    CREATE TABLE edited_files (
+        entry_uuid UUID NOT NULL,
+        seq INTEGER NOT NULL,
+        filename TEXT NOT NULL,
+        original_hash TEXT,
+        edited_hash TEXT,
+        PRIMARY KEY (entry_uuid, seq)
+    );
+    CREATE INDEX edited_files_original_hash ON edited_files (original_hash);
+    CREATE INDEX edited_files_edited_hash ON edited_files (edited_hash);
+    CREATE TABLE content_blobs (
+        hash TEXT PRIMARY KEY,
+        data BYTEA NOT NULL
+    );
+    CREATE TABLE entry_facts (
+        entry_uuid UUID NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (entry_uuid, key)
+    );
+    CREATE TABLE entry_tags (
+        entry_uuid UUID NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (entry_uuid, tag)
+    );
+    CREATE INDEX entry_tags_tag ON entry_tags (tag);
+    CREATE TABLE entry_custom_fields (
+        entry_uuid UUID NOT NULL,
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (entry_uuid, name)
+    );
+    CREATE TABLE sessions (
+        uuid UUID PRIMARY KEY,
+        start_time TIMESTAMPTZ NOT NULL,
+        duration_ms BIGINT NOT NULL,
+        status TEXT NOT NULL,
+        local_user TEXT NOT NULL,
+        cmd TEXT NOT NULL
+    );
+    CREATE TABLE session_events (
+        session_uuid UUID NOT NULL,
+        seq BIGINT NOT NULL,
+        offset_ms BIGINT NOT NULL,
+        event_type TEXT NOT NULL,
+        data BYTEA,
+        PRIMARY KEY (session_uuid, seq)
+    );
+    CREATE INDEX session_events_offset ON session_events (session_uuid, offset_ms);
+";
+
+// Entries with the file contents they refer to, for from_row_entry.
+const SELECT_ENTRIES: &str =
+    "SELECT prodlog_entries.*, original_blob.data AS original_blob, edited_blob.data AS edited_blob FROM prodlog_entries
+     LEFT JOIN content_blobs AS original_blob ON original_blob.hash = prodlog_entries.original_hash
+     LEFT JOIN content_blobs AS edited_blob ON edited_blob.hash = prodlog_entries.edited_hash";
+
+fn migrate(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    // Postgres changes the schema in a transaction, so unlike sqlite a failed
+    // migration doesn't leave it half done.
+    let mut tx = client.transaction()?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )"
+    )?;
+    let version: Option<i32> = tx.query_one("SELECT MAX(version) FROM schema_migrations", &[])?.try_get(0)?;
+    match version {
+        None => {
+            tx.batch_execute(SCHEMA)?;
+            tx.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[&SCHEMA_VERSION])?;
+        }
+        Some(SCHEMA_VERSION) => {
+            print_prodlog_message("Database is up to date.");
+        }
+        // There's only the one version so far, so any other was created by a newer
+        // prodlog. Older versions get an arm that migrates them once the schema changes.
+        Some(version) => {
+            prodlog_panic(
+                &format!("Database schema version {} is not supported. Please upgrade Prodlog.", version)
+            );
+        }
+    }
+    tx.commit()
+}
+
+// Instances sharing the database could otherwise delete contents in prune_contents
+// that another one is just starting to refer to.
+fn lock_content(client: &mut impl GenericClient, hash: &str) -> Result<(), postgres::Error> {
+    let key = u64::from_str_radix(&hash[..16.min(hash.len())], 16).unwrap_or_default() as i64;
+    client.execute("SELECT pg_advisory_xact_lock($1)", &[&key])?;
+    Ok(())
+}
+
+/// Stores `data` in content_blobs if it isn't there yet, and returns its hash. Empty
+/// contents aren't stored.
+fn store_content(client: &mut impl GenericClient, data: &[u8]) -> Result<Option<String>, postgres::Error> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let hash = sha256_hex(data);
+    lock_content(client, &hash)?;
+    // Don't send the contents again if they're there
+    if client.query_opt("SELECT 1 FROM content_blobs WHERE hash = $1", &[&hash])?.is_none() {
+        client.execute(
+            "INSERT INTO content_blobs (hash, data) VALUES ($1, $2) ON CONFLICT (hash) DO NOTHING",
+            &[&hash, &data]
+        )?;
+    }
+    Ok(Some(hash))
+}
+
+// The hashes of the contents referred to by the rows of `table` for an entry.
+fn content_hashes(
+    client: &mut impl GenericClient,
+    table: &str,
+    uuid_column: &str,
+    uuid: Uuid
+) -> Result<Vec<String>, postgres::Error> {
+    let rows = client.query(
+        &format!("SELECT original_hash, edited_hash FROM {} WHERE {} = $1", table, uuid_column),
+        &[&uuid]
+    )?;
+    let mut hashes = Vec::new();
+    for row in rows {
+        hashes.extend(row.try_get::<_, Option<String>>(0)?);
+        hashes.extend(row.try_get::<_, Option<String>>(1)?);
+    }
+    Ok(hashes)
+}
+
+// Deletes the contents no entry refers to anymore, so e.g. a redacted password doesn't
+// stay behind in the contents from before redacting it.
+fn prune_contents(client: &mut impl GenericClient, hashes: &[String]) -> Result<(), postgres::Error> {
+    for hash in hashes {
+        lock_content(client, hash)?;
+        client.execute(
+            "DELETE FROM content_blobs WHERE hash = $1
+             AND NOT EXISTS (SELECT 1 FROM prodlog_entries WHERE original_hash = $1 OR edited_hash = $1)
+             AND NOT EXISTS (SELECT 1 FROM edited_files WHERE original_hash = $1 OR edited_hash = $1)",
+            &[hash]
+        )?;
+    }
+    Ok(())
+}
+
+fn write_entry(
+    client: &mut impl GenericClient,
+    capture: &CaptureV2_4,
+    task_id: Option<i64>,
+    output: &[u8]
+) -> Result<(), postgres::Error> {
+    let end_time = capture.start_time + Duration::milliseconds(capture.duration_ms as i64);
+    let old_hashes = content_hashes(client, "prodlog_entries", "uuid", capture.uuid)?;
+    let original_hash = store_content(client, &capture.original_content)?;
+    let edited_hash = store_content(client, &capture.edited_content)?;
+    client.execute("DELETE FROM prodlog_entries WHERE uuid = $1", &[&capture.uuid])?;
+    client.execute(
+        "INSERT INTO prodlog_entries (capture_type, uuid, host, cwd, cmd, start_time, end_time, duration_ms, message, is_noop, exit_code, local_user, remote_user, filename, terminal_rows, terminal_cols, task_id, output, original_hash, edited_hash, parent_uuid, status, content_error, terminal)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24)",
+        &[
+            &match capture.capture_type {
+                CaptureType::Run => "run",
+                CaptureType::Edit => "edit",
+                CaptureType::Note => "note",
+            },
+            &capture.uuid,
+            &capture.host,
+            &capture.cwd,
+            &capture.cmd,
+            &capture.start_time,
+            &end_time,
+            &(capture.duration_ms as i64),
+            &capture.message,
+            &capture.is_noop,
+            &capture.exit_code,
+            &capture.local_user,
+            &capture.remote_user,
+            &capture.filename,
+            &(capture.terminal_rows as i32),
+            &(capture.terminal_cols as i32),
+            &task_id,
+            &output,
+            &original_hash,
+            &edited_hash,
+            &capture.parent_uuid,
+            &capture.status.as_str(),
+            &capture.content_error,
+            &capture.terminal,
+        ]
+    )?;
+    prune_contents(client, &old_hashes)
+}
+
+fn write_events(client: &mut impl GenericClient, capture: &CaptureV2_4) -> Result<(), postgres::Error> {
+    client.execute("DELETE FROM capture_events WHERE entry_uuid = $1", &[&capture.uuid])?;
+    append_events_to(client, "capture_events", "entry_uuid", capture.uuid, &capture.events)
+}
+
+fn write_additional_files(client: &mut impl GenericClient, capture: &CaptureV2_4) -> Result<(), postgres::Error> {
+    let old_hashes = content_hashes(client, "edited_files", "entry_uuid", capture.uuid)?;
+    client.execute("DELETE FROM edited_files WHERE entry_uuid = $1", &[&capture.uuid])?;
+    for (seq, file) in capture.additional_files.iter().enumerate() {
+        let original_hash = store_content(client, &file.original_content)?;
+        let edited_hash = store_content(client, &file.edited_content)?;
+        client.execute(
+            "INSERT INTO edited_files (entry_uuid, seq, filename, original_hash, edited_hash) VALUES ($1, $2, $3, $4, $5)",
+            &[&capture.uuid, &(seq as i32), &file.filename, &original_hash, &edited_hash]
+        )?;
+    }
+    prune_contents(client, &old_hashes)
+}
+
+fn write_facts(client: &mut impl GenericClient, capture: &CaptureV2_4) -> Result<(), postgres::Error> {
+    client.execute("DELETE FROM entry_facts WHERE entry_uuid = $1", &[&capture.uuid])?;
+    for (key, value) in capture.facts.iter() {
+        client.execute(
+            "INSERT INTO entry_facts (entry_uuid, key, value) VALUES ($1, $2, $3)",
+            &[&capture.uuid, key, value]
+        )?;
+    }
+    Ok(())
+}
+
+fn write_tags(client: &mut impl GenericClient, uuid: Uuid, tags: &[String]) -> Result<(), postgres::Error> {
+    client.execute("DELETE FROM entry_tags WHERE entry_uuid = $1", &[&uuid])?;
+    for tag in tags {
+        client.execute(
+            "INSERT INTO entry_tags (entry_uuid, tag) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&uuid, tag]
+        )?;
+    }
+    Ok(())
+}
+
+fn write_custom_fields(
+    client: &mut impl GenericClient,
+    uuid: Uuid,
+    fields: &BTreeMap<String, String>
+) -> Result<(), postgres::Error> {
+    client.execute("DELETE FROM entry_custom_fields WHERE entry_uuid = $1", &[&uuid])?;
+    for (name, value) in fields.iter() {
+        client.execute(
+            "INSERT INTO entry_custom_fields (entry_uuid, name, value) VALUES ($1, $2, $3)",
+            &[&uuid, name, value]
+        )?;
+    }
+    Ok(())
+}
+
+// Captures and sessions store their events the same way, in their own table.
+fn append_events_to(
+    client: &mut impl GenericClient,
+    table: &str,
+    uuid_column: &str,
+    uuid: Uuid,
+    events: &[CaptureEvent]
+) -> Result<(), postgres::Error> {
+    let next_seq: i64 = client
+        .query_one(&format!("SELECT COALESCE(MAX(seq) + 1, 0) FROM {} WHERE {} = $1", table, uuid_column), &[&uuid])?
+        .try_get(0)?;
+    let stmt = client.prepare(
+        &format!("INSERT INTO {} ({}, seq, offset_ms, event_type, data) VALUES ($1, $2, $3, $4, $5)", table, uuid_column)
+    )?;
+    for (seq, event) in (next_seq..).zip(events.iter()) {
+        // Event types follow the asciicast v2 codes
+        let (event_type, data) = match &event.kind {
+            CaptureEventKind::Output(data) => ("o", Some(data.clone())),
+            CaptureEventKind::Input(data) => ("i", Some(data.clone())),
+            // Masked input has no data, what was typed is never stored
+            CaptureEventKind::MaskedInput => ("i", None),
+            CaptureEventKind::Resize { cols, rows } => ("r", Some(format!("{}x{}", cols, rows).into_bytes())),
+        };
+        client.execute(&stmt, &[&uuid, &seq, &(event.offset_ms as i64), &event_type, &data])?;
+    }
+    Ok(())
+}
+
+fn from_row_event(row: &Row) -> Result<Option<CaptureEvent>, postgres::Error> {
+    let offset_ms: i64 = row.try_get("offset_ms")?;
+    let event_type: String = row.try_get("event_type")?;
+    let kind = match (event_type.as_str(), row.try_get::<_, Option<Vec<u8>>>("data")?) {
+        ("o", data) => CaptureEventKind::Output(data.unwrap_or_default()),
+        ("i", Some(data)) => CaptureEventKind::Input(data),
+        ("i", None) => CaptureEventKind::MaskedInput,
+        ("r", data) => {
+            let size = String::from_utf8_lossy(&data.unwrap_or_default()).to_string();
+            match size.split_once('x').map(|(c, r)| (c.parse::<u16>(), r.parse::<u16>())) {
+                Some((Ok(cols), Ok(rows))) => CaptureEventKind::Resize { cols, rows },
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(CaptureEvent { offset_ms: offset_ms as u64, kind }))
+}
+
+fn from_row_entry(row: &Row) -> Result<CaptureV2_4, postgres::Error> {
+    let capture_type: String = row.try_get("capture_type")?;
+    Ok(CaptureV2_4 {
+        capture_type: match capture_type.as_str() {
+            "run" => CaptureType::Run,
+            "note" => CaptureType::Note,
+            _ => CaptureType::Edit,
+        },
+        uuid: row.try_get("uuid")?,
+        host: row.try_get("host")?,
+        cwd: row.try_get("cwd")?,
+        cmd: row.try_get("cmd")?,
+        start_time: row.try_get("start_time")?,
+        duration_ms: row.try_get::<_, i64>("duration_ms")? as u64,
+        message: row.try_get("message")?,
+        is_noop: row.try_get("is_noop")?,
+        exit_code: row.try_get("exit_code")?,
+        status: CaptureStatus::parse(&row.try_get::<_, String>("status")?),
+        local_user: row.try_get("local_user")?,
+        remote_user: row.try_get("remote_user")?,
+        filename: row.try_get("filename")?,
+        terminal_rows: row.try_get::<_, i32>("terminal_rows")? as u16,
+        terminal_cols: row.try_get::<_, i32>("terminal_cols")? as u16,
+        task_id: row.try_get("task_id")?,
+        parent_uuid: row.try_get("parent_uuid")?,
+        captured_output: row.try_get("output")?,
+        original_content: row.try_get::<_, Option<Vec<u8>>>("original_blob")?.unwrap_or_default(),
+        edited_content: row.try_get::<_, Option<Vec<u8>>>("edited_blob")?.unwrap_or_default(),
+        original_hash: row.try_get("original_hash")?,
+        edited_hash: row.try_get("edited_hash")?,
+        facts: BTreeMap::new(),
+        tags: Vec::new(),
+        custom_fields: BTreeMap::new(),
+        additional_files: Vec::new(),
+        content_error: row.try_get("content_error")?,
+        terminal: row.try_get("terminal")?,
+        events: Vec::new(),
+    })
+}
+
+// Loads the events, files, facts, tags and custom fields of entries, with a query for
+// each rather than for each entry, as the database is usually not on this machine.
+// Events are only loaded when asked for, as they hold all output of a capture.
+fn load_details(client: &mut impl GenericClient, entries: &mut [CaptureV2_4], with_events: bool) -> Result<(), postgres::Error> {
+    let uuids: Vec<Uuid> = entries.iter().map(|entry| entry.uuid).collect();
+    let index: HashMap<Uuid, usize> = uuids.iter().enumerate().map(|(i, uuid)| (*uuid, i)).collect();
+    let entry_of = |row: &Row| -> Result<usize, postgres::Error> { Ok(index[&row.try_get::<_, Uuid>("entry_uuid")?]) };
+
+    if with_events {
+        for row in client.query(
+            "SELECT entry_uuid, offset_ms, event_type, data FROM capture_events WHERE entry_uuid = ANY($1) ORDER BY entry_uuid, seq",
+            &[&uuids]
+        )? {
+            if let Some(event) = from_row_event(&row)? {
+                entries[entry_of(&row)?].events.push(event);
+            }
+        }
+    }
+    for row in client.query(
+        "SELECT edited_files.*, original_blob.data AS original_blob, edited_blob.data AS edited_blob FROM edited_files
+         LEFT JOIN content_blobs AS original_blob ON original_blob.hash = edited_files.original_hash
+         LEFT JOIN content_blobs AS edited_blob ON edited_blob.hash = edited_files.edited_hash
+         WHERE entry_uuid = ANY($1) ORDER BY entry_uuid, seq",
+        &[&uuids]
+    )? {
+        entries[entry_of(&row)?].additional_files.push(EditedFile {
+            filename: row.try_get("filename")?,
+            original_content: row.try_get::<_, Option<Vec<u8>>>("original_blob")?.unwrap_or_default(),
+            edited_content: row.try_get::<_, Option<Vec<u8>>>("edited_blob")?.unwrap_or_default(),
+            original_hash: row.try_get("original_hash")?,
+            edited_hash: row.try_get("edited_hash")?,
+        });
+    }
+    for row in client.query("SELECT entry_uuid, key, value FROM entry_facts WHERE entry_uuid = ANY($1)", &[&uuids])? {
+        entries[entry_of(&row)?].facts.insert(row.try_get("key")?, row.try_get("value")?);
+    }
+    for row in client.query(
+        "SELECT entry_uuid, tag FROM entry_tags WHERE entry_uuid = ANY($1) ORDER BY tag",
+        &[&uuids]
+    )? {
+        entries[entry_of(&row)?].tags.push(row.try_get("tag")?);
+    }
+    for row in client.query("SELECT entry_uuid, name, value FROM entry_custom_fields WHERE entry_uuid = ANY($1)", &[&uuids])? {
+        entries[entry_of(&row)?].custom_fields.insert(row.try_get("name")?, row.try_get("value")?);
+    }
+
+    for entry in entries.iter_mut() {
+        if !entry.events.is_empty() {
+            entry.captured_output = entry.output_from_events();
+        }
+    }
+    Ok(())
+}
+
+// The output in an entry's events, without the events themselves.
+fn load_output(client: &mut impl GenericClient, uuid: Uuid) -> Result<Vec<u8>, postgres::Error> {
+    let chunks = client
+        .query(
+            "SELECT data FROM capture_events WHERE entry_uuid = $1 AND event_type = 'o' AND data IS NOT NULL ORDER BY seq",
+            &[&uuid]
+        )?
+        .iter()
+        .map(|row| row.try_get::<_, Vec<u8>>(0))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(chunks.concat())
+}
+
+fn load_entries(
+    client: &mut impl GenericClient,
+    query: &str,
+    params: &[&(dyn ToSql + Sync)],
+    with_events: bool
+) -> Result<Vec<CaptureV2_4>, postgres::Error> {
+    let mut entries = client
+        .query(query, params)?
+        .iter()
+        .map(from_row_entry)
+        .collect::<Result<Vec<_>, _>>()?;
+    load_details(client, &mut entries, with_events)?;
+    Ok(entries)
+}
+
+fn from_row_task(row: &Row) -> Result<Task, postgres::Error> {
+    Ok(Task {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn from_row_session(row: &Row) -> Result<Session, postgres::Error> {
+    Ok(Session {
+        uuid: row.try_get("uuid")?,
+        start_time: row.try_get::<_, DateTime<Utc>>("start_time")?,
+        duration_ms: row.try_get::<_, i64>("duration_ms")? as u64,
+        status: CaptureStatus::parse(&row.try_get::<_, String>("status")?),
+        local_user: row.try_get("local_user")?,
+        cmd: row.try_get("cmd")?,
+        events: Vec::new(),
+    })
+}
+
+fn write_session(client: &mut impl GenericClient, session: &Session) -> Result<(), postgres::Error> {
+    client.execute(
+        "INSERT INTO sessions (uuid, start_time, duration_ms, status, local_user, cmd) VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (uuid) DO UPDATE SET start_time = EXCLUDED.start_time, duration_ms = EXCLUDED.duration_ms,
+             status = EXCLUDED.status, local_user = EXCLUDED.local_user, cmd = EXCLUDED.cmd",
+        &[
+            &session.uuid,
+            &session.start_time,
+            &(session.duration_ms as i64),
+            &session.status.as_str(),
+            &session.local_user,
+            &session.cmd,
+        ]
+    )?;
+    Ok(())
+}
+
+// How the server's certificate is checked. postgres::Config only knows sslmode disable,
+// prefer and require, which like in libpq encrypt without checking the certificate.
+#[derive(Default, PartialEq, Debug)]
+struct TlsOptions {
+    verify_ca: bool,
+    verify_hostname: bool,
+    // A PEM file with the certificate of the CA that signed the server's certificate,
+    // when that's not one of the system's
+    root_cert: Option<String>,
+}
+
+// Takes sslmode=verify-ca and verify-full and sslrootcert out of the query of a
+// database URL, as postgres::Config doesn't accept them.
+fn split_tls_options(url: &str) -> Result<(String, TlsOptions), String> {
+    let mut options = TlsOptions::default();
+    let Some((base, query)) = url.split_once('?') else {
+        return Ok((url.to_string(), options));
+    };
+    let mut params = Vec::new();
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some(("sslmode", "verify-ca")) => {
+                options.verify_ca = true;
+                params.push("sslmode=require".to_string());
+            }
+            Some(("sslmode", "verify-full")) => {
+                options.verify_ca = true;
+                options.verify_hostname = true;
+                params.push("sslmode=require".to_string());
+            }
+            Some(("sslrootcert", path)) => {
+                let path = urlencoding::decode(path).map_err(|e| format!("Invalid sslrootcert: {}", e))?;
+                options.root_cert = Some(path.into_owned());
+            }
+            _ => params.push(param.to_string()),
+        }
+    }
+    if params.is_empty() {
+        return Ok((base.to_string(), options));
+    }
+    Ok((format!("{}?{}", base, params.join("&")), options))
+}
+
+fn tls_connector(options: &TlsOptions) -> Result<MakeTlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = &options.root_cert {
+        let pem = std::fs::read(path).map_err(|e| format!("Error reading sslrootcert {}: {}", path, e))?;
+        let cert = native_tls::Certificate::from_pem(&pem).map_err(|e| format!("Invalid sslrootcert {}: {}", path, e))?;
+        builder.add_root_certificate(cert);
+    }
+    builder.danger_accept_invalid_certs(!options.verify_ca);
+    builder.danger_accept_invalid_hostnames(!options.verify_hostname);
+    let connector = builder.build().map_err(|e| format!("Error setting up TLS: {}", e))?;
+    Ok(MakeTlsConnector::new(connector))
+}
+
+impl PostgresSink {
+    /// Connects to the database at `url`, e.g. postgres://prodlog@db.example.com/prodlog,
+    /// and creates or migrates the schema.
+    pub fn new(url: &str) -> Self {
+        match Self::connect(url) {
+            Ok(postgres_sink) => postgres_sink,
+            Err(e) => prodlog_panic(&e.to_string()),
+        }
+    }
+
+    /// Like `new`, but returns an error instead of exiting when the database can't
+    /// be reached.
+    pub fn connect(url: &str) -> Result<Self, std::io::Error> {
+        let (url, tls_options) = split_tls_options(url).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let config: postgres::Config = url.parse().map_err(|e|
+            std::io::Error::new(std::io::ErrorKind::Other, format!("Invalid database URL: {}", e))
+        )?;
+        let tls = tls_connector(&tls_options).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let manager = PostgresConnectionManager::new(config, tls);
+        // Each prodlog_record opens a few sinks, and the database is shared
+        let pool = r2d2::Pool::builder()
+            .max_size(4)
+            .min_idle(Some(1))
+            .connection_timeout(std::time::Duration::from_secs(10))
+            .build(manager)
+            .map_err(|e|
+                std::io::Error::new(std::io::ErrorKind::Other, format!("Error connecting to the database: {}", e))
+            )?;
+
+        let postgres_sink = PostgresSink { pool: Some(pool) };
+        postgres_sink.with_client(migrate).map_err(|e|
+            std::io::Error::new(std::io::ErrorKind::Other, format!("Error migrating database: {}", e))
+        )?;
+        Ok(postgres_sink)
+    }
+
+    // The postgres client blocks on a runtime of its own, which tokio doesn't allow on
+    // the threads of its runtime, like those of the web UI's handlers. That includes
+    // closing the connections when the sink is dropped.
+    fn with_client<T>(
+        &self,
+        f: impl FnOnce(&mut postgres::Client) -> Result<T, postgres::Error>
+    ) -> Result<T, std::io::Error> {
+        let pool = self.pool.as_ref().expect("pool is only taken when dropping");
+        tokio::task::block_in_place(|| {
+            let mut client = pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            f(&mut client).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        })
+    }
+
+    fn insert_or_update_entry(&self, capture: &CaptureV2_4, is_insert: bool) -> Result<(), std::io::Error> {
+        let task_id = if is_insert {
+            self.get_active_task()?
+        } else {
+            capture.task_id
+        };
+        // For entries with timing information the output is stored in capture_events
+        // and reassembled when the entry is read.
+        let output: &[u8] = if capture.events.is_empty() { &capture.captured_output } else { &[] };
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            write_entry(&mut tx, capture, task_id, output)?;
+            write_events(&mut tx, capture)?;
+            write_additional_files(&mut tx, capture)?;
+            write_facts(&mut tx, capture)?;
+            write_tags(&mut tx, capture.uuid, &capture.tags)?;
+            write_custom_fields(&mut tx, capture.uuid, &capture.custom_fields)?;
+            tx.commit()
+        })
+    }
+}
+
+impl Drop for PostgresSink {
+    fn drop(&mut self) {
+        let pool = self.pool.take();
+        tokio::task::block_in_place(|| drop(pool));
+    }
+}
+
+impl Sink for PostgresSink {
+    fn add_new_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.insert_or_update_entry(capture, true)
+    }
+
+    fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let updated = self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let updated = tx.execute(
+                "UPDATE prodlog_entries SET message = $1, is_noop = $2 WHERE uuid = $3",
+                &[&capture.message, &capture.is_noop, &capture.uuid]
+            )?;
+            if updated == 0 {
+                return Ok(0);
+            }
+            write_tags(&mut tx, capture.uuid, &capture.tags)?;
+            write_custom_fields(&mut tx, capture.uuid, &capture.custom_fields)?;
+            tx.commit()?;
+            Ok(updated)
+        })?;
+        if updated == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Entry {} not found", capture.uuid)));
+        }
+        Ok(())
+    }
+
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.insert_or_update_entry(capture, false)
+    }
+
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let task_id = self.get_active_task()?;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            write_entry(&mut tx, capture, task_id, &capture.captured_output)?;
+            write_additional_files(&mut tx, capture)?;
+            write_facts(&mut tx, capture)?;
+            write_tags(&mut tx, capture.uuid, &capture.tags)?;
+            write_custom_fields(&mut tx, capture.uuid, &capture.custom_fields)?;
+            tx.commit()
+        })
+    }
+
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            append_events_to(&mut tx, "capture_events", "entry_uuid", uuid, events)?;
+            tx.commit()
+        })
+    }
+
+    fn replace_events_after(&self, uuid: Uuid, kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.execute("DELETE FROM capture_events WHERE entry_uuid = $1 AND seq >= $2", &[&uuid, &(kept as i64)])?;
+            append_events_to(&mut tx, "capture_events", "entry_uuid", uuid, events)?;
+            tx.commit()
+        })
+    }
+
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let task_id = self.get_active_task()?;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let end_time = capture.start_time + Duration::milliseconds(capture.duration_ms as i64);
+            let old_hashes = content_hashes(&mut tx, "prodlog_entries", "uuid", capture.uuid)?;
+            let edited_hash = store_content(&mut tx, &capture.edited_content)?;
+            // Only update what's known when the capture ends, so changes made in the UI
+            // while the capture was running are kept. A no-op rule can mark the entry as
+            // no-op now, but doesn't undo marking it by hand.
+            let updated = tx.execute(
+                "UPDATE prodlog_entries SET end_time = $1, duration_ms = $2, exit_code = $3, status = $4, terminal_rows = $5, terminal_cols = $6, edited_hash = $7, content_error = $8, is_noop = (is_noop OR $9) WHERE uuid = $10",
+                &[
+                    &end_time,
+                    &(capture.duration_ms as i64),
+                    &capture.exit_code,
+                    &capture.status.as_str(),
+                    &(capture.terminal_rows as i32),
+                    &(capture.terminal_cols as i32),
+                    &edited_hash,
+                    &capture.content_error,
+                    &capture.is_noop,
+                    &capture.uuid,
+                ]
+            )?;
+            if updated == 0 {
+                // The entry couldn't be stored when the capture started. Store it now, the
+                // output that was appended since is picked up from capture_events.
+                write_entry(&mut tx, capture, task_id, &capture.captured_output)?;
+                write_facts(&mut tx, capture)?;
+                write_tags(&mut tx, capture.uuid, &capture.tags)?;
+                write_custom_fields(&mut tx, capture.uuid, &capture.custom_fields)?;
+            }
+            prune_contents(&mut tx, &old_hashes)?;
+            write_additional_files(&mut tx, capture)?;
+            tx.commit()
+        })
+    }
+
+    fn get_entries(&self, filters: &Filters) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        let mut query = format!("{} WHERE TRUE", SELECT_ENTRIES);
+        let mut params: Vec<Box<dyn ToSql + Sync>> = Vec::new();
+        // Adds a parameter and returns its placeholder
+        let mut param = |value: String| {
+            params.push(Box::new(value));
+            format!("${}", params.len())
+        };
+
+        // Dates are compared in UTC, like the timestamps sqlite stores
+        if let Some(date_from) = &filters.date_from {
+            query.push_str(&format!(" AND start_time >= ({}::text::timestamp AT TIME ZONE 'UTC')", param(format!("{}T00:00:00", date_from))));
+        }
+
+        if let Some(date_to) = &filters.date_to {
+            query.push_str(&format!(" AND start_time <= ({}::text::timestamp AT TIME ZONE 'UTC')", param(format!("{}T23:59:59", date_to))));
+        }
+
+        // ILIKE, as LIKE in sqlite ignores case
+        if let Some(host) = &filters.host {
+            query.push_str(&format!(" AND host ILIKE {}", param(format!("%{}%", host))));
+        }
+
+        if let Some(command) = &filters.search {
+            let pattern = param(format!("%{}%", command));
+            query.push_str(&format!(" AND (cmd ILIKE {0} OR message ILIKE {0})", pattern));
+        }
+
+        if let Some(status) = &filters.status {
+            query.push_str(&format!(" AND status = {}", param(status.clone())));
+        }
+
+        if let Some(terminal) = &filters.terminal {
+            query.push_str(&format!(" AND terminal = {}", param(terminal.clone())));
+        }
+
+        if let Some(tag) = &filters.tag {
+            query.push_str(&format!(" AND EXISTS (SELECT 1 FROM entry_tags WHERE entry_uuid = prodlog_entries.uuid AND tag = {})", param(tag.clone())));
+        }
+
+        if let Some(field) = &filters.field {
+            match field.split_once('=') {
+                Some((name, value)) => {
+                    let (name, value) = (param(name.to_string()), param(value.to_string()));
+                    query.push_str(&format!(" AND EXISTS (SELECT 1 FROM entry_custom_fields WHERE entry_uuid = prodlog_entries.uuid AND name = {} AND value = {})", name, value));
+                }
+                None => {
+                    query.push_str(&format!(" AND EXISTS (SELECT 1 FROM entry_custom_fields WHERE entry_uuid = prodlog_entries.uuid AND name = {})", param(field.clone())));
+                }
+            }
+        }
+
+        if let Some(true) = &filters.show_noop {
+            // Don't filter out no-op entries
+        } else {
+            query.push_str(" AND NOT is_noop");
+        }
+
+        query.push_str(" ORDER BY start_time DESC");
+
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+        self.with_client(|client| {
+            let entries = load_entries(client, &query, &params, false)?;
+            // Like SqliteSink, where contents are compressed, searching only the output
+            // of the entries that the other filters left
+            let Some(content) = &filters.search_content else {
+                return Ok(entries);
+            };
+            let mut found = Vec::new();
+            for entry in entries {
+                if entry_contains(&entry, content, || load_output(client, entry.uuid))? {
+                    found.push(entry);
+                }
+            }
+            Ok(found)
+        })
+    }
+
+    fn get_entry_by_id(&self, uuid: Uuid) -> Result<Option<CaptureV2_4>, std::io::Error> {
+        let query = format!("{} WHERE uuid = $1", SELECT_ENTRIES);
+        let entries = self.with_client(|client| load_entries(client, &query, &[&uuid], true))?;
+        Ok(entries.into_iter().next())
+    }
+
+    fn get_child_entries(&self, parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        let query = format!("{} WHERE parent_uuid = $1 ORDER BY start_time", SELECT_ENTRIES);
+        self.with_client(|client| load_entries(client, &query, &[&parent_uuid], false))
+    }
+
+    fn get_content_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query_opt("SELECT data FROM content_blobs WHERE hash = $1", &[&hash])?
+                .map(|row| row.try_get(0))
+                .transpose()
+        })
+    }
+
+    fn create_task(&self, name: &str) -> Result<i64, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query_one("INSERT INTO tasks (name, created_at) VALUES ($1, $2) RETURNING id", &[&name, &Utc::now()])?
+                .try_get(0)
+        })
+    }
+
+    fn get_all_tasks(&self) -> Result<Vec<Task>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query("SELECT id, name, created_at FROM tasks ORDER BY id DESC", &[])?
+                .iter()
+                .map(from_row_task)
+                .collect()
+        })
+    }
+
+    fn get_task_by_id(&self, id: i64) -> Result<Option<Task>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query_opt("SELECT id, name, created_at FROM tasks WHERE id = $1", &[&id])?
+                .as_ref()
+                .map(from_row_task)
+                .transpose()
+        })
+    }
+
+    fn update_task_name(&self, task_id: i64, name: &str) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            client.execute("UPDATE tasks SET name = $1 WHERE id = $2", &[&name, &task_id])?;
+            Ok(())
+        })
+    }
+
+    fn assign_entries_to_task(&self, entry_uuids: &[String], task_id: Option<i64>) -> Result<(), std::io::Error> {
+        // Like SqliteSink, UUIDs that aren't valid match no entry
+        let uuids: Vec<Uuid> = entry_uuids.iter().filter_map(|uuid| Uuid::parse_str(uuid).ok()).collect();
+        self.with_client(|client| {
+            client.execute("UPDATE prodlog_entries SET task_id = $1 WHERE uuid = ANY($2)", &[&task_id, &uuids])?;
+            Ok(())
+        })
+    }
+
+    fn get_active_task(&self) -> Result<Option<i64>, std::io::Error> {
+        self.with_client(|client| client.query_one("SELECT task_id FROM active_task WHERE id = 1", &[])?.try_get(0))
+    }
+
+    fn set_active_task(&self, task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            client.execute("UPDATE active_task SET task_id = $1 WHERE id = 1", &[&task_id])?;
+            Ok(())
+        })
+    }
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            write_tags(&mut tx, uuid, tags)?;
+            tx.commit()
+        })
+    }
+
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            client.execute("UPDATE prodlog_entries SET is_noop = $1 WHERE uuid = $2", &[&is_noop, &uuid])?;
+            Ok(())
+        })
+    }
+
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            write_custom_fields(&mut tx, uuid, fields)?;
+            tx.commit()
+        })
+    }
+
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query("SELECT DISTINCT tag FROM entry_tags ORDER BY tag", &[])?
+                .iter()
+                .map(|row| row.try_get(0))
+                .collect()
+        })
+    }
+
+    fn begin_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            write_session(&mut tx, session)?;
+            append_events_to(&mut tx, "session_events", "session_uuid", session.uuid, &session.events)?;
+            tx.commit()
+        })
+    }
+
+    fn append_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            append_events_to(&mut tx, "session_events", "session_uuid", uuid, events)?;
+            tx.commit()
+        })
+    }
+
+    fn finalize_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.with_client(|client| write_session(client, session))
+    }
+
+    fn redact_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.execute("DELETE FROM session_events WHERE session_uuid = $1", &[&uuid])?;
+            append_events_to(&mut tx, "session_events", "session_uuid", uuid, events)?;
+            tx.commit()
+        })
+    }
+
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query("SELECT * FROM sessions ORDER BY start_time DESC", &[])?
+                .iter()
+                .map(from_row_session)
+                .collect()
+        })
+    }
+
+    fn get_session_by_id(&self, uuid: Uuid) -> Result<Option<Session>, std::io::Error> {
+        self.with_client(|client| {
+            client
+                .query_opt("SELECT * FROM sessions WHERE uuid = $1", &[&uuid])?
+                .as_ref()
+                .map(from_row_session)
+                .transpose()
+        })
+    }
+
+    fn get_session_events(&self, uuid: Uuid, from_ms: u64, to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error> {
+        self.with_client(|client| {
+            let mut events = Vec::new();
+            if let Some(row) = client.query_opt(
+                "SELECT offset_ms, event_type, data FROM session_events WHERE session_uuid = $1 AND event_type = 'r' AND offset_ms < $2 ORDER BY seq DESC LIMIT 1",
+                &[&uuid, &(from_ms as i64)]
+            )? {
+                events.extend(from_row_event(&row)?);
+            }
+            for row in client.query(
+                "SELECT offset_ms, event_type, data FROM session_events WHERE session_uuid = $1 AND offset_ms >= $2 AND offset_ms <= $3 ORDER BY seq",
+                &[&uuid, &(from_ms as i64), &(to_ms as i64)]
+            )? {
+                events.extend(from_row_event(&row)?);
+            }
+            Ok(events)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs against the database in PRODLOG_TEST_DATABASE_URL, e.g.
+    // postgres://postgres@localhost/prodlog_test
+    fn test_sink() -> PostgresSink {
+        let url = std::env::var("PRODLOG_TEST_DATABASE_URL")
+            .expect("Set PRODLOG_TEST_DATABASE_URL to the database to run this test against");
+        PostgresSink::new(&url)
+    }
+
+    #[test]
+    fn test_split_tls_options() {
+        let url = "postgres://prodlog@db.example.com/prodlog";
+        assert_eq!(split_tls_options(url).unwrap(), (url.to_string(), TlsOptions::default()));
+        let (url, options) = split_tls_options(
+            "postgres://prodlog@db.example.com/prodlog?sslmode=verify-full&sslrootcert=%2Fetc%2Fca.pem&application_name=prodlog"
+        ).unwrap();
+        assert_eq!(url, "postgres://prodlog@db.example.com/prodlog?sslmode=require&application_name=prodlog");
+        assert_eq!(options, TlsOptions { verify_ca: true, verify_hostname: true, root_cert: Some("/etc/ca.pem".to_string()) });
+        let (url, options) = split_tls_options("postgres://db.example.com/prodlog?sslmode=verify-ca").unwrap();
+        assert_eq!(url, "postgres://db.example.com/prodlog?sslmode=require");
+        assert!(options.verify_ca && !options.verify_hostname);
+    }
+
+    #[test]
+    #[ignore = "needs a database, run with PRODLOG_TEST_DATABASE_URL=<URL> cargo test -- --ignored"]
+    fn test_entry_round_trip() {
+        let sink = test_sink();
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: Utc::now(),
+            duration_ms: 3000,
+            status: CaptureStatus::Completed,
+            local_user: "alice".to_string(),
+            cmd: "ssh db1".to_string(),
+            events: vec![
+                CaptureEvent { offset_ms: 0, kind: CaptureEventKind::Resize { cols: 100, rows: 30 } },
+                CaptureEvent { offset_ms: 1000, kind: CaptureEventKind::Output(b"$ vi app.conf\r\n".to_vec()) },
+                CaptureEvent { offset_ms: 2000, kind: CaptureEventKind::MaskedInput },
+            ],
+        };
+        sink.begin_session(&session).unwrap();
+        assert_eq!(sink.get_session_events(session.uuid, 500, 3000).unwrap(), session.events);
+
+        let mut capture = session.slice_to_capture(session.events.clone(), 0, 3000);
+        capture.capture_type = CaptureType::Edit;
+        capture.host = "db1".to_string();
+        capture.filename = "/etc/app.conf".to_string();
+        capture.original_content = b"port = 80\n".repeat(100);
+        capture.edited_content = b"port = 443\n".repeat(100);
+        // The next edit starts from where this one ended, so its contents are shared
+        capture.additional_files.push(EditedFile {
+            filename: "/etc/app.conf.bak".to_string(),
+            original_content: capture.edited_content.clone(),
+            edited_content: Vec::new(),
+            original_hash: None,
+            edited_hash: None,
+        });
+        let tag = format!("test-{}", capture.uuid);
+        capture.tags = vec![tag.clone()];
+        capture.facts.insert("os".to_string(), "Debian".to_string());
+        capture.custom_fields.insert("change_request".to_string(), "CR-1".to_string());
+        let task_id = sink.create_task("round trip").unwrap();
+        sink.set_active_task(Some(task_id)).unwrap();
+        sink.add_new_entry(&capture).unwrap();
+
+        let stored = sink.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        assert_eq!(stored.task_id, Some(task_id));
+        assert_eq!(stored.captured_output, capture.captured_output);
+        assert_eq!(stored.events, capture.events);
+        assert_eq!((stored.original_content.clone(), stored.edited_content.clone()), (capture.original_content.clone(), capture.edited_content.clone()));
+        assert_eq!(stored.additional_files[0].original_hash, stored.edited_hash);
+        assert_eq!(stored.additional_files[0].edited_hash, None);
+        assert_eq!((stored.tags.clone(), stored.facts.clone(), stored.custom_fields.clone()), (capture.tags, capture.facts, capture.custom_fields));
+        assert_eq!(sink.get_content_blob(stored.edited_hash.as_ref().unwrap()).unwrap(), Some(capture.edited_content));
+
+        let filters = Filters { tag: Some(tag.clone()), field: Some("change_request=CR-1".to_string()), host: Some("DB".to_string()), ..Filters::default() };
+        let found: Vec<Uuid> = sink.get_entries(&filters).unwrap().iter().map(|entry| entry.uuid).collect();
+        assert_eq!(found, vec![capture.uuid]);
+        for (text, matches) in [("VI APP", true), ("port = 443", true), ("port = 8080", false)] {
+            let filters = Filters { tag: Some(tag.clone()), search_content: Some(text.to_string()), ..Filters::default() };
+            assert_eq!(sink.get_entries(&filters).unwrap().len(), matches as usize, "{}", text);
+        }
+
+        // Editing it in the UI keeps the output appended since it was loaded
+        let mut edited = stored.clone();
+        let appended = CaptureEvent { offset_ms: 2500, kind: CaptureEventKind::Output(b":wq\r\n".to_vec()) };
+        sink.append_events(capture.uuid, std::slice::from_ref(&appended)).unwrap();
+        edited.message = "switch to TLS".to_string();
+        sink.update_entry(&edited).unwrap();
+        let updated = sink.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        assert_eq!(updated.events, [capture.events.clone(), vec![appended]].concat());
+        assert_eq!(updated.message, edited.message);
+
+        // The output held back by --output-tail replaces what was held back before
+        let held = CaptureEvent { offset_ms: 3000, kind: CaptureEventKind::Output(b"tail\r\n".to_vec()) };
+        sink.replace_events_after(capture.uuid, updated.events.len() - 1, std::slice::from_ref(&held)).unwrap();
+        let replaced = sink.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        assert_eq!(replaced.events, [capture.events.clone(), vec![held]].concat());
+
+        // Contents no entry refers to anymore are deleted, like those before redacting
+        let mut redacted = stored.clone();
+        redacted.original_content = b"port = [REDACTED]\n".repeat(100);
+        sink.redact_entry(&redacted).unwrap();
+        assert_eq!(sink.get_content_blob(stored.original_hash.as_ref().unwrap()).unwrap(), None);
+        assert!(sink.get_content_blob(stored.edited_hash.as_ref().unwrap()).unwrap().is_some());
+    }
+}
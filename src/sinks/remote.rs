@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::io::{ BufRead, BufReader, Write };
+use std::os::unix::net::UnixStream;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, OnceLock };
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+use tokio::io::{ AsyncBufReadExt, AsyncWriteExt };
+use tokio::net::UnixListener;
+use uuid::Uuid;
+
+use crate::helpers::compare_major_minor_versions;
+use crate::model::{ CaptureEvent, CaptureV2_4, Session, Task };
+use crate::{ print_prodlog_message, print_prodlog_warning };
+use super::{ Filters, Sink };
+
+/// The socket in the prodlog directory the first prodlog_record instance listens on,
+/// so later instances can write their entries through it.
+pub const SOCKET_FILE: &str = "prodlog.sock";
+
+// Don't let a hanging primary hang the terminal as well.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A capture with its events, which aren't part of its JSON representation.
+#[derive(Serialize, Deserialize)]
+struct WireCapture {
+    capture: CaptureV2_4,
+    events: Vec<CaptureEvent>,
+}
+
+impl From<&CaptureV2_4> for WireCapture {
+    fn from(capture: &CaptureV2_4) -> Self {
+        WireCapture { capture: capture.clone(), events: capture.events.clone() }
+    }
+}
+
+impl WireCapture {
+    fn into_capture(self) -> CaptureV2_4 {
+        let mut capture = self.capture;
+        capture.events = self.events;
+        capture
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireSession {
+    session: Session,
+    events: Vec<CaptureEvent>,
+}
+
+impl From<&Session> for WireSession {
+    fn from(session: &Session) -> Self {
+        WireSession { session: session.clone(), events: session.events.clone() }
+    }
+}
+
+impl WireSession {
+    fn into_session(self) -> Session {
+        let mut session = self.session;
+        session.events = self.events;
+        session
+    }
+}
+
+// One per Sink method, sent as a line of JSON. The primary answers each with a line
+// holding a Response.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    Hello { version: String },
+    AddNewEntry { capture: WireCapture },
+    UpdateEntry { capture: WireCapture },
+    RedactEntry { capture: WireCapture },
+    BeginEntry { capture: WireCapture },
+    AppendEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    ReplaceEventsAfter { uuid: Uuid, kept: usize, events: Vec<CaptureEvent> },
+    FinalizeEntry { capture: WireCapture },
+    GetEntries { filters: Filters },
+    GetEntryById { uuid: Uuid },
+    GetChildEntries { parent_uuid: Uuid },
+    CreateTask { name: String },
+    GetAllTasks,
+    GetTaskById { id: i64 },
+    UpdateTaskName { task_id: i64, name: String },
+    AssignEntriesToTask { entry_uuids: Vec<String>, task_id: Option<i64> },
+    GetActiveTask,
+    SetActiveTask { task_id: Option<i64> },
+    SetEntryTags { uuid: Uuid, tags: Vec<String> },
+    SetEntryNoop { uuid: Uuid, is_noop: bool },
+    SetEntryCustomFields { uuid: Uuid, fields: BTreeMap<String, String> },
+    GetAllTags,
+    GetContentBlob { hash: String },
+    BeginSession { session: WireSession },
+    AppendSessionEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    RedactSessionEvents { uuid: Uuid, events: Vec<CaptureEvent> },
+    FinalizeSession { session: WireSession },
+    GetSessions,
+    GetSessionById { uuid: Uuid },
+    GetSessionEvents { uuid: Uuid, from_ms: u64, to_ms: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+/// The sink of a prodlog_record instance that was started while another one was
+/// already running. Everything is written through the first instance, so its web UI
+/// shows the entries of all terminals.
+pub struct RemoteSink {
+    socket_path: PathBuf,
+    connection: Mutex<Option<BufReader<UnixStream>>>,
+    // Takes over from the primary instance when it's gone, so nothing is lost when it
+    // exits before we do, and the web UI and the other instances keep working. Returns
+    // the sink to write to from then on, or None if another instance took over first.
+    take_over: Box<dyn Fn() -> Option<Box<dyn Sink>> + Send + Sync>,
+    direct: OnceLock<Box<dyn Sink>>,
+}
+
+impl RemoteSink {
+    /// Attaches to the instance listening on `socket_path`, if there is one.
+    /// `take_over` makes this the primary instance, for when it's gone.
+    pub fn connect(
+        socket_path: &Path,
+        take_over: impl Fn() -> Option<Box<dyn Sink>> + Send + Sync + 'static
+    ) -> Result<Self, std::io::Error> {
+        let connection = Self::open(socket_path)?;
+        Ok(RemoteSink {
+            socket_path: socket_path.to_path_buf(),
+            connection: Mutex::new(Some(connection)),
+            take_over: Box::new(take_over),
+            direct: OnceLock::new(),
+        })
+    }
+
+    fn open(socket_path: &Path) -> Result<BufReader<UnixStream>, std::io::Error> {
+        let stream = UnixStream::connect(socket_path)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut connection = BufReader::new(stream);
+        let hello = Request::Hello { version: env!("CARGO_PKG_VERSION").to_string() };
+        Self::send(&mut connection, &hello)?;
+        match Self::receive(&mut connection)? {
+            Response::Ok(_) => Ok(connection),
+            Response::Error(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+        }
+    }
+
+    fn send(connection: &mut BufReader<UnixStream>, request: &Request) -> Result<(), std::io::Error> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        connection.get_mut().write_all(line.as_bytes())
+    }
+
+    fn receive(connection: &mut BufReader<UnixStream>) -> Result<Response, std::io::Error> {
+        let mut reply = String::new();
+        if connection.read_line(&mut reply)? == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    // Attaches to the primary instance again, e.g. after it was restarted, or takes over
+    // from it if it's gone.
+    fn reconnect(&self) -> Option<BufReader<UnixStream>> {
+        if let Ok(connection) = Self::open(&self.socket_path) {
+            print_prodlog_message("Attached to the prodlog_record instance that's running now");
+            return Some(connection);
+        }
+        match (self.take_over)() {
+            Some(sink) => {
+                let _ = self.direct.set(sink);
+                print_prodlog_message(
+                    "The prodlog_record instance this one wrote through is gone, this one took over its web UI and the other instances"
+                );
+                None
+            }
+            None => {
+                let connection = Self::open(&self.socket_path).ok();
+                if connection.is_some() {
+                    print_prodlog_message("Attached to the prodlog_record instance that took over");
+                }
+                connection
+            }
+        }
+    }
+
+    /// Sends `request` to the primary instance, or runs `direct` on the database once
+    /// this instance took over from it.
+    fn call<T: DeserializeOwned>(
+        &self,
+        request: Request,
+        direct: impl FnOnce(&dyn Sink) -> Result<T, std::io::Error>
+    ) -> Result<T, std::io::Error> {
+        let mut connection = self.connection.lock().unwrap();
+        // Tried again once when the request couldn't be sent
+        for _ in 0..2 {
+            if connection.is_none() && self.direct.get().is_none() {
+                *connection = self.reconnect();
+            }
+            let Some(stream) = connection.as_mut() else {
+                break;
+            };
+            if let Err(e) = Self::send(stream, &request) {
+                // A request is only handled once its whole line arrived, so this one wasn't
+                print_prodlog_warning(&format!("Lost the connection to the other prodlog_record instance: {}", e));
+                *connection = None;
+                continue;
+            }
+            return match Self::receive(stream) {
+                Ok(Response::Ok(value)) => serde_json::from_value(value).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+                Ok(Response::Error(e)) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+                Err(e) => {
+                    // The request may have been handled or not, so it's not sent again or
+                    // written directly, which could write it twice.
+                    *connection = None;
+                    Err(std::io::Error::new(e.kind(), format!("No answer from the other prodlog_record instance: {}", e)))
+                }
+            };
+        }
+        drop(connection);
+        match self.direct.get() {
+            Some(sink) => direct(sink.as_ref()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "Can't reach the other prodlog_record instance")),
+        }
+    }
+}
+
+impl Sink for RemoteSink {
+    fn add_new_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.call(Request::AddNewEntry { capture: capture.into() }, |sink| sink.add_new_entry(capture))
+    }
+
+    fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.call(Request::UpdateEntry { capture: capture.into() }, |sink| sink.update_entry(capture))
+    }
+
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.call(Request::RedactEntry { capture: capture.into() }, |sink| sink.redact_entry(capture))
+    }
+
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.call(Request::BeginEntry { capture: capture.into() }, |sink| sink.begin_entry(capture))
+    }
+
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.call(Request::AppendEvents { uuid, events: events.to_vec() }, |sink| sink.append_events(uuid, events))
+    }
+
+    fn replace_events_after(&self, uuid: Uuid, kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.call(Request::ReplaceEventsAfter { uuid, kept, events: events.to_vec() }, |sink|
+            sink.replace_events_after(uuid, kept, events)
+        )
+    }
+
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        self.call(Request::FinalizeEntry { capture: capture.into() }, |sink| sink.finalize_entry(capture))
+    }
+
+    fn get_entries(&self, filters: &Filters) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        let filters_json = serde_json::to_value(filters)?;
+        let entries: Vec<WireCapture> = self.call(
+            Request::GetEntries { filters: serde_json::from_value(filters_json)? },
+            |sink| Ok(sink.get_entries(filters)?.iter().map(WireCapture::from).collect())
+        )?;
+        Ok(entries.into_iter().map(WireCapture::into_capture).collect())
+    }
+
+    fn get_entry_by_id(&self, uuid: Uuid) -> Result<Option<CaptureV2_4>, std::io::Error> {
+        let entry: Option<WireCapture> = self.call(Request::GetEntryById { uuid }, |sink|
+            Ok(sink.get_entry_by_id(uuid)?.as_ref().map(WireCapture::from))
+        )?;
+        Ok(entry.map(WireCapture::into_capture))
+    }
+
+    fn get_child_entries(&self, parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        let entries: Vec<WireCapture> = self.call(Request::GetChildEntries { parent_uuid }, |sink|
+            Ok(sink.get_child_entries(parent_uuid)?.iter().map(WireCapture::from).collect())
+        )?;
+        Ok(entries.into_iter().map(WireCapture::into_capture).collect())
+    }
+
+    fn create_task(&self, name: &str) -> Result<i64, std::io::Error> {
+        self.call(Request::CreateTask { name: name.to_string() }, |sink| sink.create_task(name))
+    }
+
+    fn get_all_tasks(&self) -> Result<Vec<Task>, std::io::Error> {
+        self.call(Request::GetAllTasks, |sink| sink.get_all_tasks())
+    }
+
+    fn get_task_by_id(&self, id: i64) -> Result<Option<Task>, std::io::Error> {
+        self.call(Request::GetTaskById { id }, |sink| sink.get_task_by_id(id))
+    }
+
+    fn update_task_name(&self, task_id: i64, name: &str) -> Result<(), std::io::Error> {
+        self.call(Request::UpdateTaskName { task_id, name: name.to_string() }, |sink|
+            sink.update_task_name(task_id, name)
+        )
+    }
+
+    fn assign_entries_to_task(&self, entry_uuids: &[String], task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.call(Request::AssignEntriesToTask { entry_uuids: entry_uuids.to_vec(), task_id }, |sink|
+            sink.assign_entries_to_task(entry_uuids, task_id)
+        )
+    }
+
+    fn get_active_task(&self) -> Result<Option<i64>, std::io::Error> {
+        self.call(Request::GetActiveTask, |sink| sink.get_active_task())
+    }
+
+    fn set_active_task(&self, task_id: Option<i64>) -> Result<(), std::io::Error> {
+        self.call(Request::SetActiveTask { task_id }, |sink| sink.set_active_task(task_id))
+    }
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error> {
+        self.call(Request::SetEntryTags { uuid, tags: tags.to_vec() }, |sink| sink.set_entry_tags(uuid, tags))
+    }
+
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error> {
+        self.call(Request::SetEntryNoop { uuid, is_noop }, |sink| sink.set_entry_noop(uuid, is_noop))
+    }
+
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        self.call(Request::SetEntryCustomFields { uuid, fields: fields.clone() }, |sink|
+            sink.set_entry_custom_fields(uuid, fields)
+        )
+    }
+
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error> {
+        self.call(Request::GetAllTags, |sink| sink.get_all_tags())
+    }
+
+    fn get_content_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.call(Request::GetContentBlob { hash: hash.to_string() }, |sink| sink.get_content_blob(hash))
+    }
+
+    fn begin_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.call(Request::BeginSession { session: session.into() }, |sink| sink.begin_session(session))
+    }
+
+    fn append_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.call(Request::AppendSessionEvents { uuid, events: events.to_vec() }, |sink|
+            sink.append_session_events(uuid, events)
+        )
+    }
+
+    fn redact_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        self.call(Request::RedactSessionEvents { uuid, events: events.to_vec() }, |sink|
+            sink.redact_session_events(uuid, events)
+        )
+    }
+
+    fn finalize_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        self.call(Request::FinalizeSession { session: session.into() }, |sink| sink.finalize_session(session))
+    }
+
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error> {
+        self.call(Request::GetSessions, |sink| sink.get_sessions())
+    }
+
+    fn get_session_by_id(&self, uuid: Uuid) -> Result<Option<Session>, std::io::Error> {
+        self.call(Request::GetSessionById { uuid }, |sink| sink.get_session_by_id(uuid))
+    }
+
+    fn get_session_events(&self, uuid: Uuid, from_ms: u64, to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error> {
+        self.call(Request::GetSessionEvents { uuid, from_ms, to_ms }, |sink|
+            sink.get_session_events(uuid, from_ms, to_ms)
+        )
+    }
+}
+
+/// Listens on `socket_path` for instances started later. Fails with AddrInUse if
+/// another instance is listening already.
+pub fn listen(socket_path: &Path) -> Result<UnixListener, std::io::Error> {
+    match UnixListener::bind(socket_path) {
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(socket_path).is_ok() {
+                return Err(e);
+            }
+            // Left behind by an instance that didn't exit cleanly
+            std::fs::remove_file(socket_path)?;
+            UnixListener::bind(socket_path)
+        }
+        result => result,
+    }
+}
+
+/// Writes what the attached instances send to `sink`.
+pub async fn serve(listener: UnixListener, sink: Arc<Box<dyn Sink>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_instance(stream, sink.clone()));
+            }
+            Err(e) => {
+                print_prodlog_warning(&format!("Stopped accepting other prodlog_record instances: {}", e));
+                return;
+            }
+        }
+    }
+}
+
+async fn serve_instance(stream: tokio::net::UnixStream, sink: Arc<Box<dyn Sink>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let sink = sink.clone();
+                tokio::task
+                    ::spawn_blocking(move || handle_request(sink.as_ref().as_ref(), request)).await
+                    .unwrap_or_else(|e| Response::Error(e.to_string()))
+            }
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        let mut reply = serde_json::to_string(&response).unwrap();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn respond<T: Serialize>(result: Result<T, std::io::Error>) -> Response {
+    match result.and_then(|value| Ok(serde_json::to_value(value)?)) {
+        Ok(value) => Response::Ok(value),
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn handle_request(sink: &dyn Sink, request: Request) -> Response {
+    match request {
+        Request::Hello { version } if compare_major_minor_versions(&version, env!("CARGO_PKG_VERSION")) =>
+            Response::Ok(serde_json::Value::Null),
+        Request::Hello { version } =>
+            Response::Error(
+                format!("prodlog_record version {} can't attach to version {}", version, env!("CARGO_PKG_VERSION"))
+            ),
+        Request::AddNewEntry { capture } => respond(sink.add_new_entry(&capture.into_capture())),
+        Request::UpdateEntry { capture } => respond(sink.update_entry(&capture.into_capture())),
+        Request::RedactEntry { capture } => respond(sink.redact_entry(&capture.into_capture())),
+        Request::BeginEntry { capture } => respond(sink.begin_entry(&capture.into_capture())),
+        Request::AppendEvents { uuid, events } => respond(sink.append_events(uuid, &events)),
+        Request::ReplaceEventsAfter { uuid, kept, events } => respond(sink.replace_events_after(uuid, kept, &events)),
+        Request::FinalizeEntry { capture } => respond(sink.finalize_entry(&capture.into_capture())),
+        Request::GetEntries { filters } =>
+            respond(sink.get_entries(&filters).map(|entries| entries.iter().map(WireCapture::from).collect::<Vec<_>>())),
+        Request::GetEntryById { uuid } =>
+            respond(sink.get_entry_by_id(uuid).map(|entry| entry.as_ref().map(WireCapture::from))),
+        Request::GetChildEntries { parent_uuid } =>
+            respond(
+                sink.get_child_entries(parent_uuid).map(|entries| entries.iter().map(WireCapture::from).collect::<Vec<_>>())
+            ),
+        Request::CreateTask { name } => respond(sink.create_task(&name)),
+        Request::GetAllTasks => respond(sink.get_all_tasks()),
+        Request::GetTaskById { id } => respond(sink.get_task_by_id(id)),
+        Request::UpdateTaskName { task_id, name } => respond(sink.update_task_name(task_id, &name)),
+        Request::AssignEntriesToTask { entry_uuids, task_id } =>
+            respond(sink.assign_entries_to_task(&entry_uuids, task_id)),
+        Request::GetActiveTask => respond(sink.get_active_task()),
+        Request::SetActiveTask { task_id } => respond(sink.set_active_task(task_id)),
+        Request::SetEntryTags { uuid, tags } => respond(sink.set_entry_tags(uuid, &tags)),
+        Request::SetEntryNoop { uuid, is_noop } => respond(sink.set_entry_noop(uuid, is_noop)),
+        Request::SetEntryCustomFields { uuid, fields } => respond(sink.set_entry_custom_fields(uuid, &fields)),
+        Request::GetAllTags => respond(sink.get_all_tags()),
+        Request::GetContentBlob { hash } => respond(sink.get_content_blob(&hash)),
+        Request::BeginSession { session } => respond(sink.begin_session(&session.into_session())),
+        Request::AppendSessionEvents { uuid, events } => respond(sink.append_session_events(uuid, &events)),
+        Request::RedactSessionEvents { uuid, events } => respond(sink.redact_session_events(uuid, &events)),
+        Request::FinalizeSession { session } => respond(sink.finalize_session(&session.into_session())),
+        Request::GetSessions => respond(sink.get_sessions()),
+        Request::GetSessionById { uuid } => respond(sink.get_session_by_id(uuid)),
+        Request::GetSessionEvents { uuid, from_ms, to_ms } => respond(sink.get_session_events(uuid, from_ms, to_ms)),
+    }
+}
@@ -3,37 +3,233 @@ use r2d2::PooledConnection;
 use rusqlite::params;
 use rusqlite::Error::QueryReturnedNoRows;
 use rusqlite::OptionalExtension;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use uuid::Uuid;
-use std::path::PathBuf;
-use crate::{ helpers::compare_major_minor_versions, model::*, print_prodlog_message, print_prodlog_warning, prodlog_panic };
-use super::Sink;
+use std::path::Path;
+use crate::{ helpers::{ compare_major_minor_versions, major_minor_version, sha256_hex }, model::*, print_prodlog_message, print_prodlog_warning, prodlog_panic };
+use super::{ entry_contains, Sink };
 use r2d2_sqlite::SqliteConnectionManager;
 
 pub struct SqliteSink {
     pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
 }
 
+// Blobs that are stored compressed start with this, followed by the codec. Blobs
+// without it are stored as they are, as all blobs were before 2.19.
+const BLOB_MAGIC: &[u8] = b"\xffPL";
+const CODEC_RAW: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+// Smaller blobs, like most keystrokes, don't get smaller by compressing them.
+const MIN_COMPRESSED_SIZE: usize = 64;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses `data` for storing it in a blob column, if that makes it smaller.
+fn encode_blob(data: &[u8]) -> Vec<u8> {
+    if data.len() >= MIN_COMPRESSED_SIZE {
+        if let Ok(compressed) = zstd::bulk::compress(data, ZSTD_LEVEL) {
+            if compressed.len() + BLOB_MAGIC.len() + 1 < data.len() {
+                return [BLOB_MAGIC, &[CODEC_ZSTD], &compressed].concat();
+            }
+        }
+    }
+    if data.starts_with(BLOB_MAGIC) {
+        // So it isn't mistaken for a compressed blob
+        return [BLOB_MAGIC, &[CODEC_RAW], data].concat();
+    }
+    data.to_vec()
+}
+
+/// Reads a blob written by encode_blob, or one written before blobs were compressed.
+fn decode_blob(blob: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+    match blob.strip_prefix(BLOB_MAGIC).and_then(|rest| rest.split_first()) {
+        Some((&CODEC_RAW, data)) => Ok(data.to_vec()),
+        Some((&CODEC_ZSTD, data)) => zstd::stream::decode_all(data),
+        Some((codec, _)) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown blob codec {}, written by a newer version of Prodlog?", codec)
+        )),
+        None => Ok(blob),
+    }
+}
+
+fn get_blob(row: &rusqlite::Row, column: &str) -> rusqlite::Result<Vec<u8>> {
+    decode_blob(row.get(column)?).map_err(|e|
+        rusqlite::Error::FromSqlConversionFailure(
+            row.as_ref().column_index(column).unwrap_or_default(),
+            rusqlite::types::Type::Blob,
+            Box::new(e)
+        )
+    )
+}
+
+// Compresses the blobs of all rows of `table`, which were all stored as they are.
+fn compress_blobs(conn: &rusqlite::Connection, table: &str, columns: &[&str]) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let mut last_rowid = 0i64;
+    loop {
+        // In batches, so the whole table doesn't have to fit in memory
+        let rows = {
+            let mut stmt = tx.prepare(
+                &format!("SELECT rowid, {} FROM {} WHERE rowid > ? ORDER BY rowid LIMIT 1000", columns.join(", "), table)
+            )?;
+            let rows = stmt.query_map(params![last_rowid], |row| {
+                let blobs = (0..columns.len())
+                    .map(|i| row.get::<_, Option<Vec<u8>>>(i + 1))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok((row.get::<_, i64>(0)?, blobs))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let Some((rowid, _)) = rows.last() else {
+            break;
+        };
+        last_rowid = *rowid;
+        let assignments: Vec<String> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| format!("{} = ?{}", column, i + 2))
+            .collect();
+        let mut stmt = tx.prepare(&format!("UPDATE {} SET {} WHERE rowid = ?1", table, assignments.join(", ")))?;
+        for (rowid, blobs) in rows {
+            let mut values: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(rowid)];
+            values.extend(
+                blobs.into_iter().map(|blob| Box::new(blob.map(|data| encode_blob(&data))) as Box<dyn rusqlite::ToSql>)
+            );
+            stmt.execute(rusqlite::params_from_iter(values.iter().map(|value| value.as_ref())))?;
+        }
+    }
+    tx.commit()
+}
+
+// File contents are stored once in content_blobs, keyed by their SHA-256, and entries
+// refer to them by hash: the contents after one edit are usually those before the next.
+// Entries written before 2.20 had them inline, which is still read.
+
+/// Stores `data` in content_blobs if it isn't there yet, and returns its hash. Empty
+/// contents aren't stored.
+fn store_content(conn: &rusqlite::Connection, data: &[u8]) -> rusqlite::Result<Option<String>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let hash = sha256_hex(data);
+    let exists = conn
+        .query_row("SELECT 1 FROM content_blobs WHERE hash = ?", params![hash], |_| Ok(()))
+        .optional()?
+        .is_some();
+    // Not compressing contents that are there already. Another connection may still
+    // store the same contents in between.
+    if !exists {
+        conn.execute("INSERT OR IGNORE INTO content_blobs (hash, data) VALUES (?1, ?2)", params![hash, encode_blob(data)])?;
+    }
+    Ok(Some(hash))
+}
+
+// The hashes of the contents referred to by the rows of `table` for an entry.
+fn content_hashes(conn: &rusqlite::Connection, table: &str, uuid_column: &str, uuid: Uuid) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        &format!("SELECT original_hash, edited_hash FROM {} WHERE {} = ?", table, uuid_column)
+    )?;
+    let rows = stmt.query_map(params![uuid.to_string()], |row| {
+        Ok([row.get::<_, Option<String>>(0)?, row.get::<_, Option<String>>(1)?])
+    })?;
+    let mut hashes = Vec::new();
+    for row in rows {
+        hashes.extend(row?.into_iter().flatten());
+    }
+    Ok(hashes)
+}
+
+// Deletes the contents no entry refers to anymore, so e.g. a redacted password doesn't
+// stay behind in the contents from before redacting it.
+fn prune_contents(conn: &rusqlite::Connection, hashes: &[String]) -> rusqlite::Result<()> {
+    for hash in hashes {
+        conn.execute(
+            "DELETE FROM content_blobs WHERE hash = ?1
+             AND NOT EXISTS (SELECT 1 FROM prodlog_entries WHERE original_hash = ?1 OR edited_hash = ?1)
+             AND NOT EXISTS (SELECT 1 FROM edited_files WHERE original_hash = ?1 OR edited_hash = ?1)",
+            params![hash]
+        )?;
+    }
+    Ok(())
+}
+
+// Reads file contents from the blob joined in as `blob_column` if the row refers to
+// one, or from `inline_column` for rows written before 2.20.
+fn get_content(
+    row: &rusqlite::Row,
+    hash_column: &str,
+    blob_column: &str,
+    inline_column: &str
+) -> rusqlite::Result<(Vec<u8>, Option<String>)> {
+    match row.get::<_, Option<String>>(hash_column)? {
+        Some(hash) => Ok((get_blob(row, blob_column)?, Some(hash))),
+        None => Ok((get_blob(row, inline_column)?, None)),
+    }
+}
+
+// Moves the file contents of all rows of `table` into content_blobs.
+fn move_contents(conn: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let mut last_rowid = 0i64;
+    loop {
+        let rows = {
+            let mut stmt = tx.prepare(
+                &format!("SELECT rowid, original_content, edited_content FROM {} WHERE rowid > ? ORDER BY rowid LIMIT 1000", table)
+            )?;
+            let rows = stmt.query_map(params![last_rowid], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Option<Vec<u8>>>(1)?, row.get::<_, Option<Vec<u8>>>(2)?))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        let Some((rowid, _, _)) = rows.last() else {
+            break;
+        };
+        last_rowid = *rowid;
+        let mut stmt = tx.prepare(
+            &format!("UPDATE {} SET original_hash = ?2, edited_hash = ?3, original_content = X'', edited_content = X'' WHERE rowid = ?1", table)
+        )?;
+        for (rowid, original, edited) in rows {
+            let mut hashes = Vec::new();
+            for blob in [original, edited] {
+                let data = decode_blob(blob.unwrap_or_default())
+                    .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+                hashes.push(store_content(&tx, &data)?);
+            }
+            stmt.execute(params![rowid, hashes[0], hashes[1]])?;
+        }
+    }
+    tx.commit()
+}
+
+// Gives the space back to the file system. This needs as much free space as the
+// database takes, so the space is only reused by sqlite if it fails.
+fn vacuum(conn: &rusqlite::Connection) {
+    if let Err(e) = conn.execute("VACUUM", []) {
+        print_prodlog_warning(&format!("Couldn't shrink the database file: {}", e));
+    }
+}
+
 fn migrate_up_one(
     conn: &PooledConnection<SqliteConnectionManager>,
     version: &str
 ) -> rusqlite::Result<String> {
     match version {
-        "2.2.0" => {
+        "2.2" => {
             conn.execute(
                 "ALTER TABLE prodlog_entries ADD COLUMN is_noop BOOLEAN DEFAULT FALSE",
                 []
             )?;
             Ok("2.3.0".to_string())
         }
-        "2.3.0" => {
+        "2.3" => {
             conn.execute("ALTER TABLE prodlog_entries ADD COLUMN local_user TEXT DEFAULT ''", [])?;
             conn.execute("ALTER TABLE prodlog_entries ADD COLUMN remote_user TEXT DEFAULT ''", [])?;
             conn.execute("ALTER TABLE prodlog_entries ADD COLUMN terminal_rows INT DEFAULT 0", [])?;
             conn.execute("ALTER TABLE prodlog_entries ADD COLUMN terminal_cols INT DEFAULT 0", [])?;
             Ok("2.4.0".to_string())
         }
-        "2.4.0" => {
+        "2.4" => {
             // Add tasks table and task_id column to entries
             conn.execute(
                 "CREATE TABLE IF NOT EXISTS tasks (
@@ -63,6 +259,162 @@ fn migrate_up_one(
         "2.6" => {
             Ok("2.7".to_string())
         }
+        "2.7" => {
+            // Add table for timed output chunks and terminal resizes
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS capture_events (
+                    entry_uuid TEXT NOT NULL,
+                    seq INTEGER NOT NULL,
+                    offset_ms INTEGER NOT NULL,
+                    event_type TEXT NOT NULL,
+                    data BLOB,
+                    PRIMARY KEY (entry_uuid, seq)
+                )",
+                []
+            )?;
+            Ok("2.8".to_string())
+        }
+        "2.8" => {
+            // Add parent_uuid column for nested captures
+            conn.execute("ALTER TABLE prodlog_entries ADD COLUMN parent_uuid TEXT", [])?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS prodlog_entries_parent_uuid ON prodlog_entries (parent_uuid)",
+                []
+            )?;
+            Ok("2.9".to_string())
+        }
+        "2.9" => {
+            // Add status column for captures that were interrupted
+            conn.execute("ALTER TABLE prodlog_entries ADD COLUMN status TEXT DEFAULT 'completed'", [])?;
+            Ok("2.10".to_string())
+        }
+        "2.10" => {
+            Ok("2.11".to_string())
+        }
+        "2.11" => {
+            Ok("2.12".to_string())
+        }
+        "2.12" => {
+            // Add column to flag edits whose file content didn't arrive intact
+            conn.execute("ALTER TABLE prodlog_entries ADD COLUMN content_error TEXT", [])?;
+            Ok("2.13".to_string())
+        }
+        "2.13" => {
+            // Add table for the files of multi-file edits, other than the first one
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS edited_files (
+                    entry_uuid TEXT NOT NULL,
+                    seq INTEGER NOT NULL,
+                    filename TEXT NOT NULL,
+                    original_content BLOB,
+                    edited_content BLOB,
+                    PRIMARY KEY (entry_uuid, seq)
+                )",
+                []
+            )?;
+            Ok("2.14".to_string())
+        }
+        "2.14" => {
+            // Add table for facts about the environment a capture ran in
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS entry_facts (
+                    entry_uuid TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    PRIMARY KEY (entry_uuid, key)
+                )",
+                []
+            )?;
+            Ok("2.15".to_string())
+        }
+        "2.15" => {
+            // Add tables for tags and custom fields set by the operator
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS entry_tags (
+                    entry_uuid TEXT NOT NULL,
+                    tag TEXT NOT NULL,
+                    PRIMARY KEY (entry_uuid, tag)
+                )",
+                []
+            )?;
+            conn.execute("CREATE INDEX IF NOT EXISTS entry_tags_tag ON entry_tags (tag)", [])?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS entry_custom_fields (
+                    entry_uuid TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    PRIMARY KEY (entry_uuid, name)
+                )",
+                []
+            )?;
+            Ok("2.16".to_string())
+        }
+        "2.16" => {
+            // Add tables for recordings of whole terminal sessions
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS sessions (
+                    uuid TEXT PRIMARY KEY,
+                    start_time TEXT NOT NULL,
+                    duration_ms INTEGER NOT NULL,
+                    status TEXT NOT NULL,
+                    local_user TEXT NOT NULL,
+                    cmd TEXT NOT NULL
+                )",
+                []
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS session_events (
+                    session_uuid TEXT NOT NULL,
+                    seq INTEGER NOT NULL,
+                    offset_ms INTEGER NOT NULL,
+                    event_type TEXT NOT NULL,
+                    data BLOB,
+                    PRIMARY KEY (session_uuid, seq)
+                )",
+                []
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS session_events_offset ON session_events (session_uuid, offset_ms)",
+                []
+            )?;
+            Ok("2.17".to_string())
+        }
+        "2.17" => {
+            // Add column for the terminal an entry was captured in, when several
+            // prodlog_record instances share one primary
+            conn.execute("ALTER TABLE prodlog_entries ADD COLUMN terminal TEXT DEFAULT ''", [])?;
+            Ok("2.18".to_string())
+        }
+        "2.18" => {
+            // Compress output and file contents
+            print_prodlog_message("Compressing output and file contents, this may take a while for large databases...");
+            compress_blobs(conn, "prodlog_entries", &["output", "original_content", "edited_content"])?;
+            compress_blobs(conn, "edited_files", &["original_content", "edited_content"])?;
+            compress_blobs(conn, "capture_events", &["data"])?;
+            compress_blobs(conn, "session_events", &["data"])?;
+            vacuum(conn);
+            Ok("2.19".to_string())
+        }
+        "2.19" => {
+            // Store file contents once, keyed by their hash
+            print_prodlog_message("Deduplicating file contents, this may take a while for large databases...");
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS content_blobs (
+                    hash TEXT PRIMARY KEY,
+                    data BLOB NOT NULL
+                )",
+                []
+            )?;
+            for table in ["prodlog_entries", "edited_files"] {
+                conn.execute(&format!("ALTER TABLE {} ADD COLUMN original_hash TEXT", table), [])?;
+                conn.execute(&format!("ALTER TABLE {} ADD COLUMN edited_hash TEXT", table), [])?;
+                conn.execute(&format!("CREATE INDEX IF NOT EXISTS {0}_original_hash ON {0} (original_hash)", table), [])?;
+                conn.execute(&format!("CREATE INDEX IF NOT EXISTS {0}_edited_hash ON {0} (edited_hash)", table), [])?;
+                move_contents(conn, table)?;
+            }
+            vacuum(conn);
+            Ok("2.20".to_string())
+        }
         _ => {
             prodlog_panic(
                 &format!("Database schema version {} is not supported. Please upgrade Prodlog.", version)
@@ -77,9 +429,10 @@ impl SqliteSink {
         let conn = self.pool
             .get()
             .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+        // Steps that change nothing are applied within the same millisecond
         let result = conn
             .query_row(
-                "SELECT version, dirty FROM schema_migrations ORDER BY applied_at DESC LIMIT 1",
+                "SELECT version, dirty FROM schema_migrations ORDER BY applied_at DESC, rowid DESC LIMIT 1",
                 [],
                 |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, bool>(1)?))
             )
@@ -120,9 +473,12 @@ impl SqliteSink {
             (Some(version), false) => {
                 print_prodlog_warning(&format!("Upgrading database from version: {}", version));
                 self.set_schema_version(version.as_str(), true)?;
-                let new_version = migrate_up_one(&conn, &version)?;
+                // Fresh databases record the full package version, migrations only care about major.minor.
+                let new_version = migrate_up_one(&conn, &major_minor_version(&version))?;
                 self.set_schema_version(new_version.as_str(), false)?;
                 print_prodlog_warning(&format!("    ==> new version: {}", new_version));
+                // Each step takes a connection, so hand this one back to not run out of them
+                drop(conn);
                 self.migrate()?;
             }
             (_, true) => {
@@ -151,10 +507,20 @@ impl SqliteSink {
                         task_id INTEGER,
                         output BLOB,
                         original_content BLOB,
-                        edited_content BLOB
+                        edited_content BLOB,
+                        parent_uuid TEXT,
+                        status TEXT DEFAULT 'completed',
+                        content_error TEXT,
+                        terminal TEXT DEFAULT '',
+                        original_hash TEXT,
+                        edited_hash TEXT
                     );",
                     []
                 )?;
+                conn.execute(
+                    "CREATE INDEX IF NOT EXISTS prodlog_entries_parent_uuid ON prodlog_entries (parent_uuid);",
+                    []
+                )?;
                 conn.execute(
                     "CREATE TABLE IF NOT EXISTS tasks (
                         id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -172,6 +538,94 @@ impl SqliteSink {
                     []
                 )?;
                 conn.execute("INSERT OR IGNORE INTO active_task (id, task_id) VALUES (1, NULL)", [])?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS capture_events (
+                        entry_uuid TEXT NOT NULL,
+                        seq INTEGER NOT NULL,
+                        offset_ms INTEGER NOT NULL,
+                        event_type TEXT NOT NULL,
+                        data BLOB,
+                        PRIMARY KEY (entry_uuid, seq)
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS edited_files (
+                        entry_uuid TEXT NOT NULL,
+                        seq INTEGER NOT NULL,
+                        filename TEXT NOT NULL,
+                        original_content BLOB,
+                        edited_content BLOB,
+                        original_hash TEXT,
+                        edited_hash TEXT,
+                        PRIMARY KEY (entry_uuid, seq)
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS content_blobs (
+                        hash TEXT PRIMARY KEY,
+                        data BLOB NOT NULL
+                    );",
+                    []
+                )?;
+                for table in ["prodlog_entries", "edited_files"] {
+                    conn.execute(&format!("CREATE INDEX IF NOT EXISTS {0}_original_hash ON {0} (original_hash)", table), [])?;
+                    conn.execute(&format!("CREATE INDEX IF NOT EXISTS {0}_edited_hash ON {0} (edited_hash)", table), [])?;
+                }
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS entry_facts (
+                        entry_uuid TEXT NOT NULL,
+                        key TEXT NOT NULL,
+                        value TEXT NOT NULL,
+                        PRIMARY KEY (entry_uuid, key)
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS entry_tags (
+                        entry_uuid TEXT NOT NULL,
+                        tag TEXT NOT NULL,
+                        PRIMARY KEY (entry_uuid, tag)
+                    );",
+                    []
+                )?;
+                conn.execute("CREATE INDEX IF NOT EXISTS entry_tags_tag ON entry_tags (tag)", [])?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS entry_custom_fields (
+                        entry_uuid TEXT NOT NULL,
+                        name TEXT NOT NULL,
+                        value TEXT NOT NULL,
+                        PRIMARY KEY (entry_uuid, name)
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS sessions (
+                        uuid TEXT PRIMARY KEY,
+                        start_time TEXT NOT NULL,
+                        duration_ms INTEGER NOT NULL,
+                        status TEXT NOT NULL,
+                        local_user TEXT NOT NULL,
+                        cmd TEXT NOT NULL
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE TABLE IF NOT EXISTS session_events (
+                        session_uuid TEXT NOT NULL,
+                        seq INTEGER NOT NULL,
+                        offset_ms INTEGER NOT NULL,
+                        event_type TEXT NOT NULL,
+                        data BLOB,
+                        PRIMARY KEY (session_uuid, seq)
+                    );",
+                    []
+                )?;
+                conn.execute(
+                    "CREATE INDEX IF NOT EXISTS session_events_offset ON session_events (session_uuid, offset_ms)",
+                    []
+                )?;
                 self.set_schema_version(env!("CARGO_PKG_VERSION"), false)?;
             }
         }
@@ -179,66 +633,317 @@ impl SqliteSink {
         Ok(())
     }
 
-    pub fn new(prodlog_file: &PathBuf) -> Self {
-        let prodlog_file = prodlog_file.clone();
+    pub fn new(prodlog_file: &Path) -> Self {
+        match Self::open(prodlog_file) {
+            Ok(sqlite_sink) => sqlite_sink,
+            Err(e) => prodlog_panic(&e.to_string()),
+        }
+    }
+
+    /// Like `new`, but returns an error instead of exiting when the database can't
+    /// be opened.
+    pub fn open(prodlog_file: &Path) -> Result<Self, std::io::Error> {
+        let prodlog_file = prodlog_file.to_path_buf();
         let manager = SqliteConnectionManager::file(prodlog_file);
-        let pool = match r2d2::Pool::new(manager) {
-            Ok(pool) => pool,
-            Err(e) => { prodlog_panic(&format!("Error creating sqlite pool: {}", e)) }
-        };
+        let pool = r2d2::Pool::new(manager).map_err(|e|
+            std::io::Error::new(std::io::ErrorKind::Other, format!("Error creating sqlite pool: {}", e))
+        )?;
 
         let sqlite_sink = SqliteSink { pool: Arc::new(pool) };
-        let x = sqlite_sink.migrate();
-        match x {
-            Ok(_) => sqlite_sink,
-            Err(e) => { prodlog_panic(&format!("Error migrating database: {}", e)) }
-        }
+        sqlite_sink.migrate().map_err(|e|
+            std::io::Error::new(std::io::ErrorKind::Other, format!("Error migrating database: {}", e))
+        )?;
+        Ok(sqlite_sink)
     }
 
     fn insert_or_update_entry(&self, capture: &CaptureV2_4, is_insert: bool) -> Result<(), std::io::Error> {
-        let end_time = capture.start_time + Duration::milliseconds(capture.duration_ms as i64);
-        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         let task_id = if is_insert {
             self.get_active_task()?
         } else {
             capture.task_id
         };
-        conn
-            .execute(
-                "INSERT OR REPLACE INTO prodlog_entries (capture_type, uuid, host, cwd, cmd, start_time, end_time, duration_ms, message, is_noop, exit_code, local_user, remote_user, filename, terminal_rows, terminal_cols, task_id, output, original_content, edited_content)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20)",
-                params![
-                    if capture.capture_type == CaptureType::Run {
-                        "run"
-                    } else {
-                        "edit"
-                    },
-                    capture.uuid.to_string(),
-                    &capture.host,
-                    &capture.cwd,
-                    &capture.cmd,
-                    capture.start_time.to_rfc3339(),
-                    end_time.to_rfc3339(),
-                    capture.duration_ms as i64,
-                    capture.message,
-                    capture.is_noop,
-                    capture.exit_code,
-                    capture.local_user,
-                    capture.remote_user,
-                    capture.filename,
-                    capture.terminal_rows,
-                    capture.terminal_cols,
-                    task_id,
-                    &capture.captured_output,
-                    capture.original_content,
-                    capture.edited_content
-                ]
-            )
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        // For entries with timing information the output is stored in capture_events
+        // and reassembled when the entry is read.
+        let output: &[u8] = if capture.events.is_empty() { &capture.captured_output } else { &[] };
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_entry(&tx, capture, task_id, output).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_events(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_additional_files(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_facts(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_tags(&tx, capture.uuid, &capture.tags).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_custom_fields(&tx, capture.uuid, &capture.custom_fields).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         Ok(())
     }
 }
 
+fn write_entry(
+    conn: &rusqlite::Connection,
+    capture: &CaptureV2_4,
+    task_id: Option<i64>,
+    output: &[u8]
+) -> rusqlite::Result<()> {
+    let end_time = capture.start_time + Duration::milliseconds(capture.duration_ms as i64);
+    let old_hashes = content_hashes(conn, "prodlog_entries", "uuid", capture.uuid)?;
+    let original_hash = store_content(conn, &capture.original_content)?;
+    let edited_hash = store_content(conn, &capture.edited_content)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO prodlog_entries (capture_type, uuid, host, cwd, cmd, start_time, end_time, duration_ms, message, is_noop, exit_code, local_user, remote_user, filename, terminal_rows, terminal_cols, task_id, output, original_content, edited_content, parent_uuid, status, content_error, terminal, original_hash, edited_hash)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, X'', X'', ?19, ?20, ?21, ?22, ?23, ?24)",
+        params![
+            match capture.capture_type {
+                CaptureType::Run => "run",
+                CaptureType::Edit => "edit",
+                CaptureType::Note => "note",
+            },
+            capture.uuid.to_string(),
+            &capture.host,
+            &capture.cwd,
+            &capture.cmd,
+            capture.start_time.to_rfc3339(),
+            end_time.to_rfc3339(),
+            capture.duration_ms as i64,
+            capture.message,
+            capture.is_noop,
+            capture.exit_code,
+            capture.local_user,
+            capture.remote_user,
+            capture.filename,
+            capture.terminal_rows,
+            capture.terminal_cols,
+            task_id,
+            encode_blob(output),
+            capture.parent_uuid.map(|uuid| uuid.to_string()),
+            capture.status.as_str(),
+            capture.content_error,
+            capture.terminal,
+            original_hash,
+            edited_hash
+        ]
+    )?;
+    prune_contents(conn, &old_hashes)
+}
+
+fn write_events(conn: &rusqlite::Connection, capture: &CaptureV2_4) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM capture_events WHERE entry_uuid = ?", params![capture.uuid.to_string()])?;
+    append_events(conn, capture.uuid, &capture.events)
+}
+
+fn write_additional_files(conn: &rusqlite::Connection, capture: &CaptureV2_4) -> rusqlite::Result<()> {
+    let uuid_str = capture.uuid.to_string();
+    let old_hashes = content_hashes(conn, "edited_files", "entry_uuid", capture.uuid)?;
+    conn.execute("DELETE FROM edited_files WHERE entry_uuid = ?", params![uuid_str])?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO edited_files (entry_uuid, seq, filename, original_content, edited_content, original_hash, edited_hash) VALUES (?1, ?2, ?3, X'', X'', ?4, ?5)"
+    )?;
+    for (seq, file) in capture.additional_files.iter().enumerate() {
+        let original_hash = store_content(conn, &file.original_content)?;
+        let edited_hash = store_content(conn, &file.edited_content)?;
+        stmt.execute(params![uuid_str, seq as i64, file.filename, original_hash, edited_hash])?;
+    }
+    prune_contents(conn, &old_hashes)
+}
+
+fn load_additional_files(conn: &rusqlite::Connection, entry: &mut CaptureV2_4) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT edited_files.*, original_blob.data AS original_blob, edited_blob.data AS edited_blob FROM edited_files
+         LEFT JOIN content_blobs AS original_blob ON original_blob.hash = edited_files.original_hash
+         LEFT JOIN content_blobs AS edited_blob ON edited_blob.hash = edited_files.edited_hash
+         WHERE entry_uuid = ? ORDER BY seq"
+    )?;
+    entry.additional_files = stmt
+        .query_map(params![entry.uuid.to_string()], |row| {
+            let (original_content, original_hash) = get_content(row, "original_hash", "original_blob", "original_content")?;
+            let (edited_content, edited_hash) = get_content(row, "edited_hash", "edited_blob", "edited_content")?;
+            Ok(EditedFile {
+                filename: row.get("filename")?,
+                original_content,
+                edited_content,
+                original_hash,
+                edited_hash,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(())
+}
+
+fn write_facts(conn: &rusqlite::Connection, capture: &CaptureV2_4) -> rusqlite::Result<()> {
+    let uuid_str = capture.uuid.to_string();
+    conn.execute("DELETE FROM entry_facts WHERE entry_uuid = ?", params![uuid_str])?;
+    let mut stmt = conn.prepare("INSERT INTO entry_facts (entry_uuid, key, value) VALUES (?1, ?2, ?3)")?;
+    for (key, value) in capture.facts.iter() {
+        stmt.execute(params![uuid_str, key, value])?;
+    }
+    Ok(())
+}
+
+fn load_facts(conn: &rusqlite::Connection, entry: &mut CaptureV2_4) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT key, value FROM entry_facts WHERE entry_uuid = ?")?;
+    entry.facts = stmt
+        .query_map(params![entry.uuid.to_string()], |row| Ok((row.get("key")?, row.get("value")?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+fn write_tags(conn: &rusqlite::Connection, uuid: Uuid, tags: &[String]) -> rusqlite::Result<()> {
+    let uuid_str = uuid.to_string();
+    conn.execute("DELETE FROM entry_tags WHERE entry_uuid = ?", params![uuid_str])?;
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO entry_tags (entry_uuid, tag) VALUES (?1, ?2)")?;
+    for tag in tags {
+        stmt.execute(params![uuid_str, tag])?;
+    }
+    Ok(())
+}
+
+fn load_tags(conn: &rusqlite::Connection, entry: &mut CaptureV2_4) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT tag FROM entry_tags WHERE entry_uuid = ? ORDER BY tag")?;
+    entry.tags = stmt
+        .query_map(params![entry.uuid.to_string()], |row| row.get("tag"))?
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+fn write_custom_fields(
+    conn: &rusqlite::Connection,
+    uuid: Uuid,
+    fields: &BTreeMap<String, String>
+) -> rusqlite::Result<()> {
+    let uuid_str = uuid.to_string();
+    conn.execute("DELETE FROM entry_custom_fields WHERE entry_uuid = ?", params![uuid_str])?;
+    let mut stmt = conn.prepare("INSERT INTO entry_custom_fields (entry_uuid, name, value) VALUES (?1, ?2, ?3)")?;
+    for (name, value) in fields.iter() {
+        stmt.execute(params![uuid_str, name, value])?;
+    }
+    Ok(())
+}
+
+fn load_custom_fields(conn: &rusqlite::Connection, entry: &mut CaptureV2_4) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT name, value FROM entry_custom_fields WHERE entry_uuid = ?")?;
+    entry.custom_fields = stmt
+        .query_map(params![entry.uuid.to_string()], |row| Ok((row.get("name")?, row.get("value")?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+fn append_events(conn: &rusqlite::Connection, uuid: Uuid, events: &[CaptureEvent]) -> rusqlite::Result<()> {
+    append_events_to(conn, "capture_events", "entry_uuid", uuid, events)
+}
+
+// Captures and sessions store their events the same way, in their own table.
+fn append_events_to(
+    conn: &rusqlite::Connection,
+    table: &str,
+    uuid_column: &str,
+    uuid: Uuid,
+    events: &[CaptureEvent]
+) -> rusqlite::Result<()> {
+    let uuid_str = uuid.to_string();
+    let next_seq: i64 = conn.query_row(
+        &format!("SELECT COALESCE(MAX(seq) + 1, 0) FROM {} WHERE {} = ?", table, uuid_column),
+        params![uuid_str],
+        |row| row.get(0)
+    )?;
+    let mut stmt = conn.prepare(
+        &format!("INSERT INTO {} ({}, seq, offset_ms, event_type, data) VALUES (?1, ?2, ?3, ?4, ?5)", table, uuid_column)
+    )?;
+    for (seq, event) in (next_seq..).zip(events.iter()) {
+        // Event types follow the asciicast v2 codes
+        let (event_type, data) = match &event.kind {
+            CaptureEventKind::Output(data) => ("o", Some(encode_blob(data))),
+            CaptureEventKind::Input(data) => ("i", Some(encode_blob(data))),
+            // Masked input has no data, what was typed is never stored
+            CaptureEventKind::MaskedInput => ("i", None),
+            CaptureEventKind::Resize { cols, rows } => ("r", Some(format!("{}x{}", cols, rows).into_bytes())),
+        };
+        stmt.execute(params![uuid_str, seq, event.offset_ms as i64, event_type, data])?;
+    }
+    Ok(())
+}
+
+fn from_row_event(row: &rusqlite::Row) -> rusqlite::Result<Option<CaptureEvent>> {
+    let offset_ms: i64 = row.get("offset_ms")?;
+    let event_type: String = row.get("event_type")?;
+    let data = match row.get::<_, Option<Vec<u8>>>("data")? {
+        Some(_) => Some(get_blob(row, "data")?),
+        None => None,
+    };
+    let kind = match (event_type.as_str(), data) {
+        ("o", data) => CaptureEventKind::Output(data.unwrap_or_default()),
+        ("i", Some(data)) => CaptureEventKind::Input(data),
+        ("i", None) => CaptureEventKind::MaskedInput,
+        ("r", data) => {
+            let size = String::from_utf8_lossy(&data.unwrap_or_default()).to_string();
+            match size.split_once('x').map(|(c, r)| (c.parse::<u16>(), r.parse::<u16>())) {
+                Some((Ok(cols), Ok(rows))) => CaptureEventKind::Resize { cols, rows },
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(CaptureEvent { offset_ms: offset_ms as u64, kind }))
+}
+
+fn load_events(conn: &rusqlite::Connection, entry: &mut CaptureV2_4) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT offset_ms, event_type, data FROM capture_events WHERE entry_uuid = ? ORDER BY seq"
+    )?;
+    entry.events = stmt
+        .query_map(params![entry.uuid.to_string()], from_row_event)?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    if !entry.events.is_empty() {
+        entry.captured_output = entry.output_from_events();
+    }
+    Ok(())
+}
+
+// The output in an entry's events, without the events themselves.
+fn load_output(conn: &rusqlite::Connection, uuid: Uuid) -> rusqlite::Result<Vec<u8>> {
+    let mut stmt = conn.prepare(
+        "SELECT data FROM capture_events WHERE entry_uuid = ? AND event_type = 'o' AND data IS NOT NULL ORDER BY seq"
+    )?;
+    let chunks = stmt
+        .query_map(params![uuid.to_string()], |row| get_blob(row, "data"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(chunks.concat())
+}
+
+fn from_row_session(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    let uuid_str: String = row.get("uuid")?;
+    Ok(Session {
+        uuid: Uuid::parse_str(&uuid_str).map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?,
+        start_time: row.get("start_time")?,
+        duration_ms: row.get("duration_ms")?,
+        status: CaptureStatus::parse(&row.get::<_, String>("status")?),
+        local_user: row.get("local_user")?,
+        cmd: row.get("cmd")?,
+        events: Vec::new(),
+    })
+}
+
+fn write_session(conn: &rusqlite::Connection, session: &Session) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO sessions (uuid, start_time, duration_ms, status, local_user, cmd) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            session.uuid.to_string(),
+            session.start_time.to_rfc3339(),
+            session.duration_ms as i64,
+            session.status.as_str(),
+            session.local_user,
+            session.cmd
+        ]
+    )?;
+    Ok(())
+}
+
+// Entries with the file contents they refer to, for from_row_entry.
+const SELECT_ENTRIES: &str =
+    "SELECT prodlog_entries.*, original_blob.data AS original_blob, edited_blob.data AS edited_blob FROM prodlog_entries
+     LEFT JOIN content_blobs AS original_blob ON original_blob.hash = prodlog_entries.original_hash
+     LEFT JOIN content_blobs AS edited_blob ON edited_blob.hash = prodlog_entries.edited_hash";
 
 fn from_row_entry(row: &rusqlite::Row) -> rusqlite::Result<CaptureV2_4> {
     let capture_type: String = row.get("capture_type")?;
@@ -246,13 +951,21 @@ fn from_row_entry(row: &rusqlite::Row) -> rusqlite::Result<CaptureV2_4> {
     let uuid = Uuid::parse_str(&uuid_str).map_err(|e|
         rusqlite::Error::InvalidParameterName(e.to_string())
     )?;
+    let parent_uuid = match row.get::<_, Option<String>>("parent_uuid")? {
+        Some(parent_uuid_str) => Some(Uuid::parse_str(&parent_uuid_str).map_err(|e|
+            rusqlite::Error::InvalidParameterName(e.to_string())
+        )?),
+        None => None,
+    };
+    let (original_content, original_hash) = get_content(row, "original_hash", "original_blob", "original_content")?;
+    let (edited_content, edited_hash) = get_content(row, "edited_hash", "edited_blob", "edited_content")?;
     Ok(CaptureV2_4 {
-        capture_type: if capture_type == "run" {
-            CaptureType::Run
-        } else {
-            CaptureType::Edit
+        capture_type: match capture_type.as_str() {
+            "run" => CaptureType::Run,
+            "note" => CaptureType::Note,
+            _ => CaptureType::Edit,
         },
-        uuid: uuid,
+        uuid,
         host: row.get("host")?,
         cwd: row.get("cwd")?,
         cmd: row.get("cmd")?,
@@ -261,15 +974,26 @@ fn from_row_entry(row: &rusqlite::Row) -> rusqlite::Result<CaptureV2_4> {
         message: row.get("message")?,
         is_noop: row.get("is_noop")?,
         exit_code: row.get("exit_code")?,
+        status: CaptureStatus::parse(&row.get::<_, String>("status")?),
         local_user: row.get("local_user")?,
         remote_user: row.get("remote_user")?,
         filename: row.get("filename")?,
         terminal_rows: row.get("terminal_rows")?,
         terminal_cols: row.get("terminal_cols")?,
         task_id: row.get("task_id")?,
-        captured_output: row.get("output")?,
-        original_content: row.get("original_content")?,
-        edited_content: row.get("edited_content")?,
+        parent_uuid,
+        captured_output: get_blob(row, "output")?,
+        original_content,
+        edited_content,
+        original_hash,
+        edited_hash,
+        facts: BTreeMap::new(),
+        tags: Vec::new(),
+        custom_fields: BTreeMap::new(),
+        additional_files: Vec::new(),
+        content_error: row.get("content_error")?,
+        terminal: row.get::<_, Option<String>>("terminal")?.unwrap_or_default(),
+        events: Vec::new(),
     })
 }
 
@@ -288,13 +1012,102 @@ impl Sink for SqliteSink {
     }
 
     fn update_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let updated = tx
+            .execute(
+                "UPDATE prodlog_entries SET message = ?1, is_noop = ?2 WHERE uuid = ?3",
+                params![capture.message, capture.is_noop, capture.uuid.to_string()]
+            )
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        if updated == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Entry {} not found", capture.uuid)));
+        }
+        write_tags(&tx, capture.uuid, &capture.tags).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_custom_fields(&tx, capture.uuid, &capture.custom_fields).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn redact_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
         self.insert_or_update_entry(capture, false)
     }
 
+    fn begin_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let task_id = self.get_active_task()?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_entry(&tx, capture, task_id, &capture.captured_output)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_additional_files(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_facts(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_tags(&tx, capture.uuid, &capture.tags).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_custom_fields(&tx, capture.uuid, &capture.custom_fields).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn append_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        append_events(&tx, uuid, events).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn replace_events_after(&self, uuid: Uuid, kept: usize, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.execute("DELETE FROM capture_events WHERE entry_uuid = ?1 AND seq >= ?2", params![uuid.to_string(), kept as i64])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        append_events(&tx, uuid, events).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn finalize_entry(&self, capture: &CaptureV2_4) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let end_time = capture.start_time + Duration::milliseconds(capture.duration_ms as i64);
+        let old_hashes = content_hashes(&tx, "prodlog_entries", "uuid", capture.uuid)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let edited_hash = store_content(&tx, &capture.edited_content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        // Only update what's known when the capture ends, so changes made in the UI
+        // while the capture was running are kept. A no-op rule can mark the entry as
+        // no-op now, but doesn't undo marking it by hand.
+        let updated = tx
+            .execute(
+                "UPDATE prodlog_entries SET end_time = ?1, duration_ms = ?2, exit_code = ?3, status = ?4, terminal_rows = ?5, terminal_cols = ?6, edited_content = X'', edited_hash = ?7, content_error = ?8, is_noop = (is_noop OR ?9) WHERE uuid = ?10",
+                params![
+                    end_time.to_rfc3339(),
+                    capture.duration_ms as i64,
+                    capture.exit_code,
+                    capture.status.as_str(),
+                    capture.terminal_rows,
+                    capture.terminal_cols,
+                    edited_hash,
+                    capture.content_error,
+                    capture.is_noop,
+                    capture.uuid.to_string()
+                ]
+            )
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        if updated == 0 {
+            // The entry couldn't be stored when the capture started. Store it now, the
+            // output that was appended since is picked up from capture_events.
+            let task_id = self.get_active_task()?;
+            write_entry(&tx, capture, task_id, &capture.captured_output)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            write_facts(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            write_tags(&tx, capture.uuid, &capture.tags).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            write_custom_fields(&tx, capture.uuid, &capture.custom_fields).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+        prune_contents(&tx, &old_hashes).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_additional_files(&tx, capture).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
     fn get_entries(&self, filters: &super::Filters) -> Result<Vec<CaptureV2_4>, std::io::Error> {
         let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-        let mut query = String::from("SELECT * FROM prodlog_entries WHERE 1=1");
+        let mut query = format!("{} WHERE 1=1", SELECT_ENTRIES);
         let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
 
         if let Some(date_from) = &filters.date_from {
@@ -318,14 +1131,33 @@ impl Sink for SqliteSink {
             params.push(Box::new(format!("%{}%", command)));
         }
 
-        if let Some(content) = &filters.search_content {
-            query.push_str(" AND (cmd LIKE ? OR message LIKE ? OR CAST(output AS TEXT) LIKE ? OR CAST(original_content AS TEXT) LIKE ? OR CAST(edited_content AS TEXT) LIKE ?)");
-            let search_pattern = format!("%{}%", content);
-            params.push(Box::new(search_pattern.clone()));
-            params.push(Box::new(search_pattern.clone()));
-            params.push(Box::new(search_pattern.clone()));
-            params.push(Box::new(search_pattern.clone()));
-            params.push(Box::new(search_pattern.clone()));
+        if let Some(status) = &filters.status {
+            query.push_str(" AND status = ?");
+            params.push(Box::new(status.clone()));
+        }
+
+        if let Some(terminal) = &filters.terminal {
+            query.push_str(" AND terminal = ?");
+            params.push(Box::new(terminal.clone()));
+        }
+
+        if let Some(tag) = &filters.tag {
+            query.push_str(" AND EXISTS (SELECT 1 FROM entry_tags WHERE entry_uuid = prodlog_entries.uuid AND tag = ?)");
+            params.push(Box::new(tag.clone()));
+        }
+
+        if let Some(field) = &filters.field {
+            match field.split_once('=') {
+                Some((name, value)) => {
+                    query.push_str(" AND EXISTS (SELECT 1 FROM entry_custom_fields WHERE entry_uuid = prodlog_entries.uuid AND name = ? AND value = ?)");
+                    params.push(Box::new(name.to_string()));
+                    params.push(Box::new(value.to_string()));
+                }
+                None => {
+                    query.push_str(" AND EXISTS (SELECT 1 FROM entry_custom_fields WHERE entry_uuid = prodlog_entries.uuid AND name = ?)");
+                    params.push(Box::new(field.clone()));
+                }
+            }
         }
 
         if let Some(true) = &filters.show_noop {
@@ -340,7 +1172,7 @@ impl Sink for SqliteSink {
             .prepare(&query)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-        let entries = stmt
+        let mut entries = stmt
             .query_map(rusqlite::params_from_iter(params.iter().map(|p| p.as_ref())), |row| {
                 from_row_entry(row)
             })
@@ -348,6 +1180,29 @@ impl Sink for SqliteSink {
             .collect::<Result<Vec<_>, _>>()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
+        for entry in entries.iter_mut() {
+            load_additional_files(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            load_facts(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
+        // Compressed output and file contents can't be searched in SQL, so they're only
+        // searched in the entries that the other filters left, one entry at a time
+        if let Some(content) = &filters.search_content {
+            let mut found = Vec::new();
+            for entry in entries {
+                if entry_contains(&entry, content, || load_output(&conn, entry.uuid))
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))? {
+                    found.push(entry);
+                }
+            }
+            entries = found;
+        }
+
+        for entry in entries.iter_mut() {
+            load_tags(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            load_custom_fields(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
         Ok(entries)
     }
 
@@ -356,17 +1211,54 @@ impl Sink for SqliteSink {
         let uuid_str = uuid.to_string();
         match
             conn.query_row(
-                "SELECT * FROM prodlog_entries WHERE uuid = ?",
+                &format!("{} WHERE uuid = ?", SELECT_ENTRIES),
                 params![uuid_str],
                 |row| { from_row_entry(row) }
             )
         {
-            Ok(entry) => Ok(Some(entry)),
+            Ok(mut entry) => {
+                load_events(&conn, &mut entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                load_additional_files(&conn, &mut entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                load_facts(&conn, &mut entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                load_tags(&conn, &mut entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                load_custom_fields(&conn, &mut entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                Ok(Some(entry))
+            }
             Err(QueryReturnedNoRows) => Ok(None),
             Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
         }
     }
 
+    fn get_child_entries(&self, parent_uuid: Uuid) -> Result<Vec<CaptureV2_4>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut stmt = conn
+            .prepare(&format!("{} WHERE parent_uuid = ? ORDER BY start_time", SELECT_ENTRIES))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut entries = stmt
+            .query_map(params![parent_uuid.to_string()], |row| { from_row_entry(row) })
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        for entry in entries.iter_mut() {
+            load_additional_files(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            load_facts(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            load_tags(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            load_custom_fields(&conn, entry).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
+        Ok(entries)
+    }
+
+    fn get_content_blob(&self, hash: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let blob = conn
+            .query_row("SELECT data FROM content_blobs WHERE hash = ?", params![hash], |row| row.get::<_, Vec<u8>>(0))
+            .optional()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        blob.map(decode_blob).transpose()
+    }
+
     fn create_task(&self, name: &str) -> Result<i64, std::io::Error> {
         let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         let created_at = chrono::Utc::now().to_rfc3339();
@@ -456,4 +1348,319 @@ impl Sink for SqliteSink {
         
         Ok(())
     }
+
+    fn set_entry_tags(&self, uuid: Uuid, tags: &[String]) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_tags(&tx, uuid, tags).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn set_entry_noop(&self, uuid: Uuid, is_noop: bool) -> Result<(), std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        conn.execute(
+            "UPDATE prodlog_entries SET is_noop = ? WHERE uuid = ?",
+            params![is_noop, uuid.to_string()]
+        ).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(())
+    }
+
+    fn set_entry_custom_fields(&self, uuid: Uuid, fields: &BTreeMap<String, String>) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_custom_fields(&tx, uuid, fields).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn begin_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_session(&tx, session).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        append_events_to(&tx, "session_events", "session_uuid", session.uuid, &session.events)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn append_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        append_events_to(&tx, "session_events", "session_uuid", uuid, events)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn finalize_session(&self, session: &Session) -> Result<(), std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        write_session(&conn, session).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn redact_session_events(&self, uuid: Uuid, events: &[CaptureEvent]) -> Result<(), std::io::Error> {
+        let mut conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tx = conn.transaction().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.execute("DELETE FROM session_events WHERE session_uuid = ?", params![uuid.to_string()])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        append_events_to(&tx, "session_events", "session_uuid", uuid, events)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        tx.commit().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn get_sessions(&self) -> Result<Vec<Session>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut stmt = conn
+            .prepare("SELECT * FROM sessions ORDER BY start_time DESC")
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let sessions = stmt
+            .query_map([], from_row_session)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(sessions)
+    }
+
+    fn get_session_by_id(&self, uuid: Uuid) -> Result<Option<Session>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        conn.query_row("SELECT * FROM sessions WHERE uuid = ?", params![uuid.to_string()], from_row_session)
+            .optional()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn get_session_events(&self, uuid: Uuid, from_ms: u64, to_ms: u64) -> Result<Vec<CaptureEvent>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let uuid_str = uuid.to_string();
+        let mut events: Vec<CaptureEvent> = conn
+            .query_row(
+                "SELECT offset_ms, event_type, data FROM session_events WHERE session_uuid = ?1 AND event_type = 'r' AND offset_ms < ?2 ORDER BY seq DESC LIMIT 1",
+                params![uuid_str, from_ms as i64],
+                from_row_event
+            )
+            .optional()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            .flatten()
+            .into_iter()
+            .collect();
+        let mut stmt = conn
+            .prepare(
+                "SELECT offset_ms, event_type, data FROM session_events WHERE session_uuid = ?1 AND offset_ms >= ?2 AND offset_ms <= ?3 ORDER BY seq"
+            )
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        events.extend(
+            stmt
+                .query_map(params![uuid_str, from_ms as i64, to_ms as i64], from_row_event)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+                .into_iter()
+                .flatten()
+        );
+        Ok(events)
+    }
+
+    fn get_all_tags(&self) -> Result<Vec<String>, std::io::Error> {
+        let conn = self.pool.get().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT tag FROM entry_tags ORDER BY tag")
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let tags = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::Filters;
+
+    #[test]
+    fn test_blob_codec() {
+        let output = b"$ ls -l\r\n".repeat(100);
+        let blob = encode_blob(&output);
+        assert!(blob.starts_with(BLOB_MAGIC) && blob.len() < output.len() / 10);
+        assert_eq!(decode_blob(blob).unwrap(), output);
+
+        // Blobs written before compression are read as they are
+        assert_eq!(decode_blob(b"short".to_vec()).unwrap(), b"short");
+        assert_eq!(encode_blob(b"short"), b"short");
+        let lookalike = [BLOB_MAGIC, b"\x01not zstd"].concat();
+        assert_eq!(decode_blob(encode_blob(&lookalike)).unwrap(), lookalike);
+    }
+
+    #[test]
+    fn test_content_dedup() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE content_blobs (hash TEXT PRIMARY KEY, data BLOB NOT NULL);
+             CREATE TABLE prodlog_entries (uuid TEXT, original_hash TEXT, edited_hash TEXT);
+             CREATE TABLE edited_files (entry_uuid TEXT, original_hash TEXT, edited_hash TEXT);"
+        ).unwrap();
+        let config = b"listen 80;\n".repeat(100);
+        let hash = store_content(&conn, &config).unwrap().unwrap();
+        assert_eq!(store_content(&conn, &config).unwrap(), Some(hash.clone()));
+        assert_eq!(store_content(&conn, b"").unwrap(), None);
+        let count = |conn: &rusqlite::Connection| {
+            conn.query_row("SELECT COUNT(*) FROM content_blobs", [], |row| row.get::<_, i64>(0)).unwrap()
+        };
+        assert_eq!(count(&conn), 1);
+
+        // Only deleted once nothing refers to it
+        conn.execute("INSERT INTO edited_files VALUES ('a', NULL, ?)", params![hash]).unwrap();
+        prune_contents(&conn, std::slice::from_ref(&hash)).unwrap();
+        assert_eq!(count(&conn), 1);
+        conn.execute("DELETE FROM edited_files", []).unwrap();
+        prune_contents(&conn, &[hash]).unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+
+    #[test]
+    fn test_migrate_from_2_7() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        // The schema of a database created by prodlog 2.7
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE schema_migrations (version TEXT, dirty BOOLEAN, applied_at TIMESTAMP);
+             INSERT INTO schema_migrations VALUES ('2.7.0', 0, '2025-01-01 00:00:00.000');
+             CREATE TABLE prodlog_entries (
+                 capture_type TEXT, uuid TEXT PRIMARY KEY, host TEXT, cwd TEXT, cmd TEXT, start_time TEXT,
+                 end_time TEXT, duration_ms INTEGER, message TEXT, is_noop BOOLEAN, exit_code INTEGER,
+                 local_user TEXT, remote_user TEXT, filename TEXT, terminal_rows INTEGER, terminal_cols INTEGER,
+                 task_id INTEGER, output BLOB, original_content BLOB, edited_content BLOB
+             );
+             CREATE TABLE tasks (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, created_at TEXT NOT NULL);
+             CREATE TABLE active_task (id INTEGER PRIMARY KEY CHECK (id = 1), task_id INTEGER);
+             INSERT INTO tasks (name, created_at) VALUES ('deploy', '2025-01-01T00:00:00Z');
+             INSERT INTO active_task VALUES (1, 1);"
+        ).unwrap();
+        let run = Uuid::new_v4();
+        let edit = Uuid::new_v4();
+        let config = b"listen 80;\n".repeat(100);
+        let insert = "INSERT INTO prodlog_entries VALUES
+            (?1, ?2, 'web1', '/etc', ?3, '2025-01-01T00:00:00Z', '2025-01-01T00:00:01Z', 1000, '', 0, 0,
+             'alice', 'root', ?4, 24, 80, 1, ?5, ?6, ?7)";
+        conn.execute(insert, params!["run", run.to_string(), "uptime", "", b"up 3 days\r\n".to_vec(), b"".to_vec(), b"".to_vec()]).unwrap();
+        conn.execute(insert, params!["edit", edit.to_string(), "vim", "nginx.conf", b"".to_vec(), config.clone(), b"listen 443;\n".to_vec()]).unwrap();
+        drop(conn);
+
+        let sink = SqliteSink::open(&path).unwrap();
+        assert_eq!(sink.get_schema_version().unwrap(), (Some(major_minor_version(env!("CARGO_PKG_VERSION"))), false));
+        let entry = sink.get_entry_by_id(run).unwrap().unwrap();
+        assert_eq!(entry.captured_output, b"up 3 days\r\n");
+        assert!(entry.status == CaptureStatus::Completed && entry.task_id == Some(1) && entry.tags.is_empty());
+        let entry = sink.get_entry_by_id(edit).unwrap().unwrap();
+        assert_eq!((entry.original_content, entry.edited_content), (config, b"listen 443;\n".to_vec()));
+        assert!(entry.original_hash.is_some());
+        assert_eq!(sink.get_active_task().unwrap(), Some(1));
+        assert_eq!(sink.get_entries(&Filters::default()).unwrap().len(), 2);
+
+        // Opening it again doesn't migrate it again
+        drop(sink);
+        let sink = SqliteSink::open(&path).unwrap();
+        assert_eq!(sink.get_entries(&Filters::default()).unwrap().len(), 2);
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_search_content() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let sink = SqliteSink::open(&path).unwrap();
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: chrono::Utc::now(),
+            duration_ms: 1000,
+            status: CaptureStatus::Completed,
+            local_user: "alice".to_string(),
+            cmd: "bash".to_string(),
+            events: Vec::new(),
+        };
+        let output = |offset_ms, data: &str| CaptureEvent { offset_ms, kind: CaptureEventKind::Output(data.as_bytes().to_vec()) };
+        let mut capture = session.slice_to_capture(vec![output(0, "Disk /dev/sd"), output(10, "a: 1 TiB\r\n")], 0, 1000);
+        capture.cmd = "fdisk -l".to_string();
+        sink.add_new_entry(&capture).unwrap();
+
+        let search = |text: &str| {
+            let filters = Filters { search_content: Some(text.to_string()), ..Filters::default() };
+            sink.get_entries(&filters).unwrap()
+        };
+        // Also across the events the output was recorded in
+        let found = search("/DEV/SDA");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].uuid == capture.uuid && found[0].events.is_empty());
+        assert_eq!(search("fdisk").len(), 1);
+        assert!(search("/dev/sdb").is_empty());
+        let filters = Filters { search_content: Some("sda".to_string()), host: Some("elsewhere".to_string()), ..Filters::default() };
+        assert!(sink.get_entries(&filters).unwrap().is_empty());
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_update_keeps_appended_events() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let sink = SqliteSink::open(&path).unwrap();
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: chrono::Utc::now(),
+            duration_ms: 0,
+            status: CaptureStatus::InProgress,
+            local_user: "alice".to_string(),
+            cmd: "bash".to_string(),
+            events: Vec::new(),
+        };
+        let output = |offset_ms, data: &str| CaptureEvent { offset_ms, kind: CaptureEventKind::Output(data.as_bytes().to_vec()) };
+        let mut capture = session.slice_to_capture(Vec::new(), 0, 0);
+        capture.status = CaptureStatus::InProgress;
+        sink.begin_entry(&capture).unwrap();
+        sink.append_events(capture.uuid, &[output(0, "Copying ")]).unwrap();
+
+        // The capture is still running while the entry is edited in the UI
+        let mut edited = sink.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        sink.append_events(capture.uuid, &[output(10, "done\r\n")]).unwrap();
+        edited.message = "restore from backup".to_string();
+        edited.tags = vec!["restore".to_string()];
+        sink.update_entry(&edited).unwrap();
+
+        let stored = sink.get_entry_by_id(capture.uuid).unwrap().unwrap();
+        assert_eq!(stored.events, [output(0, "Copying "), output(10, "done\r\n")]);
+        assert!(stored.message == edited.message && stored.tags == edited.tags);
+        assert!(stored.status == CaptureStatus::InProgress);
+        edited.uuid = Uuid::new_v4();
+        assert_eq!(sink.update_entry(&edited).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redact_session_events() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let sink = SqliteSink::open(&path).unwrap();
+        let output = |offset_ms, data: &str| CaptureEvent { offset_ms, kind: CaptureEventKind::Output(data.as_bytes().to_vec()) };
+        let session = Session {
+            uuid: Uuid::new_v4(),
+            start_time: chrono::Utc::now(),
+            duration_ms: 0,
+            status: CaptureStatus::InProgress,
+            local_user: "alice".to_string(),
+            cmd: "bash".to_string(),
+            events: vec![output(0, "$ ")],
+        };
+        sink.begin_session(&session).unwrap();
+        sink.append_session_events(session.uuid, &[output(10, "hunter2\r\n")]).unwrap();
+
+        sink.redact_session_events(session.uuid, &[output(0, "$ "), output(10, "[REDACTED]\r\n")]).unwrap();
+        sink.append_session_events(session.uuid, &[output(20, "$ ")]).unwrap();
+        let events = sink.get_session_events(session.uuid, 0, 100).unwrap();
+        let data: Vec<Vec<u8>> = events
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                CaptureEventKind::Output(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(data, [b"$ ".to_vec(), b"[REDACTED]\r\n".to_vec(), b"$ ".to_vec()]);
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Arc, Mutex };
+
+use axum::{
+    extract::{ ws::{ Message, WebSocket, WebSocketUpgrade }, ConnectInfo, State },
+    http::{ header, HeaderMap, StatusCode },
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use serde_with::serde_as;
+use serde_with::base64::Base64;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::config::get_config;
+use crate::model::{ CaptureStatus, CaptureType, CaptureV2_4 };
+use crate::print_prodlog_warning;
+
+// How much of the session output is kept for viewers that join later. While a
+// capture runs we keep more, so viewers joining in the middle of it see it all.
+const LIVE_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+const LIVE_IDLE_BUFFER_BYTES: usize = 64 * 1024;
+// Viewers that fall further behind than this are disconnected, and start over
+// from the buffered output when they reconnect.
+const LIVE_CHANNEL_CAPACITY: usize = 1024;
+
+/// What viewers of the live session are sent, as JSON text messages.
+#[serde_as]
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Output {
+        #[serde_as(as = "Base64")]
+        data: Vec<u8>,
+    },
+    Resize {
+        cols: u16,
+        rows: u16,
+    },
+    CaptureStarted {
+        uuid: Uuid,
+        parent_uuid: Option<Uuid>,
+        capture_type: CaptureType,
+        host: String,
+        cwd: String,
+        cmd: String,
+    },
+    CaptureStopped {
+        uuid: Uuid,
+        status: CaptureStatus,
+        exit_code: i32,
+    },
+}
+
+struct LiveState {
+    buffer: VecDeque<u8>,
+    size: Option<(u16, u16)>,
+    // The CaptureStarted events of the captures that are running
+    active_captures: Vec<LiveEvent>,
+}
+
+/// Passes what goes through the terminal on to viewers in the web UI.
+pub struct LiveHub {
+    tx: broadcast::Sender<LiveEvent>,
+    state: Mutex<LiveState>,
+}
+
+impl LiveHub {
+    pub fn new(size: Option<(u16, u16)>) -> Self {
+        let (tx, _) = broadcast::channel(LIVE_CHANNEL_CAPACITY);
+        LiveHub {
+            tx,
+            state: Mutex::new(LiveState { buffer: VecDeque::new(), size, active_captures: Vec::new() }),
+        }
+    }
+
+    pub fn output(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.buffer.extend(data);
+        let limit = if state.active_captures.is_empty() { LIVE_IDLE_BUFFER_BYTES } else { LIVE_BUFFER_BYTES };
+        if state.buffer.len() > limit {
+            let excess = state.buffer.len() - limit;
+            state.buffer.drain(..excess);
+        }
+        // Sending only fails when nobody is watching
+        let _ = self.tx.send(LiveEvent::Output { data: data.to_vec() });
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.size = Some((cols, rows));
+        let _ = self.tx.send(LiveEvent::Resize { cols, rows });
+    }
+
+    pub fn capture_started(&self, capture: &CaptureV2_4) {
+        let event = LiveEvent::CaptureStarted {
+            uuid: capture.uuid,
+            parent_uuid: capture.parent_uuid,
+            capture_type: capture.capture_type.clone(),
+            host: capture.host.clone(),
+            cwd: capture.cwd.clone(),
+            cmd: capture.cmd.clone(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.active_captures.push(event.clone());
+        let _ = self.tx.send(event);
+    }
+
+    pub fn capture_stopped(&self, capture: &CaptureV2_4) {
+        let mut state = self.state.lock().unwrap();
+        state.active_captures.retain(
+            |event| !matches!(event, LiveEvent::CaptureStarted { uuid, .. } if *uuid == capture.uuid)
+        );
+        let _ = self.tx.send(LiveEvent::CaptureStopped {
+            uuid: capture.uuid,
+            status: capture.status.clone(),
+            exit_code: capture.exit_code,
+        });
+    }
+
+    /// Returns the events that bring a new viewer up to date, and a receiver for
+    /// everything after that.
+    fn subscribe(&self) -> (Vec<LiveEvent>, broadcast::Receiver<LiveEvent>) {
+        let state = self.state.lock().unwrap();
+        let mut snapshot = Vec::new();
+        if let Some((cols, rows)) = state.size {
+            snapshot.push(LiveEvent::Resize { cols, rows });
+        }
+        snapshot.extend(state.active_captures.iter().cloned());
+        if !state.buffer.is_empty() {
+            snapshot.push(LiveEvent::Output { data: state.buffer.iter().copied().collect() });
+        }
+        // Subscribing while holding the lock, so no output is missed or sent twice
+        (snapshot, self.tx.subscribe())
+    }
+}
+
+// Browsers let any website open a WebSocket to localhost, and tell the server which
+// site did in the Origin header. Only the pages of the UI itself, on its own host and
+// port, may open the live view, or every site the operator has open could follow it.
+// Unless it's shared, that host has to be this machine, so a site whose name was made
+// to point here can't either.
+fn is_own_origin(headers: &HeaderMap, port: u16, shared: bool) -> bool {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(origin), Some(host)) = (header(header::ORIGIN), header(header::HOST)) else {
+        return false;
+    };
+    let Some((name, host_port)) = host.rsplit_once(':') else {
+        return false;
+    };
+    let same_site = ["http://", "https://"]
+        .iter()
+        .any(|scheme| origin.eq_ignore_ascii_case(&format!("{}{}", scheme, host)));
+    let is_loopback = name.eq_ignore_ascii_case("localhost") ||
+        name.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    same_site && host_port == port.to_string() && (shared || is_loopback)
+}
+
+// The live view shows everything typed and printed, passwords too if the terminal
+// echoes them, so it's only shared with other machines when asked for.
+pub async fn handle_live_ws(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(hub): State<Arc<LiveHub>>
+) -> impl IntoResponse {
+    if !addr.ip().is_loopback() && !get_config().share_live_view {
+        print_prodlog_warning(&format!("Refused live view for {}, start prodlog_record with --share-live-view to allow it", addr));
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "The live view is only shared with other machines with --share-live-view" }))).into_response();
+    }
+    if !is_own_origin(&headers, get_config().port, get_config().share_live_view) {
+        let origin = headers.get(header::ORIGIN).and_then(|value| value.to_str().ok()).unwrap_or("no origin");
+        print_prodlog_warning(&format!("Refused live view for a page of another website ({})", origin));
+        return (StatusCode::FORBIDDEN, Json(json!({ "error": "The live view can only be opened from the prodlog UI" }))).into_response();
+    }
+    ws.on_upgrade(move |socket| stream_to_viewer(socket, hub))
+}
+
+async fn send_event(socket: &mut WebSocket, event: &LiveEvent) -> Result<(), axum::Error> {
+    socket.send(Message::Text(serde_json::to_string(event).unwrap())).await
+}
+
+async fn stream_to_viewer(mut socket: WebSocket, hub: Arc<LiveHub>) {
+    let (snapshot, mut rx) = hub.subscribe();
+    for event in snapshot.iter() {
+        if send_event(&mut socket, event).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    if send_event(&mut socket, &event).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => {
+                    let _ = socket.close().await;
+                    return;
+                }
+            },
+            // The view is read-only, anything viewers send is ignored
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(origin: Option<&str>, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, host.parse().unwrap());
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, origin.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_live_view_needs_own_origin() {
+        assert!(is_own_origin(&headers(Some("http://localhost:5000"), "localhost:5000"), 5000, false));
+        assert!(is_own_origin(&headers(Some("http://[::1]:5000"), "[::1]:5000"), 5000, false));
+        assert!(!is_own_origin(&headers(Some("https://evil.example"), "localhost:5000"), 5000, false));
+        assert!(!is_own_origin(&headers(None, "localhost:5000"), 5000, false));
+        assert!(!is_own_origin(&headers(Some("http://localhost:8080"), "localhost:8080"), 5000, false));
+        // A site whose name resolves to this machine
+        assert!(!is_own_origin(&headers(Some("http://evil.example:5000"), "evil.example:5000"), 5000, false));
+        assert!(is_own_origin(&headers(Some("http://bastion:5000"), "bastion:5000"), 5000, true));
+        assert!(!is_own_origin(&headers(Some("http://evil.example"), "bastion:5000"), 5000, true));
+    }
+}
@@ -1,14 +1,18 @@
-use axum::{ routing::{get, post}, Router, response::Response, http::StatusCode };
+use axum::{ extract::DefaultBodyLimit, routing::{get, post}, Router, response::Response, http::StatusCode };
 use tokio::sync::RwLock;
 use std::sync::Arc;
 use crate::{config::get_config, sinks::Sink};
 use axum::response::Html;
 
+pub mod live;
 mod rest;
 mod static_files;
 
 type ProdlogUiState = Arc<RwLock<Box<dyn Sink>>>;
 
+// Ingested entries can carry the whole output of e.g. a CI job, base64 encoded.
+const INGEST_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
 pub async fn handle_prodlog_dyn_css() -> Response {
     let background = get_config().ui_background.clone();
     let css = format!("
@@ -53,16 +57,35 @@ pub async fn handle_react_app() -> Html<String> {
     }
 }
 
-pub async fn run_ui(sink: Arc<RwLock<Box<dyn Sink>>>, port: u16) {
+pub async fn run_ui(sink: Arc<RwLock<Box<dyn Sink>>>, live_hub: Arc<live::LiveHub>, port: u16) {
+    // The live view doesn't need the sink, only what passes through the terminal
+    let live_routes = Router::new()
+        .route("/api/live", get(live::handle_live_ws))
+        .with_state(live_hub);
+
     let app = Router::new()
         // API routes 
-        .route("/api/entries", get(rest::handle_entries_get))
+        .route(
+            "/api/entries",
+            get(rest::handle_entries_get)
+                .post(rest::handle_entries_post)
+                .layer(DefaultBodyLimit::max(INGEST_BODY_LIMIT))
+        )
         .route("/api/entries/summary", get(rest::handle_entries_summary_get))
         .route("/api/entry/:uuid", get(rest::handle_entry_get))
+        .route("/api/entry/:uuid/cast", get(rest::handle_entry_cast_get))
+        .route("/api/entry/:uuid/tree", get(rest::handle_entry_tree_get))
         .route("/api/entry", post(rest::handle_entry_post))
         .route("/api/entry/redact", post(rest::handle_entry_redact_post))
         .route("/api/redact", post(rest::handle_bulk_redact_post))  // Changed to /api/redact
         .route("/diffcontent/:uuid", get(rest::handle_diffcontent))
+        .route("/api/blob/:hash", get(rest::handle_blob_get))
+        .route("/api/tags", get(rest::handle_tags_get))
+        .route("/api/noop-rules", get(rest::handle_noop_rules_get))
+        .route("/api/noop-rules/apply", post(rest::handle_noop_rules_apply_post))
+        .route("/api/sessions", get(rest::handle_sessions_get))
+        .route("/api/session/:uuid", get(rest::handle_session_get))
+        .route("/api/session/:uuid/promote", post(rest::handle_session_promote_post))
         
         // Task management routes
         .route("/api/tasks", get(rest::handle_tasks_get))
@@ -82,14 +105,15 @@ pub async fn run_ui(sink: Arc<RwLock<Box<dyn Sink>>>, port: u16) {
         
         // React app SPA fallback - must be last to catch all other routes
         .fallback(get(handle_react_app))
-        .with_state(sink);
+        .with_state(sink)
+        .merge(live_routes);
 
     let addr = format!("0.0.0.0:{}", port);
     match tokio::net::TcpListener::bind(addr).await {
         Ok(listener) => {
             // TODO: this printing could be prettier
             super::print_prodlog_message(&format!("Starting web UI on http://localhost:{}", port));
-            axum::serve(listener, app).await.unwrap();
+            axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.unwrap();
         }
         Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
             super::print_prodlog_message(
@@ -1,13 +1,16 @@
+use std::collections::{ BTreeMap, HashSet };
 use std::sync::Arc;
 
-use axum::{ extract::{Path, State, Query}, http::StatusCode, response::IntoResponse, Json };
+use axum::{ extract::{Path, State, Query}, http::{header, HeaderMap, StatusCode}, response::IntoResponse, Json };
+use chrono::{ DateTime, Duration, Utc };
 use serde::Deserialize;
 use serde_json::json;
+use serde_with::{ base64::Base64, serde_as };
 use similar::{ ChangeTag, TextDiff };
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::{model::{CaptureV2_4, CaptureV2_4Summary}, sinks::{Sink, Filters}, helpers::redact_passwords_from_entry, print_prodlog_warning};
+use crate::{model::{CaptureStatus, CaptureTreeNode, CaptureType, CaptureV2_4, CaptureV2_4Summary, EditedFile, Session}, sinks::{Sink, Filters}, helpers::{constant_time_eq, redact_passwords_from_entry, redact_passwords_from_events}, config::get_config, print_prodlog_warning, cast::to_asciicast, noop};
 
 use super::ProdlogUiState;
 
@@ -16,6 +19,9 @@ pub struct EntryPostData {
     pub uuid: String,
     pub message: String,
     pub is_noop: bool,
+    // Left unchanged when not given
+    pub tags: Option<Vec<String>>,
+    pub custom_fields: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Deserialize)]
@@ -29,6 +35,157 @@ pub struct BulkRedactData {
     pub passwords: Vec<String>,
 }
 
+#[derive(Deserialize)]
+pub struct NoopRulesApplyData {
+    // Name of the rule to apply, all rules are applied if not given
+    pub rule: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionRangeQuery {
+    pub from_ms: Option<u64>,
+    pub to_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionPromoteData {
+    pub from_ms: u64,
+    pub to_ms: u64,
+    // What was run where isn't recorded in a session, so it's filled in by hand
+    pub host: String,
+    pub cwd: String,
+    pub cmd: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub remote_user: String,
+}
+
+// An entry recorded by another tool, e.g. a CI pipeline. Only what identifies the
+// change is required, the rest defaults to empty.
+#[serde_as]
+#[derive(Deserialize)]
+pub struct IngestEntryData {
+    pub capture_type: CaptureType,
+    // Lets a tool send the same entry again, e.g. when retrying
+    pub uuid: Option<Uuid>,
+    pub host: String,
+    #[serde(default)]
+    pub cwd: String,
+    #[serde(default)]
+    pub cmd: String,
+    pub start_time: DateTime<Utc>,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub message: String,
+    // Decided by the no-op rules when not given
+    pub is_noop: Option<bool>,
+    #[serde(default)]
+    pub exit_code: i32,
+    #[serde(default)]
+    pub local_user: String,
+    #[serde(default)]
+    pub remote_user: String,
+    #[serde(default)]
+    pub filename: String,
+    // The active task when not given
+    pub task_id: Option<i64>,
+    pub parent_uuid: Option<Uuid>,
+    #[serde_as(as = "Base64")]
+    #[serde(default)]
+    pub captured_output: Vec<u8>,
+    #[serde_as(as = "Base64")]
+    #[serde(default)]
+    pub original_content: Vec<u8>,
+    #[serde_as(as = "Base64")]
+    #[serde(default)]
+    pub edited_content: Vec<u8>,
+    #[serde(default)]
+    pub additional_files: Vec<EditedFile>,
+    #[serde(default)]
+    pub facts: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub custom_fields: BTreeMap<String, String>,
+    // Where the entry came from, e.g. the name of the pipeline
+    #[serde(default)]
+    pub terminal: String,
+}
+
+impl IngestEntryData {
+    fn validate(&self) -> Result<(), String> {
+        if self.host.trim().is_empty() {
+            return Err("host is required".to_string());
+        }
+        match self.capture_type {
+            CaptureType::Run if self.cmd.trim().is_empty() => {
+                return Err("cmd is required for Run entries".to_string());
+            }
+            CaptureType::Edit if self.filename.trim().is_empty() => {
+                return Err("filename is required for Edit entries".to_string());
+            }
+            CaptureType::Note if self.message.trim().is_empty() => {
+                return Err("message is required for Note entries".to_string());
+            }
+            _ => (),
+        }
+        if self.capture_type != CaptureType::Edit && !self.additional_files.is_empty() {
+            return Err("additional_files is only allowed for Edit entries".to_string());
+        }
+        if self.custom_fields.keys().any(|name| name.trim().is_empty()) {
+            return Err("Custom field names cannot be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn into_capture(self) -> CaptureV2_4 {
+        let mut tags: Vec<String> = self.tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        let mut capture = CaptureV2_4 {
+            capture_type: self.capture_type,
+            uuid: self.uuid.unwrap_or_else(Uuid::new_v4),
+            host: self.host.trim().to_string(),
+            cwd: self.cwd,
+            cmd: self.cmd,
+            start_time: self.start_time,
+            duration_ms: self.duration_ms,
+            message: self.message,
+            is_noop: false,
+            exit_code: self.exit_code,
+            status: CaptureStatus::Completed,
+            local_user: self.local_user,
+            remote_user: self.remote_user,
+            filename: self.filename,
+            // There's no terminal, but the output is still shown in one
+            terminal_rows: 24,
+            terminal_cols: 80,
+            task_id: self.task_id,
+            parent_uuid: self.parent_uuid,
+            captured_output: self.captured_output,
+            original_content: self.original_content,
+            edited_content: self.edited_content,
+            original_hash: None,
+            edited_hash: None,
+            facts: self.facts,
+            tags,
+            custom_fields: self.custom_fields,
+            additional_files: self.additional_files,
+            content_error: None,
+            terminal: self.terminal,
+            events: Vec::new(),
+        };
+        capture.is_noop = self.is_noop.unwrap_or_else(|| noop::matching_rule(&capture).is_some());
+        capture
+    }
+}
+
 #[derive(Deserialize)]
 pub struct TaskCreateData {
     pub name: String,
@@ -46,7 +203,7 @@ pub async fn get_entry(
     sink: Arc<RwLock<Box<dyn Sink>>>,
     uuid: &str,
 ) -> Result<CaptureV2_4, (StatusCode, String)> {
-    let uuid = match Uuid::parse_str(&uuid) {
+    let uuid = match Uuid::parse_str(uuid) {
         Ok(uuid) => uuid,
         Err(_) => {
             return Err((StatusCode::BAD_REQUEST, "Invalid UUID format".to_string()));
@@ -78,6 +235,293 @@ pub async fn handle_entry_get(
     }
 }
 
+fn build_capture_tree(
+    sink: &dyn Sink,
+    entry: &CaptureV2_4,
+    visited: &mut HashSet<Uuid>,
+) -> Result<CaptureTreeNode, std::io::Error> {
+    visited.insert(entry.uuid);
+    let mut children = Vec::new();
+    for child in sink.get_child_entries(entry.uuid)? {
+        // Guard against cycles in imported or hand-edited data
+        if !visited.contains(&child.uuid) {
+            children.push(build_capture_tree(sink, &child, visited)?);
+        }
+    }
+    Ok(CaptureTreeNode { entry: entry.into(), children })
+}
+
+pub async fn handle_entry_tree_get(
+    State(sink): State<ProdlogUiState>,
+    Path(uuid): Path<String>,
+) -> impl IntoResponse {
+    let entry = match get_entry(sink.clone(), &uuid).await {
+        Ok(entry) => entry,
+        Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
+    };
+
+    match build_capture_tree(sink.read().await.as_ref(), &entry, &mut HashSet::new()) {
+        Ok(tree) => (StatusCode::OK, Json(tree)).into_response(),
+        Err(err) => {
+            let error_msg = format!("Error loading children of entry {}: {}", entry.uuid, err);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        },
+    }
+}
+
+async fn get_session(
+    sink: &ProdlogUiState,
+    uuid: &str,
+) -> Result<Session, (StatusCode, String)> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID format".to_string()))?;
+    match sink.read().await.get_session_by_id(uuid) {
+        Ok(Some(session)) => Ok(session),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Session not found".to_string())),
+        Err(err) => {
+            let error_msg = format!("Error loading session {}: {}", uuid, err);
+            print_prodlog_warning(&error_msg);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_msg))
+        }
+    }
+}
+
+pub async fn handle_sessions_get(
+    State(sink): State<ProdlogUiState>,
+) -> impl IntoResponse {
+    match sink.read().await.get_sessions() {
+        Ok(sessions) => (StatusCode::OK, Json(sessions)).into_response(),
+        Err(e) => {
+            let error_msg = format!("Error loading sessions: {}", e);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        }
+    }
+}
+
+pub async fn handle_session_get(
+    State(sink): State<ProdlogUiState>,
+    Path(uuid): Path<String>,
+    Query(range): Query<SessionRangeQuery>,
+) -> impl IntoResponse {
+    let session = match get_session(&sink, &uuid).await {
+        Ok(session) => session,
+        Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
+    };
+    let from_ms = range.from_ms.unwrap_or(0);
+    let to_ms = range.to_ms.unwrap_or(i64::MAX as u64);
+    match sink.read().await.get_session_events(session.uuid, from_ms, to_ms) {
+        Ok(events) => (StatusCode::OK, Json(json!({ "session": session, "events": events }))).into_response(),
+        Err(e) => {
+            let error_msg = format!("Error loading events of session {}: {}", session.uuid, e);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        }
+    }
+}
+
+pub async fn handle_session_promote_post(
+    State(sink): State<ProdlogUiState>,
+    Path(uuid): Path<String>,
+    Json(data): Json<SessionPromoteData>
+) -> impl IntoResponse {
+    if data.from_ms >= data.to_ms {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "The time range is empty" }))).into_response();
+    }
+    if data.host.trim().is_empty() || data.cwd.trim().is_empty() || data.cmd.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Host, cwd and cmd are required" }))).into_response();
+    }
+    let session = match get_session(&sink, &uuid).await {
+        Ok(session) => session,
+        Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
+    };
+    let events = match sink.read().await.get_session_events(session.uuid, data.from_ms, data.to_ms) {
+        Ok(events) => events,
+        Err(e) => {
+            let error_msg = format!("Error loading events of session {}: {}", session.uuid, e);
+            print_prodlog_warning(&error_msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    };
+
+    let mut entry = session.slice_to_capture(events, data.from_ms, data.to_ms);
+    entry.host = data.host.trim().to_string();
+    entry.cwd = data.cwd.trim().to_string();
+    entry.cmd = data.cmd.trim().to_string();
+    entry.message = data.message;
+    entry.remote_user = data.remote_user;
+    // So it's clear the entry wasn't captured with prodlog run
+    entry.custom_fields.insert("promoted_from_session".to_string(), session.uuid.to_string());
+
+    match sink.write().await.add_new_entry(&entry) {
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "message": "Session slice promoted to an entry",
+            "uuid": entry.uuid
+        }))).into_response(),
+        Err(e) => {
+            let error_msg = format!("Error saving entry {}: {}", entry.uuid, e);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        }
+    }
+}
+
+// Anyone who can reach the web UI could otherwise add entries to the log, so this
+// needs --ingest-token.
+fn check_ingest_token(headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = get_config().ingest_token.as_deref().filter(|token| !token.is_empty()) else {
+        return Err((StatusCode::FORBIDDEN, "Adding entries is disabled, start prodlog_record with --ingest-token to enable it"));
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "Adding entries needs the token given with --ingest-token")),
+    }
+}
+
+/// Parses and checks what was posted to /api/entries: one entry, or an array of them.
+fn parse_ingest_entries(data: serde_json::Value) -> Result<Vec<IngestEntryData>, String> {
+    let entries = if data.is_array() {
+        serde_json::from_value::<Vec<IngestEntryData>>(data)
+    } else {
+        serde_json::from_value::<IngestEntryData>(data).map(|entry| vec![entry])
+    };
+    let entries = entries.map_err(|e| format!("Invalid entry: {}", e))?;
+    if entries.is_empty() {
+        return Err("No entries given".to_string());
+    }
+
+    let mut uuids = HashSet::new();
+    for (index, entry) in entries.iter().enumerate() {
+        entry.validate().map_err(|e| format!("Entry {}: {}", index, e))?;
+        if let Some(uuid) = entry.uuid {
+            if !uuids.insert(uuid) {
+                return Err(format!("Entry {}: uuid {} is used by another entry too", index, uuid));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Adds the entries that weren't added before, and returns the uuids of the entries
+/// that were added and of those that were skipped. Nothing is added if any entry
+/// refers to a task that doesn't exist.
+fn add_ingested_entries(sink: &dyn Sink, entries: Vec<IngestEntryData>) -> Result<(Vec<Uuid>, Vec<Uuid>), (StatusCode, String)> {
+    let internal_error = |error_msg: String| (StatusCode::INTERNAL_SERVER_ERROR, error_msg);
+    for (index, entry) in entries.iter().enumerate() {
+        if let Some(task_id) = entry.task_id {
+            match sink.get_task_by_id(task_id) {
+                Ok(Some(_)) => (),
+                Ok(None) => return Err((StatusCode::BAD_REQUEST, format!("Entry {}: there's no task {}", index, task_id))),
+                Err(e) => return Err(internal_error(format!("Error loading task {}: {}", task_id, e))),
+            }
+        }
+    }
+
+    let mut created = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in entries {
+        if let Some(uuid) = entry.uuid {
+            match sink.get_entry_by_id(uuid) {
+                Ok(Some(_)) => {
+                    skipped.push(uuid);
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => return Err(internal_error(format!("Error loading entry {}: {}", uuid, e))),
+            }
+        }
+        let task_id = entry.task_id;
+        let entry = entry.into_capture();
+        sink.add_new_entry(&entry).map_err(|e| internal_error(format!("Error saving entry {}: {}", entry.uuid, e)))?;
+        // New entries go in the active task, so an explicit task is assigned afterwards
+        if let Some(task_id) = task_id {
+            sink.assign_entries_to_task(&[entry.uuid.to_string()], Some(task_id)).map_err(|e|
+                internal_error(format!("Error assigning entry {} to task {}: {}", entry.uuid, task_id, e))
+            )?;
+        }
+        created.push(entry.uuid);
+    }
+    Ok((created, skipped))
+}
+
+/// Adds entries recorded by other tools. Nothing is added unless all entries are
+/// valid, and entries that were added before, by uuid, are skipped.
+pub async fn handle_entries_post(
+    State(sink): State<ProdlogUiState>,
+    headers: HeaderMap,
+    Json(data): Json<serde_json::Value>
+) -> impl IntoResponse {
+    if let Err((status, error_msg)) = check_ingest_token(&headers) {
+        return (status, Json(json!({ "error": error_msg }))).into_response();
+    }
+    let entries = match parse_ingest_entries(data) {
+        Ok(entries) => entries,
+        Err(error_msg) => return (StatusCode::BAD_REQUEST, Json(json!({ "error": error_msg }))).into_response(),
+    };
+
+    // Holding the write lock throughout, so an entry sent twice at the same time is
+    // only added once
+    let (created, skipped) = match add_ingested_entries(sink.write().await.as_ref(), entries) {
+        Ok(result) => result,
+        Err((status, error_msg)) => {
+            if status == StatusCode::INTERNAL_SERVER_ERROR {
+                print_prodlog_warning(&error_msg);
+            }
+            return (status, Json(json!({ "error": error_msg }))).into_response();
+        }
+    };
+
+    (StatusCode::OK, Json(json!({
+        "message": format!("{} entries added, {} were added before", created.len(), skipped.len()),
+        "created": created,
+        "skipped": skipped
+    }))).into_response()
+}
+
+pub async fn handle_entry_cast_get(
+    State(sink): State<ProdlogUiState>,
+    Path(uuid): Path<String>,
+) -> impl IntoResponse {
+    match get_entry(sink.clone(), &uuid).await {
+        Ok(entry) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "application/x-asciicast".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.cast\"", entry.uuid)),
+            ],
+            to_asciicast(&entry),
+        ).into_response(),
+        Err((status, message)) => (status, Json(json!({ "error": message }))).into_response(),
+    }
+}
+
+/// File contents by their SHA-256, see the original_hash and edited_hash of entries.
+pub async fn handle_blob_get(
+    State(sink): State<ProdlogUiState>,
+    Path(hash): Path<String>,
+) -> impl IntoResponse {
+    if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Invalid hash, expected a lowercase hex SHA-256" }))).into_response();
+    }
+    match sink.read().await.get_content_blob(&hash) {
+        Ok(Some(data)) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            data,
+        ).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({ "error": "Blob not found" }))).into_response(),
+        Err(e) => {
+            let error_msg = format!("Error loading blob {}: {}", hash, e);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        }
+    }
+}
+
 pub async fn handle_entries_get(
     State(sink): State<ProdlogUiState>,
     Query(filters): Query<Filters>,
@@ -123,14 +567,43 @@ pub async fn handle_entry_post(
         Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
     };
 
-    match sink.write().await.update_entry(&entry) {
-        Ok(_) => (StatusCode::OK, Json(json!({ "message": "Entry updated successfully" }))).into_response(),
-        Err(err) => {
-            let error_msg = format!("Error saving entry {}: {}", entry.uuid, err);
+    if let Some(custom_fields) = &data.custom_fields {
+        if custom_fields.keys().any(|name| name.trim().is_empty()) {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "Custom field names cannot be empty" }))).into_response();
+        }
+    }
+
+    if let Err(err) = sink.write().await.update_entry(&entry) {
+        let error_msg = format!("Error saving entry {}: {}", entry.uuid, err);
+        print_prodlog_warning(&error_msg);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+    }
+
+    // Tags and custom fields are saved after the entry, which would otherwise write back the old ones
+    if let Some(tags) = data.tags {
+        let mut tags: Vec<String> = tags
+            .iter()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        if let Err(err) = sink.read().await.set_entry_tags(entry.uuid, &tags) {
+            let error_msg = format!("Error saving tags of entry {}: {}", entry.uuid, err);
             print_prodlog_warning(&error_msg);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
-        },
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    }
+
+    if let Some(custom_fields) = data.custom_fields {
+        if let Err(err) = sink.read().await.set_entry_custom_fields(entry.uuid, &custom_fields) {
+            let error_msg = format!("Error saving custom fields of entry {}: {}", entry.uuid, err);
+            print_prodlog_warning(&error_msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
     }
+
+    (StatusCode::OK, Json(json!({ "message": "Entry updated successfully" }))).into_response()
 }
 
 fn simple_diff(orig: &str, edited: &str) -> String {
@@ -163,9 +636,17 @@ pub async fn handle_diffcontent(
         Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
     };
 
-    let orig = String::from_utf8_lossy(&entry.original_content);
-    let edited = String::from_utf8_lossy(&entry.edited_content);
-    (StatusCode::OK, Json(json!({ "diff": simple_diff(&orig, &edited) }))).into_response()
+    // "diff" is the diff of the first file, "files" has the diff of every file.
+    let files: Vec<_> = entry
+        .edited_files()
+        .iter()
+        .map(|file| {
+            let orig = String::from_utf8_lossy(&file.original_content);
+            let edited = String::from_utf8_lossy(&file.edited_content);
+            json!({ "filename": file.filename, "diff": simple_diff(&orig, &edited) })
+        })
+        .collect();
+    (StatusCode::OK, Json(json!({ "diff": files[0]["diff"], "files": files }))).into_response()
 }
 
 pub async fn handle_entry_redact_post(
@@ -181,24 +662,66 @@ pub async fn handle_entry_redact_post(
         Err((status, message)) => return (status, Json(json!({ "error": message }))).into_response(),
     };
 
-    let password = data.password.trim();
+    let passwords = [data.password.trim().to_string()];
     
     // Use the helper function to redact the password
-    let redacted = redact_passwords_from_entry(&mut entry, &[password.to_string()]);
-
-    if !redacted {
-        return (StatusCode::OK, Json(json!({ "message": "Password not found in this entry" }))).into_response();
-    }
+    let redacted = redact_passwords_from_entry(&mut entry, &passwords);
 
     // Save the redacted entry
-    match sink.write().await.update_entry(&entry) {
-        Ok(_) => (StatusCode::OK, Json(json!({ "message": "Password redacted successfully" }))).into_response(),
-        Err(err) => {
+    if redacted {
+        if let Err(err) = sink.write().await.redact_entry(&entry) {
             let error_msg = format!("Error saving redacted entry {}: {}", entry.uuid, err);
             print_prodlog_warning(&error_msg);
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
-        },
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    }
+
+    let redacted_sessions = match redact_passwords_from_sessions(&sink, &passwords, Some(&entry)).await {
+        Ok(count) => count,
+        Err(error_msg) => {
+            print_prodlog_warning(&error_msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    };
+
+    if !redacted && redacted_sessions == 0 {
+        return (StatusCode::OK, Json(json!({ "message": "Password not found in this entry" }))).into_response();
+    }
+    (StatusCode::OK, Json(json!({ "message": "Password redacted successfully", "redacted_sessions": redacted_sessions }))).into_response()
+}
+
+/// Sessions hold everything that went through the terminal, so passwords are removed
+/// from them too. With `during`, only from the sessions that were recorded while that
+/// entry was. Returns how many sessions were changed.
+async fn redact_passwords_from_sessions(
+    sink: &ProdlogUiState,
+    passwords: &[String],
+    during: Option<&CaptureV2_4>
+) -> Result<usize, String> {
+    let sessions = sink.read().await
+        .get_sessions()
+        .map_err(|e| format!("Error loading sessions for redaction: {}", e))?;
+    let mut redacted_count = 0;
+    for session in sessions {
+        if let Some(entry) = during {
+            let session_end = session.start_time + Duration::milliseconds(session.duration_ms as i64);
+            let entry_end = entry.start_time + Duration::milliseconds(entry.duration_ms as i64);
+            let ended_before = session.status != CaptureStatus::InProgress && session_end < entry.start_time;
+            if session.start_time > entry_end || ended_before {
+                continue;
+            }
+        }
+        let mut events = sink.read().await
+            .get_session_events(session.uuid, 0, i64::MAX as u64)
+            .map_err(|e| format!("Error loading events of session {} for redaction: {}", session.uuid, e))?;
+        if redact_passwords_from_events(&mut events, passwords) {
+            sink.write().await
+                .redact_session_events(session.uuid, &events)
+                .map_err(|e| format!("Error saving redacted session {}: {}", session.uuid, e))?;
+            redacted_count += 1;
+        }
     }
+    Ok(redacted_count)
 }
 
 pub async fn handle_bulk_redact_post(
@@ -220,8 +743,9 @@ pub async fn handle_bulk_redact_post(
         return (StatusCode::BAD_REQUEST, Json(json!({ "error": "No valid passwords provided" }))).into_response();
     }
 
-    // Get all entries
-    let entries = match sink.read().await.get_entries(&Filters::default()) {
+    // Get all entries, including no-op ones
+    let filters = Filters { show_noop: Some(true), ..Filters::default() };
+    let entries = match sink.read().await.get_entries(&filters) {
         Ok(entries) => entries,
         Err(e) => {
             let error_msg = format!("Error loading entries for bulk redaction: {}", e);
@@ -235,14 +759,23 @@ pub async fn handle_bulk_redact_post(
 
     // Process each entry
     for entry in entries {
-        let mut modified_entry = entry.clone();
-        
+        // Listed entries come without their output
+        let mut modified_entry = match sink.read().await.get_entry_by_id(entry.uuid) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(e) => {
+                let error_msg = format!("Error loading entry {} for bulk redaction: {}", entry.uuid, e);
+                print_prodlog_warning(&error_msg);
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+            }
+        };
+
         // Use the helper function to redact passwords
         let entry_modified = redact_passwords_from_entry(&mut modified_entry, &passwords);
 
         // Save the modified entry if it was changed
         if entry_modified {
-            match sink.write().await.update_entry(&modified_entry) {
+            match sink.write().await.redact_entry(&modified_entry) {
                 Ok(_) => redacted_count += 1,
                 Err(e) => {
                     let error_msg = format!("Error saving redacted entry {}: {}", modified_entry.uuid, e);
@@ -254,9 +787,74 @@ pub async fn handle_bulk_redact_post(
         }
     }
 
+    let redacted_sessions = match redact_passwords_from_sessions(&sink, &passwords, None).await {
+        Ok(count) => count,
+        Err(error_msg) => {
+            print_prodlog_warning(&error_msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    };
+
     (StatusCode::OK, Json(json!({ 
-        "message": format!("Redaction complete. {} out of {} entries were modified.", redacted_count, total_entries),
+        "message": format!(
+            "Redaction complete. {} out of {} entries and {} sessions were modified.",
+            redacted_count,
+            total_entries,
+            redacted_sessions
+        ),
         "redacted_count": redacted_count,
+        "total_entries": total_entries,
+        "redacted_sessions": redacted_sessions
+    }))).into_response()
+}
+
+pub async fn handle_noop_rules_get() -> impl IntoResponse {
+    (StatusCode::OK, Json(noop::get_rules())).into_response()
+}
+
+pub async fn handle_noop_rules_apply_post(
+    State(sink): State<ProdlogUiState>,
+    Json(data): Json<NoopRulesApplyData>
+) -> impl IntoResponse {
+    let rules: Vec<&noop::NoopRule> = noop::get_rules()
+        .iter()
+        .filter(|rule| data.rule.as_ref().is_none_or(|name| &rule.name == name))
+        .collect();
+    if rules.is_empty() {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "No such no-op rule" }))).into_response();
+    }
+
+    // Entries that are already no-op aren't returned by default
+    let entries = match sink.read().await.get_entries(&Filters::default()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let error_msg = format!("Error loading entries to apply no-op rules to: {}", e);
+            print_prodlog_warning(&error_msg);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+        }
+    };
+
+    let mut marked_count = 0;
+    let total_entries = entries.len();
+
+    for entry in entries {
+        // Captures that are still running are classified when they finish
+        if entry.status == CaptureStatus::InProgress || !rules.iter().any(|rule| rule.matches(&entry)) {
+            continue;
+        }
+        match sink.write().await.set_entry_noop(entry.uuid, true) {
+            Ok(_) => marked_count += 1,
+            Err(e) => {
+                let error_msg = format!("Error saving entry {}: {}", entry.uuid, e);
+                print_prodlog_warning(&error_msg);
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response();
+            }
+        }
+    }
+
+    (StatusCode::OK, Json(json!({
+        "message": format!("{} out of {} entries were marked as no-op.", marked_count, total_entries),
+        "marked_count": marked_count,
         "total_entries": total_entries
     }))).into_response()
 }
@@ -326,6 +924,19 @@ pub async fn handle_task_update_post(
     }))).into_response()
 }
 
+pub async fn handle_tags_get(
+    State(sink): State<ProdlogUiState>,
+) -> impl IntoResponse {
+    match sink.read().await.get_all_tags() {
+        Ok(tags) => (StatusCode::OK, Json(tags)).into_response(),
+        Err(e) => {
+            let error_msg = format!("Error loading tags: {}", e);
+            print_prodlog_warning(&error_msg);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": error_msg }))).into_response()
+        }
+    }
+}
+
 pub async fn handle_tasks_get(
     State(sink): State<ProdlogUiState>,
 ) -> impl IntoResponse {
@@ -392,3 +1003,82 @@ pub async fn handle_active_task_post(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::sqlite::SqliteSink;
+
+    fn run_entry(uuid: Uuid, cmd: &str) -> serde_json::Value {
+        json!({
+            "capture_type": "Run",
+            "uuid": uuid,
+            "host": " ci-runner ",
+            "cmd": cmd,
+            "start_time": "2025-06-01T12:00:00Z",
+        })
+    }
+
+    #[test]
+    fn test_parse_ingest_entries() {
+        let uuid = Uuid::new_v4();
+        assert_eq!(parse_ingest_entries(run_entry(uuid, "make deploy")).unwrap().len(), 1);
+        let entries = json!([run_entry(uuid, "make deploy"), run_entry(Uuid::new_v4(), "ls")]);
+        assert_eq!(parse_ingest_entries(entries).unwrap().len(), 2);
+
+        assert_eq!(parse_ingest_entries(json!([])).err().unwrap(), "No entries given");
+        assert!(parse_ingest_entries(json!({ "capture_type": "Run" })).err().unwrap().starts_with("Invalid entry"));
+        let duplicated = json!([run_entry(uuid, "make deploy"), run_entry(uuid, "make deploy")]);
+        assert!(parse_ingest_entries(duplicated).err().unwrap().contains("used by another entry"));
+        let error = parse_ingest_entries(json!([run_entry(Uuid::new_v4(), "ls"), run_entry(Uuid::new_v4(), " ")])).err().unwrap();
+        assert_eq!(error, "Entry 1: cmd is required for Run entries");
+
+        let mut note = run_entry(uuid, "");
+        note["capture_type"] = "Note".into();
+        assert_eq!(parse_ingest_entries(note.clone()).err().unwrap(), "Entry 0: message is required for Note entries");
+        note["message"] = "Deployed".into();
+        note["additional_files"] = json!([{ "filename": "/etc/motd", "original_content": "", "edited_content": "" }]);
+        assert_eq!(parse_ingest_entries(note).err().unwrap(), "Entry 0: additional_files is only allowed for Edit entries");
+    }
+
+    #[test]
+    fn test_ingest_entry_into_capture() {
+        let uuid = Uuid::new_v4();
+        let mut entry = run_entry(uuid, "ls -l");
+        entry["tags"] = json!([" deploy", "", "deploy ", "ci"]);
+        let capture = parse_ingest_entries(entry.clone()).unwrap().pop().unwrap().into_capture();
+        assert!(capture.uuid == uuid && capture.host == "ci-runner");
+        assert_eq!(capture.tags, ["ci", "deploy"]);
+        // Decided by the no-op rules unless given
+        assert!(capture.is_noop);
+        entry["is_noop"] = false.into();
+        assert!(!parse_ingest_entries(entry).unwrap().pop().unwrap().into_capture().is_noop);
+
+        let mut entry = run_entry(uuid, "make deploy");
+        entry.as_object_mut().unwrap().remove("uuid");
+        let capture = parse_ingest_entries(entry).unwrap().pop().unwrap().into_capture();
+        assert!(capture.uuid != uuid && !capture.is_noop);
+    }
+
+    #[test]
+    fn test_add_ingested_entries_skips_known_uuids() {
+        let path = std::env::temp_dir().join(format!("prodlog-test-{}.sqlite", Uuid::new_v4()));
+        let sink = SqliteSink::open(&path).unwrap();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let entries = parse_ingest_entries(run_entry(first, "make deploy")).unwrap();
+        assert_eq!(add_ingested_entries(&sink, entries).unwrap(), (vec![first], vec![]));
+        let entries = parse_ingest_entries(json!([run_entry(first, "make deploy"), run_entry(second, "make test")])).unwrap();
+        assert_eq!(add_ingested_entries(&sink, entries).unwrap(), (vec![second], vec![first]));
+        assert_eq!(sink.get_entries(&Filters::default()).unwrap().len(), 2);
+
+        // Nothing is added when a task doesn't exist
+        let mut entry = run_entry(Uuid::new_v4(), "make deploy");
+        entry["task_id"] = 42.into();
+        let error = add_ingested_entries(&sink, parse_ingest_entries(entry).unwrap()).unwrap_err();
+        assert_eq!(error, (StatusCode::BAD_REQUEST, "Entry 0: there's no task 42".to_string()));
+        assert_eq!(sink.get_entries(&Filters::default()).unwrap().len(), 2);
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+}